@@ -0,0 +1,184 @@
+//! Answer Cache Module
+//!
+//! Opt-in cache (`answer_cache`) that reuses answers for prompts a chat has
+//! already asked with the same model and temperature, for FAQ-style use.
+//! Entries expire after `answer_cache_ttl_secs`.
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::CONFIG;
+
+/// What makes two requests interchangeable
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    context_key: i64,
+    prompt: String,
+    model: String,
+    /// Bit pattern of the temperature, so the key can be hashed
+    temperature: u32,
+}
+
+impl CacheKey {
+    pub fn new(context_key: i64, prompt: &str, model: &str, temperature: f32) -> Self {
+        Self {
+            context_key,
+            prompt: normalize_prompt(prompt),
+            model: model.to_string(),
+            temperature: temperature.to_bits(),
+        }
+    }
+}
+
+/// A cached answer
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedAnswer {
+    /// Raw answer, as stored in the conversation history
+    pub content: String,
+    /// Messages that were sent to the user
+    pub chunks: Vec<String>,
+}
+
+/// Reduces a prompt to what the user asked
+///
+/// The `{Username: ..., DateTime: ..., Message: ...}` envelope added to chat
+/// messages is removed (the timestamp would make every prompt unique), then
+/// case and whitespace are normalized.
+pub fn normalize_prompt(prompt: &str) -> String {
+    let text = prompt
+        .strip_prefix("{Username: ")
+        .and_then(|rest| rest.split_once(", Message: "))
+        .map(|(_, message)| message.strip_suffix('}').unwrap_or(message))
+        .unwrap_or(prompt);
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+pub struct AnswerCache {
+    entries: DashMap<CacheKey, (Instant, CachedAnswer)>,
+    ttl: Duration,
+    max_entries: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl AnswerCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            ttl,
+            max_entries,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Looks up a fresh answer, counting the hit or miss
+    pub fn get(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        let found = self
+            .entries
+            .get(key)
+            .filter(|entry| entry.0.elapsed() < self.ttl)
+            .map(|entry| entry.1.clone());
+        match found {
+            Some(answer) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(answer)
+            }
+            None => {
+                self.entries
+                    .remove_if(key, |_, entry| entry.0.elapsed() >= self.ttl);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Stores an answer; skipped while the cache is full of fresh entries
+    pub fn insert(&self, key: CacheKey, answer: CachedAnswer) {
+        if self.entries.len() >= self.max_entries {
+            self.entries.retain(|_, entry| entry.0.elapsed() < self.ttl);
+            if self.entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        self.entries.insert(key, (Instant::now(), answer));
+    }
+
+    /// Hit and miss counts since startup
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Shared cache, `None` unless `answer_cache` is enabled
+pub static ANSWER_CACHE: Lazy<Option<AnswerCache>> = Lazy::new(|| {
+    if !CONFIG.get_bool("answer_cache").unwrap_or(false) {
+        return None;
+    }
+    let ttl = CONFIG.get::<u64>("answer_cache_ttl_secs").unwrap_or(3600);
+    let max_entries = CONFIG
+        .get::<usize>("answer_cache_max_entries")
+        .unwrap_or(1000);
+    Some(AnswerCache::new(Duration::from_secs(ttl), max_entries))
+});
+
+/// Hit and miss counts, `None` when the cache is disabled
+pub fn stats() -> Option<(u64, u64)> {
+    ANSWER_CACHE.as_ref().map(AnswerCache::stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(content: &str) -> CachedAnswer {
+        CachedAnswer {
+            content: content.to_string(),
+            chunks: vec![content.to_string()],
+        }
+    }
+
+    #[test]
+    fn test_normalize_prompt_ignores_envelope_case_and_spacing() {
+        let wrapped = "{Username: Ann (@ann), DateTime: 2026-01-01 10:00:00, Message: What  are your\nHOURS?}";
+        assert_eq!(normalize_prompt(wrapped), "what are your hours?");
+        assert_eq!(
+            normalize_prompt(" What are  your hours? "),
+            "what are your hours?"
+        );
+    }
+
+    #[test]
+    fn test_cache_hits_only_identical_settings() {
+        let cache = AnswerCache::new(Duration::from_secs(60), 10);
+        cache.insert(CacheKey::new(1, "Hours?", "m", 0.7), answer("9-5"));
+
+        assert_eq!(
+            cache.get(&CacheKey::new(1, "hours?", "m", 0.7)),
+            Some(answer("9-5"))
+        );
+        assert_eq!(cache.get(&CacheKey::new(2, "hours?", "m", 0.7)), None);
+        assert_eq!(cache.get(&CacheKey::new(1, "hours?", "other", 0.7)), None);
+        assert_eq!(cache.get(&CacheKey::new(1, "hours?", "m", 0.8)), None);
+        assert_eq!(cache.stats(), (1, 3));
+    }
+
+    #[test]
+    fn test_expired_entries_miss() {
+        let cache = AnswerCache::new(Duration::ZERO, 10);
+        let key = CacheKey::new(1, "hi", "m", 0.7);
+        cache.insert(key.clone(), answer("hello"));
+        assert_eq!(cache.get(&key), None);
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+//! Answer Format Module
+//!
+//! Per-chat presets (`/format`) bundling how answers are requested and
+//! shown: an instruction for the system prompt, the `response_format`
+//! request field, and the Telegram parse mode and wrapping of each message.
+
+use teloxide::{types::ParseMode, utils::html};
+
+/// Answer format preset of a chat
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AnswerFormat {
+    /// Plain text, as the model writes it
+    #[default]
+    Chat,
+    /// Telegram Markdown
+    Markdown,
+    /// Everything in a code block
+    Code,
+    /// A JSON object in a code block
+    Json,
+}
+
+impl AnswerFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AnswerFormat::Chat => "chat",
+            AnswerFormat::Markdown => "markdown",
+            AnswerFormat::Code => "code",
+            AnswerFormat::Json => "json",
+        }
+    }
+
+    /// Instruction appended to the system prompt, `None` for plain chat
+    pub fn instruction(self) -> Option<&'static str> {
+        match self {
+            AnswerFormat::Chat => None,
+            AnswerFormat::Markdown => Some(
+                "Format answers with Markdown: *bold*, _italic_, `inline code` and ``` code blocks. Do not use headings or tables.",
+            ),
+            AnswerFormat::Code => {
+                Some("Answer with code only; put any explanation in code comments.")
+            }
+            AnswerFormat::Json => Some("Answer with a single valid JSON object and nothing else."),
+        }
+    }
+
+    /// `response_format` request field, for OpenAI-compatible servers
+    pub fn response_format(self) -> Option<serde_json::Value> {
+        match self {
+            AnswerFormat::Json => Some(serde_json::json!({"type": "json_object"})),
+            _ => None,
+        }
+    }
+
+    /// Parse mode the rendered messages are sent with
+    pub fn parse_mode(self) -> Option<ParseMode> {
+        match self {
+            AnswerFormat::Chat => None,
+            AnswerFormat::Markdown => Some(ParseMode::Markdown),
+            AnswerFormat::Code | AnswerFormat::Json => Some(ParseMode::Html),
+        }
+    }
+
+    /// Prepares one message of an answer for `parse_mode`
+    pub fn render(self, chunk: &str) -> String {
+        match self {
+            AnswerFormat::Code | AnswerFormat::Json => {
+                format!("<pre>{}</pre>", html::escape(chunk.trim()))
+            }
+            _ => chunk.to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for AnswerFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "chat" | "plain" => Ok(AnswerFormat::Chat),
+            "markdown" => Ok(AnswerFormat::Markdown),
+            "code" => Ok(AnswerFormat::Code),
+            "json" => Ok(AnswerFormat::Json),
+            other => Err(format!("Unknown answer format: {}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets_map_to_settings() {
+        assert_eq!("Plain".parse(), Ok(AnswerFormat::Chat));
+        assert_eq!(AnswerFormat::Chat.parse_mode(), None);
+        assert_eq!(AnswerFormat::Chat.render("a < b"), "a < b");
+
+        assert_eq!(AnswerFormat::Code.parse_mode(), Some(ParseMode::Html));
+        assert_eq!(
+            AnswerFormat::Code.render("if a < b && c {}\n"),
+            "<pre>if a &lt; b &amp;&amp; c {}</pre>"
+        );
+
+        assert_eq!(
+            AnswerFormat::Json.response_format(),
+            Some(serde_json::json!({"type": "json_object"}))
+        );
+        assert_eq!(AnswerFormat::Markdown.response_format(), None);
+        assert!("yaml".parse::<AnswerFormat>().is_err());
+    }
+}
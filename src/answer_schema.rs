@@ -0,0 +1,125 @@
+//! Answer Schema Module
+//!
+//! Optional JSON schema for chats with the `json` answer format, set per chat
+//! with `/schema` or for all chats with `json_schema`. The schema is shown to
+//! the model with the format instruction, and answers are validated against
+//! it before sending. An answer that doesn't match is sent back to the model
+//! once, together with the validation errors, to get a repaired version.
+
+use jsonschema::Validator;
+use std::sync::Arc;
+use tracing::{Level, event};
+
+use crate::{CONFIG, storage::Storage};
+
+/// Validation errors reported to the model at most, the rest are left out
+const MAX_REPORTED_ERRORS: usize = 10;
+
+/// Checks that `schema` is a usable JSON schema
+///
+/// # Returns
+/// * `Ok(String)` - The schema as compact JSON, ready to be stored
+/// * `Err(String)` - User-facing reason the schema was rejected
+pub fn parse(schema: &str) -> Result<String, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(schema).map_err(|e| format!("Not valid JSON: {}", e))?;
+    jsonschema::validator_for(&value).map_err(|e| format!("Not a valid JSON schema: {}", e))?;
+    Ok(value.to_string())
+}
+
+/// Schema of a chat: its own from `/schema`, else `json_schema` from configuration
+pub async fn chat_schema(chat_id: i64, storage: &Arc<dyn Storage>) -> Option<String> {
+    match storage.get_json_schema(chat_id).await {
+        Some(schema) => Some(schema),
+        None => CONFIG
+            .get_string("json_schema")
+            .ok()
+            .filter(|schema| !schema.trim().is_empty()),
+    }
+}
+
+/// Compiles a schema for validation; `None` (logged) when it is not valid
+pub fn validator(schema: &str) -> Option<Validator> {
+    let value: serde_json::Value = serde_json::from_str(schema)
+        .inspect_err(|e| event!(Level::WARN, "Ignoring unparsable JSON schema: {}", e))
+        .ok()?;
+    jsonschema::validator_for(&value)
+        .inspect_err(|e| event!(Level::WARN, "Ignoring invalid JSON schema: {}", e))
+        .ok()
+}
+
+/// Validates an answer against a schema
+///
+/// Models often wrap JSON in a ``` code block; the fence is ignored.
+///
+/// # Returns
+/// Why the answer doesn't match, empty when it does
+pub fn violations(validator: &Validator, answer: &str) -> Vec<String> {
+    let value: serde_json::Value = match serde_json::from_str(strip_code_fence(answer)) {
+        Ok(value) => value,
+        Err(e) => return vec![format!("The answer is not valid JSON: {}", e)],
+    };
+    validator
+        .iter_errors(&value)
+        .take(MAX_REPORTED_ERRORS)
+        .map(|error| error.to_string())
+        .collect()
+}
+
+/// Follow-up message asking the model to fix an answer with `violations`
+pub fn repair_prompt(violations: &[String]) -> String {
+    format!(
+        "Your answer does not match the required JSON schema:\n- {}\n\nAnswer again with only the corrected JSON object.",
+        violations.join("\n- ")
+    )
+}
+
+/// Line added to the system prompt, so the first answer can already match
+pub fn instruction(schema: &str) -> String {
+    format!("The JSON object must match this JSON schema: {}", schema)
+}
+
+/// Inside of a ``` code block, or the whole answer when it is not fenced
+fn strip_code_fence(answer: &str) -> &str {
+    let answer = answer.trim();
+    answer
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        // The opening fence may name a language, e.g. ```json
+        .map(|inner| inner.split_once('\n').map_or(inner, |(_, body)| body))
+        .unwrap_or(answer)
+        .trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERSON: &str = r#"{
+        "type": "object",
+        "properties": {"name": {"type": "string"}, "age": {"type": "integer"}},
+        "required": ["name"]
+    }"#;
+
+    #[test]
+    fn test_answers_are_checked_against_schema() {
+        let validator = validator(PERSON).unwrap();
+
+        assert!(violations(&validator, r#"{"name": "Ada", "age": 36}"#).is_empty());
+        assert!(violations(&validator, "```json\n{\"name\": \"Ada\"}\n```").is_empty());
+        assert_eq!(violations(&validator, r#"{"age": "old"}"#).len(), 2);
+        let not_json = violations(&validator, "Sure! Here is the JSON");
+        assert_eq!(not_json.len(), 1);
+        assert!(not_json[0].starts_with("The answer is not valid JSON"));
+    }
+
+    #[test]
+    fn test_parse_normalizes_and_rejects_schemas() {
+        assert_eq!(
+            parse(" { \"type\" : \"object\" } "),
+            Ok(r#"{"type":"object"}"#.to_string())
+        );
+        assert!(parse("{\"type\": ").is_err());
+        assert!(parse(r#"{"type": "strng"}"#).is_err());
+    }
+}
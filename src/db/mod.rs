@@ -1 +1,3 @@
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod sqlite;
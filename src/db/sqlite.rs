@@ -1,12 +1,74 @@
-use sqlx::{Error, Pool, Sqlite, SqlitePool, migrate::MigrateDatabase};
+use sqlx::{
+    Error, Pool, Row, Sqlite, SqlitePool, migrate::MigrateDatabase, sqlite::SqliteConnectOptions,
+};
+use std::{str::FromStr, time::Duration};
 use tracing::{Level, event};
 
+/// How long SQLite waits on a locked database before returning `SQLITE_BUSY`
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Columns added to `users` after the initial schema, as `(name, definition)`
+///
+/// Existing databases get them through `ensure_column` on startup.
+const USER_COLUMNS: &[(&str, &str)] = &[
+    ("thinking", "BOOLEAN"),
+    ("brevity", "TEXT"),
+    ("started", "BOOLEAN"),
+    ("inactive", "BOOLEAN"),
+    ("extra_headers", "TEXT"),
+    ("pinned_id", "INTEGER"),
+    ("logit_bias", "TEXT"),
+    ("chat_slots", "TEXT"),
+    ("inject_notes", "BOOLEAN"),
+    ("answer_format", "TEXT"),
+    ("json_schema", "TEXT"),
+    ("disabled_hinted", "BOOLEAN"),
+    ("api_key", "TEXT"),
+    ("last_active", "INTEGER"),
+    ("auto_clear_mins", "INTEGER"),
+    ("max_tokens", "INTEGER"),
+    ("model", "TEXT"),
+];
+
+/// Adds `column` to `table` unless it already exists
+async fn ensure_column(
+    db: &Pool<Sqlite>,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), Error> {
+    let columns = sqlx::query(&format!("PRAGMA table_info({})", table))
+        .fetch_all(db)
+        .await?;
+    if columns
+        .iter()
+        .any(|row| row.get::<String, _>("name") == column)
+    {
+        return Ok(());
+    }
+
+    event!(Level::INFO, "Adding column {}.{}", table, column);
+    sqlx::query(&format!(
+        "ALTER TABLE {} ADD COLUMN {} {}",
+        table, column, definition
+    ))
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
 pub async fn init_db() -> Result<Pool<Sqlite>, Error> {
-    if !Sqlite::database_exists("db.sqlite").await.unwrap_or(false) {
-        Sqlite::create_database("db.sqlite").await?;
+    init_db_at("db.sqlite").await
+}
+
+/// Opens (creating if needed) the database at `path` and applies the schema
+pub async fn init_db_at(path: &str) -> Result<Pool<Sqlite>, Error> {
+    if !Sqlite::database_exists(path).await.unwrap_or(false) {
+        Sqlite::create_database(path).await?;
     }
 
-    let db = SqlitePool::connect("db.sqlite").await;
+    let options = SqliteConnectOptions::from_str(path)?.busy_timeout(BUSY_TIMEOUT);
+    let db = SqlitePool::connect_with(options).await;
     if let Ok(db) = db {
         let query_res = sqlx::query(
             "CREATE TABLE IF NOT EXISTS context (
@@ -41,6 +103,104 @@ pub async fn init_db() -> Result<Pool<Sqlite>, Error> {
             return Err(err);
         }
 
+        let query_res = sqlx::query(
+            "CREATE TABLE IF NOT EXISTS personas (
+                chat_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                fingerprint TEXT NOT NULL,
+                PRIMARY KEY (chat_id, name)
+            )",
+        )
+        .execute(&db)
+        .await;
+
+        if let Err(err) = query_res {
+            event!(Level::ERROR, "Failed to create table 3: {:?}", err);
+            return Err(err);
+        }
+
+        let query_res = sqlx::query(
+            "CREATE TABLE IF NOT EXISTS chat_logs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                chat_id INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                prompt TEXT NOT NULL,
+                answer TEXT NOT NULL
+            )",
+        )
+        .execute(&db)
+        .await;
+
+        if let Err(err) = query_res {
+            event!(Level::ERROR, "Failed to create table 4: {:?}", err);
+            return Err(err);
+        }
+
+        let query_res =
+            sqlx::query("CREATE INDEX IF NOT EXISTS chat_logs_chat_id ON chat_logs (chat_id, id)")
+                .execute(&db)
+                .await;
+
+        if let Err(err) = query_res {
+            event!(Level::ERROR, "Failed to create chat_logs index: {:?}", err);
+            return Err(err);
+        }
+
+        let query_res = sqlx::query(
+            "CREATE TABLE IF NOT EXISTS banned_users (
+                chat_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, user_id)
+            )",
+        )
+        .execute(&db)
+        .await;
+
+        if let Err(err) = query_res {
+            event!(Level::ERROR, "Failed to create table 5: {:?}", err);
+            return Err(err);
+        }
+
+        let query_res = sqlx::query(
+            "CREATE TABLE IF NOT EXISTS notes (
+                chat_id INTEGER NOT NULL,
+                note_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                text TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                embedding TEXT,
+                PRIMARY KEY (chat_id, note_id)
+            )",
+        )
+        .execute(&db)
+        .await;
+
+        if let Err(err) = query_res {
+            event!(Level::ERROR, "Failed to create table 6: {:?}", err);
+            return Err(err);
+        }
+
+        let query_res = sqlx::query(
+            "CREATE TABLE IF NOT EXISTS chat_settings (
+                chat_id INTEGER PRIMARY KEY NOT NULL,
+                settings TEXT NOT NULL
+            )",
+        )
+        .execute(&db)
+        .await;
+
+        if let Err(err) = query_res {
+            event!(Level::ERROR, "Failed to create table 7: {:?}", err);
+            return Err(err);
+        }
+
+        for (column, definition) in USER_COLUMNS {
+            if let Err(err) = ensure_column(&db, "users", column, definition).await {
+                event!(Level::ERROR, "Failed to migrate users table: {:?}", err);
+                return Err(err);
+            }
+        }
+
         return Ok(db);
     } else {
         let err = db.err().unwrap();
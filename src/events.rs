@@ -0,0 +1,124 @@
+//! Event Webhook Module
+//!
+//! Posts JSON events to `event_webhook_url` so operators can follow the bot
+//! from external dashboards. Delivery runs in a background task fed by a
+//! bounded channel: when the webhook is slow and the queue is full, new
+//! events are dropped rather than delaying answers.
+
+use once_cell::sync::Lazy;
+use reqwest::Client;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{Level, event};
+
+use crate::CONFIG;
+
+/// What happened
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// An answer was delivered to the chat
+    RequestCompleted,
+    /// A request failed
+    Error { message: String },
+    /// A note was added
+    NoteAdded { note_id: i64, user_id: u64 },
+}
+
+/// Payload posted to the webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    #[serde(flatten)]
+    pub kind: EventKind,
+    pub chat_id: i64,
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+}
+
+/// Queue of the delivery task, `None` when no webhook is configured
+static SENDER: Lazy<Option<mpsc::Sender<Event>>> = Lazy::new(|| {
+    let url = CONFIG.get_string("event_webhook_url").unwrap_or_default();
+    if url.is_empty() {
+        return None;
+    }
+    let capacity = CONFIG
+        .get::<usize>("event_webhook_queue")
+        .unwrap_or(100)
+        .max(1);
+    let (tx, rx) = mpsc::channel(capacity);
+    tokio::spawn(deliver(url, rx));
+    Some(tx)
+});
+
+/// Posts queued events one by one until the bot shuts down
+async fn deliver(url: String, mut rx: mpsc::Receiver<Event>) {
+    let client = Client::new();
+    while let Some(payload) = rx.recv().await {
+        match client.post(&url).json(&payload).send().await {
+            Ok(response) if !response.status().is_success() => {
+                event!(
+                    Level::WARN,
+                    "Event webhook answered {} for {:?}",
+                    response.status(),
+                    payload.kind
+                );
+            }
+            Ok(_) => {}
+            Err(e) => event!(Level::WARN, "Event webhook failed: {}", e),
+        }
+    }
+}
+
+/// Queues an event for the webhook without waiting for delivery
+///
+/// Does nothing when `event_webhook_url` is unset.
+pub fn notify_event(chat_id: i64, kind: EventKind) {
+    let Some(sender) = SENDER.as_ref() else {
+        return;
+    };
+    let payload = Event {
+        kind,
+        chat_id,
+        timestamp: chrono::Utc::now().timestamp(),
+    };
+    if let Err(e) = sender.try_send(payload) {
+        event!(Level::WARN, "Dropping webhook event: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_payload_shape() {
+        let payload = Event {
+            kind: EventKind::NoteAdded {
+                note_id: 5,
+                user_id: 7,
+            },
+            chat_id: -100,
+            timestamp: 1700000000,
+        };
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            serde_json::json!({
+                "type": "note_added",
+                "note_id": 5,
+                "user_id": 7,
+                "chat_id": -100,
+                "timestamp": 1700000000
+            })
+        );
+
+        let payload = Event {
+            kind: EventKind::RequestCompleted,
+            chat_id: 1,
+            timestamp: 0,
+        };
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap()["type"],
+            "request_completed"
+        );
+    }
+}
@@ -31,12 +31,16 @@ pub struct Choice {
     /// Reason for completion
     pub finish_reason: String,
     /// Generated message content
+    ///
+    /// Some gateways send the streaming `delta` shape even when `stream` is
+    /// false, so that name is accepted too.
+    #[serde(alias = "delta")]
     pub message: Message,
 }
 
 /// Token usage statistics structure
 #[allow(unused)]
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct Usage {
     /// Number of tokens in the prompt
     pub prompt_tokens: u32,
@@ -46,6 +50,41 @@ pub struct Usage {
     pub total_tokens: u32,
 }
 
+/// One `data:` frame of a streamed completion
+///
+/// Servers may interleave frames carrying only metadata (role, a new
+/// `system_fingerprint`, usage); everything but the fields below is ignored.
+#[allow(unused)]
+#[derive(serde::Deserialize, Debug)]
+pub struct StreamChunk {
+    /// Content deltas, empty in usage-only frames
+    #[serde(default)]
+    pub choices: Vec<StreamChoice>,
+    /// Token usage, sent in the final frame when `stream_options.include_usage` is set
+    pub usage: Option<Usage>,
+}
+
+/// Single choice of a streamed frame
+#[allow(unused)]
+#[derive(serde::Deserialize, Debug)]
+pub struct StreamChoice {
+    /// Change to the generated message
+    #[serde(default)]
+    pub delta: Delta,
+    /// Reason for completion, set in the last content frame
+    pub finish_reason: Option<String>,
+}
+
+/// Incremental update of the generated message
+#[allow(unused)]
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct Delta {
+    /// Role, usually only present in the first frame
+    pub role: Option<String>,
+    /// Next piece of the answer
+    pub content: Option<String>,
+}
+
 /// Message structure for API communication
 #[allow(unused)]
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
@@ -54,10 +93,106 @@ pub struct Message {
     pub role: String,
     /// Actual message content
     pub content: String,
-    /// Reasoning content (if applicable)
+    /// Reasoning returned in a dedicated field instead of `<think>` tags
+    #[serde(
+        default,
+        alias = "reasoning_content",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub reasoning: Option<String>,
 }
 
+/// Response of the Anthropic `/v1/messages` endpoint
+#[allow(unused)]
+#[derive(serde::Deserialize, Debug)]
+pub struct AnthropicAnswer {
+    /// Unique response identifier
+    pub id: String,
+    /// Model name used for generation
+    pub model: String,
+    /// Generated content blocks
+    pub content: Vec<AnthropicContent>,
+    /// Reason for completion
+    pub stop_reason: Option<String>,
+    /// Token usage statistics
+    pub usage: AnthropicUsage,
+}
+
+/// Single content block of an Anthropic response
+#[allow(unused)]
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicContent {
+    /// Answer text
+    Text { text: String },
+    /// Extended thinking
+    Thinking { thinking: String },
+    /// Tool calls, images and future block types
+    #[serde(other)]
+    Other,
+}
+
+/// Token usage statistics of an Anthropic response
+#[allow(unused)]
+#[derive(serde::Deserialize, Debug)]
+pub struct AnthropicUsage {
+    /// Number of tokens in the prompt
+    pub input_tokens: u32,
+    /// Number of tokens in the completion
+    pub output_tokens: u32,
+}
+
+impl From<AnthropicAnswer> for Answer {
+    fn from(answer: AnthropicAnswer) -> Self {
+        let mut content = String::new();
+        let mut reasoning = String::new();
+        for block in answer.content {
+            match block {
+                AnthropicContent::Text { text } => content.push_str(&text),
+                AnthropicContent::Thinking { thinking } => reasoning.push_str(&thinking),
+                AnthropicContent::Other => {}
+            }
+        }
+
+        Self {
+            id: answer.id,
+            object: "message".to_string(),
+            created: 0,
+            model: answer.model,
+            choices: vec![Choice {
+                index: 0,
+                logprobs: None,
+                finish_reason: answer.stop_reason.unwrap_or_default(),
+                message: Message {
+                    role: "assistant".to_string(),
+                    content,
+                    reasoning: (!reasoning.is_empty()).then_some(reasoning),
+                },
+            }],
+            usage: Usage {
+                prompt_tokens: answer.usage.input_tokens,
+                completion_tokens: answer.usage.output_tokens,
+                total_tokens: answer.usage.input_tokens + answer.usage.output_tokens,
+            },
+            system_fingerprint: String::new(),
+        }
+    }
+}
+
+/// Response of an OpenAI-compatible `/v1/embeddings` endpoint
+#[derive(serde::Deserialize, Debug)]
+pub struct EmbeddingResponse {
+    /// One entry per input
+    pub data: Vec<EmbeddingData>,
+}
+
+/// Single embedding in an embeddings response
+#[derive(serde::Deserialize, Debug)]
+pub struct EmbeddingData {
+    /// Embedding vector
+    pub embedding: Vec<f32>,
+}
+
 impl From<&Note> for Message {
     fn from(note: &Note) -> Self {
         Self {
@@ -1,3 +1,8 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
 use tracing_appender::rolling;
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -24,3 +29,54 @@ pub fn setup_tracing() {
         .with(file_layer)
         .init();
 }
+
+/// Path of today's log file as written by the daily rolling appender (UTC date)
+pub fn today_log_path() -> PathBuf {
+    Path::new("logs").join(format!("log.txt.{}", chrono::Utc::now().format("%Y-%m-%d")))
+}
+
+/// Reads the last `n` lines of a file without loading all of it
+///
+/// The file is scanned backwards in fixed-size blocks until enough line
+/// breaks are found, so memory use is bounded by the returned text.
+pub fn tail_lines(path: &Path, n: usize) -> io::Result<String> {
+    const BLOCK: u64 = 8 * 1024;
+
+    let mut file = File::open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut pos = len;
+    let mut buf: Vec<u8> = Vec::new();
+
+    // One extra line break accounts for the file's trailing newline
+    while pos > 0 && buf.iter().filter(|&&b| b == b'\n').count() <= n {
+        let read = BLOCK.min(pos);
+        pos -= read;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut block = vec![0; read as usize];
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&buf);
+        buf = block;
+    }
+
+    let text = String::from_utf8_lossy(&buf);
+    let lines: Vec<&str> = text.lines().collect();
+    Ok(lines[lines.len().saturating_sub(n)..].join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tail_lines_across_blocks() {
+        let path = std::env::temp_dir().join(format!("tail_test_{}.txt", std::process::id()));
+        let content: String = (0..5000).map(|i| format!("line {}\n", i)).collect();
+        std::fs::write(&path, content).unwrap();
+
+        assert_eq!(tail_lines(&path, 2).unwrap(), "line 4998\nline 4999");
+        assert_eq!(tail_lines(&path, 3000).unwrap().lines().count(), 3000);
+        assert_eq!(tail_lines(&path, 10_000).unwrap().lines().count(), 5000);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -4,24 +4,34 @@
 //! with Llama AI integration. Handles configuration loading and dispatcher setup.
 
 use config::Config;
-use dashmap::DashSet;
 use lazy_static::lazy_static;
 use std::sync::Arc;
-use telegram::get_storage_handler;
-use teloxide::prelude::*;
+use storage::Storage;
+use telegram::{BusyChats, BusySet, StartedAt, get_storage_handler, sync_commands};
+use teloxide::{dptree::di::DependencyMap, prelude::*, types::UserId};
 use tracing::{Level, event};
 
+mod answer_cache;
+mod answer_format;
+mod answer_schema;
 mod db;
+mod events;
 mod lm_types;
 mod logging;
+mod postprocess;
+mod provider;
+mod raw_answer;
+mod reply_chain;
 mod storage;
+mod summary;
 mod system;
 mod telegram;
 
 lazy_static! {
     /// Global configuration instance
     /// Initialized once and available throughout the application
-    static ref CONFIG: Config = system::get_config().expect("Unable to init config.");
+    static ref CONFIG: Config = system::get_config()
+        .unwrap_or_else(|e| panic!("Unable to read {}: {}", system::SETTINGS_PATH, e));
 }
 
 /// Custom error type for the application
@@ -36,18 +46,65 @@ pub type Error = Box<dyn std::error::Error + Send + Sync>;
 /// * `Result<(), Error>` - Success or error status of bot execution
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    let started_at = StartedAt(std::time::Instant::now());
     logging::setup_tracing();
 
+    // `req_to_llama replay <file>...` re-runs recorded responses offline
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("replay") {
+        for path in &args[2..] {
+            let chunks = system::replay_recording(std::path::Path::new(path))?;
+            println!("{} -> {} chunk(s)", path, chunks.len());
+            for chunk in chunks {
+                println!("{}\n---", chunk);
+            }
+        }
+        return Ok(());
+    }
+
     event!(Level::INFO, "Preconfigure...");
+    if !std::path::Path::new(system::SETTINGS_PATH).exists() {
+        event!(
+            Level::WARN,
+            "{} not found, reading configuration from APP_* environment variables",
+            system::SETTINGS_PATH
+        );
+    }
+    if let Err(problems) = system::validate_config(&CONFIG) {
+        for problem in &problems {
+            event!(Level::ERROR, "Configuration error: {}", problem);
+        }
+        return Err(format!(
+            "Configuration is incomplete:\n- {}\nSet these keys in {} (copy _settings.toml) or as environment variables named APP_<KEY>, e.g. APP_TOKEN, APP_URL and APP_MODEL",
+            problems.join("\n- "),
+            system::SETTINGS_PATH
+        )
+        .into());
+    }
+    if let Err(e) = system::check_model_allowed(
+        &CONFIG.get_string("model").unwrap_or_default(),
+        &system::allowed_models(),
+    ) {
+        event!(
+            Level::WARN,
+            "Default `model` is missing from allowed_models: {}",
+            e
+        );
+    }
+    system::seed_messages();
 
     // Load bot token from configuration
-    let token = CONFIG.get_string("token").unwrap_or(String::new());
+    let token = system::secret("token").unwrap_or_default();
 
     // Initialize bot instance
     let bot = Bot::new(token);
 
     event!(Level::INFO, "Starting bot...");
     event!(Level::INFO, "GetMe status: {:?}", bot.get_me().await);
+    if CONFIG.get_bool("sync_commands_on_start").unwrap_or(true) {
+        // A stale menu is only cosmetic, so failures don't stop the bot
+        let _ = sync_commands(&bot).await;
+    }
 
     // Initialize default handler
     let handler = get_storage_handler();
@@ -55,15 +112,18 @@ async fn main() -> Result<(), Error> {
     // Initialize storage
     let storage = storage::create_storage().await;
 
-    event!(Level::INFO, "Storage configured. DashSet initializing.");
-    let busy: Arc<DashSet<i64>> = Arc::new(DashSet::new());
+    event!(
+        Level::INFO,
+        "Storage configured. Busy tracker initializing."
+    );
+    let busy: BusySet = Arc::new(BusyChats::default());
 
     let bot_id = bot.get_me().await.unwrap().id;
 
-    event!(Level::INFO, "Dash set ready. Running dispatcher.");
+    event!(Level::INFO, "Busy tracker ready. Running dispatcher.");
     // Start the dispatcher with configured dependencies
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![storage, busy, bot_id])
+        .dependencies(dispatcher_dependencies(storage, busy, bot_id, started_at))
         .distribution_function(|upd| upd.chat().map(|c| c.id))
         .enable_ctrlc_handler()
         .build()
@@ -73,6 +133,16 @@ async fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// Everything the handlers of `get_storage_handler` take besides the update
+fn dispatcher_dependencies(
+    storage: Arc<dyn Storage>,
+    busy: BusySet,
+    bot_id: UserId,
+    started_at: StartedAt,
+) -> DependencyMap {
+    dptree::deps![storage, busy, bot_id, started_at]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,18 +183,18 @@ mod tests {
     }
 
     #[test]
-    fn test_dashset_busy_initialization() {
-        // Test that the busy DashSet can be created and used
-        let busy: Arc<DashSet<i64>> = Arc::new(DashSet::new());
+    fn test_busy_set_initialization() {
+        // Test that the busy tracker can be created and used
+        let busy: BusySet = Arc::new(BusyChats::default());
 
         // Test basic operations
-        assert!(busy.is_empty());
-        busy.insert(123);
+        assert!(!busy.contains(&123));
+        assert!(busy.try_acquire(123, None));
         assert!(busy.contains(&123));
-        assert_eq!(busy.len(), 1);
+        assert_eq!(busy.queued(123), 0);
 
-        busy.remove(&123);
-        assert!(busy.is_empty());
+        assert!(busy.release(123).is_none());
+        assert!(!busy.contains(&123));
     }
 
     #[tokio::test]
@@ -143,6 +213,20 @@ mod tests {
         assert!(result.is_ok(), "Handler initialization should not panic");
     }
 
+    #[tokio::test]
+    async fn test_dispatcher_builds_with_real_dependencies() {
+        // Building type-checks every handler against the dependencies, so a
+        // handler taking a type main doesn't provide fails here, not at runtime
+        let _dispatcher = Dispatcher::builder(Bot::new("0:test"), get_storage_handler())
+            .dependencies(dispatcher_dependencies(
+                storage::create_storage().await,
+                Arc::new(BusyChats::default()),
+                UserId(0),
+                StartedAt(std::time::Instant::now()),
+            ))
+            .build();
+    }
+
     #[test]
     fn test_config_token_access() {
         // Test accessing token from config
@@ -167,7 +251,7 @@ mod tests {
         let _storage = storage::create_storage().await;
 
         // Test busy set initialization
-        let _busy: Arc<DashSet<i64>> = Arc::new(DashSet::new());
+        let _busy: BusySet = Arc::new(BusyChats::default());
 
         // If we reach here, all components initialized successfully
         assert!(true);
@@ -0,0 +1,311 @@
+//! Post-processing Module
+//!
+//! Ordered chain of transformations applied to model answers before they are
+//! split into Telegram messages. The chain is configured by `post_processors`,
+//! a list of built-in step names run in the given order. With
+//! `trim_answer_whitespace` (on by default), `trim_whitespace` runs last
+//! unless the list already has it.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::str::FromStr;
+use tracing::{Level, event};
+
+use crate::CONFIG;
+
+const THINK_OPEN: &str = "<think>";
+
+static THINK_TAG_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)<think>.*?</think>").expect("valid regex"));
+
+static ROLE_PREFIX_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)^\s*(assistant|ai|bot)\s*:\s*").expect("valid regex"));
+
+/// Steps from `post_processors`; only `strip_think` when unset
+static STEPS: Lazy<Vec<Step>> = Lazy::new(|| {
+    let mut steps = match CONFIG.get::<Vec<String>>("post_processors") {
+        Ok(names) => names
+            .iter()
+            .filter_map(|name| match name.parse() {
+                Ok(step) => Some(step),
+                Err(e) => {
+                    event!(Level::WARN, "{}", e);
+                    None
+                }
+            })
+            .collect(),
+        Err(_) => vec![Step::StripThink],
+    };
+    if CONFIG.get_bool("trim_answer_whitespace").unwrap_or(true)
+        && !steps.contains(&Step::TrimWhitespace)
+    {
+        steps.push(Step::TrimWhitespace);
+    }
+    steps
+});
+
+static BLANK_LINES_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n{4,}").expect("valid regex"));
+
+/// Patterns from `redact_patterns`; invalid ones are skipped with a warning
+static REDACT_PATTERNS: Lazy<Vec<Regex>> = Lazy::new(|| {
+    CONFIG
+        .get::<Vec<String>>("redact_patterns")
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|pattern| match Regex::new(pattern) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                event!(Level::WARN, "Invalid redact pattern {:?}: {}", pattern, e);
+                None
+            }
+        })
+        .collect()
+});
+
+/// A built-in post-processing step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    /// Remove `<think>` blocks, unless the chat shows reasoning
+    StripThink,
+    /// Remove a leading "Assistant:"-style role label
+    StripRolePrefix,
+    /// Replace matches of `redact_patterns` with "[redacted]"
+    Redact,
+    /// Append `response_footer`
+    Footer,
+    /// Remove surrounding whitespace
+    Trim,
+    /// Normalize line endings, drop extra blank lines, then trim
+    TrimWhitespace,
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "strip_think" => Ok(Step::StripThink),
+            "strip_role_prefix" => Ok(Step::StripRolePrefix),
+            "redact" => Ok(Step::Redact),
+            "footer" => Ok(Step::Footer),
+            "trim" => Ok(Step::Trim),
+            "trim_whitespace" => Ok(Step::TrimWhitespace),
+            other => Err(format!("Unknown post processor: {}", other)),
+        }
+    }
+}
+
+/// Per-answer inputs of the steps
+pub struct Context {
+    /// Keep `<think>` blocks
+    pub show_thinking: bool,
+}
+
+impl Step {
+    pub fn apply(&self, text: String, ctx: &Context) -> String {
+        match self {
+            Step::StripThink if ctx.show_thinking => text,
+            Step::StripThink => strip_think(&text),
+            Step::StripRolePrefix => strip_role_prefix(&text),
+            Step::Redact => redact(&text, &REDACT_PATTERNS),
+            Step::Footer => footer(
+                text,
+                &CONFIG.get_string("response_footer").unwrap_or_default(),
+            ),
+            Step::Trim => text.trim().to_string(),
+            Step::TrimWhitespace => trim_whitespace(&text),
+        }
+    }
+}
+
+pub fn strip_think(text: &str) -> String {
+    THINK_TAG_RE.replace_all(text, "").into_owned()
+}
+
+fn strip_role_prefix(text: &str) -> String {
+    ROLE_PREFIX_RE.replace(text, "").into_owned()
+}
+
+fn redact(text: &str, patterns: &[Regex]) -> String {
+    patterns.iter().fold(text.to_string(), |text, re| {
+        re.replace_all(&text, "[redacted]").into_owned()
+    })
+}
+
+fn footer(text: String, footer: &str) -> String {
+    if footer.is_empty() || text.trim().is_empty() {
+        return text;
+    }
+    format!("{}\n\n{}", text, footer)
+}
+
+/// Tidies whitespace that only wastes vertical space in Telegram
+///
+/// CRLF becomes LF, runs of more than two blank lines collapse into two and
+/// the whole text is trimmed. The lines themselves are left as they are:
+/// trailing spaces may be a Markdown line break.
+fn trim_whitespace(text: &str) -> String {
+    let text = text.replace("\r\n", "\n");
+    BLANK_LINES_RE
+        .replace_all(&text, "\n\n\n")
+        .trim()
+        .to_string()
+}
+
+/// Visible part of an answer that is still being streamed
+///
+/// Applies the configured `strip_think` and `redact` steps to the text
+/// received so far. An unfinished `<think>` block stays hidden until it
+/// closes, and with redaction only complete lines are shown, so a secret
+/// can't show before its pattern matches. The final answer still goes
+/// through the whole chain.
+#[derive(Default)]
+pub struct StreamPreview {
+    shown: String,
+}
+
+impl StreamPreview {
+    /// Takes the answer received so far
+    ///
+    /// # Returns
+    /// Text to append to what is already shown, `None` when there is none
+    pub fn update(&mut self, received: &str, ctx: &Context) -> Option<String> {
+        self.advance(preview(received, &STEPS, &REDACT_PATTERNS, ctx))
+    }
+
+    fn advance(&mut self, visible: String) -> Option<String> {
+        // Text already shown can't be taken back; it is fixed by the final answer
+        let delta = visible.strip_prefix(self.shown.as_str())?.to_string();
+        if delta.is_empty() {
+            return None;
+        }
+        self.shown = visible;
+        Some(delta)
+    }
+}
+
+/// What `steps` let through of a partly received answer
+fn preview(received: &str, steps: &[Step], patterns: &[Regex], ctx: &Context) -> String {
+    let mut text = received.to_string();
+    if steps.contains(&Step::StripThink) && !ctx.show_thinking {
+        text = strip_think(&text);
+        if let Some(open) = text.find(THINK_OPEN) {
+            text.truncate(open);
+        }
+        // "<thi" may be the start of a tag that is still arriving
+        let partial = (1..THINK_OPEN.len())
+            .rev()
+            .find(|&len| text.ends_with(&THINK_OPEN[..len]))
+            .unwrap_or(0);
+        text.truncate(text.len() - partial);
+    }
+    if steps.contains(&Step::Redact) && !patterns.is_empty() {
+        text.truncate(text.rfind('\n').map_or(0, |end| end + 1));
+        text = redact(&text, patterns);
+    }
+    text
+}
+
+/// Runs a chain of steps in order
+pub fn run_steps(content: &str, steps: &[Step], ctx: &Context) -> String {
+    steps
+        .iter()
+        .fold(content.to_string(), |text, step| step.apply(text, ctx))
+}
+
+/// Runs the configured `post_processors` chain
+pub fn run(content: &str, ctx: &Context) -> String {
+    run_steps(content, &STEPS, ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> Context {
+        Context {
+            show_thinking: false,
+        }
+    }
+
+    #[test]
+    fn test_strip_think_respects_show_thinking() {
+        let text = "<think>hmm</think>Answer".to_string();
+        assert_eq!(Step::StripThink.apply(text.clone(), &ctx()), "Answer");
+        let shown = Context {
+            show_thinking: true,
+        };
+        assert_eq!(Step::StripThink.apply(text.clone(), &shown), text);
+    }
+
+    #[test]
+    fn test_strip_role_prefix_only_at_start() {
+        assert_eq!(strip_role_prefix("Assistant: Hi, AI: here"), "Hi, AI: here");
+        assert_eq!(strip_role_prefix("  bot:Hello"), "Hello");
+        assert_eq!(strip_role_prefix("No label"), "No label");
+    }
+
+    #[test]
+    fn test_redact_and_footer() {
+        let patterns = [Regex::new(r"\d{4}-\d{4}").unwrap()];
+        assert_eq!(redact("card 1234-5678 ok", &patterns), "card [redacted] ok");
+        assert_eq!(footer("Answer".into(), "-- bot"), "Answer\n\n-- bot");
+        assert_eq!(footer("   ".into(), "-- bot"), "   ");
+        assert_eq!(footer("Answer".into(), ""), "Answer");
+    }
+
+    #[test]
+    fn test_steps_run_in_order() {
+        let text = "<think>x</think>\n  Assistant: Hi  ";
+        let strip_first = [Step::StripThink, Step::Trim, Step::StripRolePrefix];
+        assert_eq!(run_steps(text, &strip_first, &ctx()), "Hi");
+
+        // The role label is not at the start while the think block is still there
+        let prefix_first = [Step::StripRolePrefix, Step::StripThink, Step::Trim];
+        assert_eq!(run_steps(text, &prefix_first, &ctx()), "Assistant: Hi");
+    }
+
+    #[test]
+    fn test_trim_whitespace() {
+        let text = "\r\n  \n  Hello  \r\nworld\r\n\r\n\r\n\r\n  - item\n\n\n\nend \n\n";
+        assert_eq!(
+            trim_whitespace(text),
+            "Hello  \nworld\n\n\n  - item\n\n\nend"
+        );
+        // Up to two blank lines, indentation and trailing spaces are kept
+        assert_eq!(trim_whitespace("a\n\n    code"), "a\n\n    code");
+        assert_eq!(trim_whitespace("a\n\n\nb"), "a\n\n\nb");
+        assert_eq!(trim_whitespace("line  \nbreak"), "line  \nbreak");
+        assert_eq!("Trim_Whitespace".parse::<Step>(), Ok(Step::TrimWhitespace));
+    }
+
+    #[test]
+    fn test_stream_preview_hides_thinking_and_secrets() {
+        let steps = [Step::StripThink, Step::Redact];
+        let patterns = [Regex::new(r"\d{4}-\d{4}").unwrap()];
+        let mut shown = StreamPreview::default();
+        let mut feed = |received: &str| shown.advance(preview(received, &steps, &patterns, &ctx()));
+
+        assert_eq!(feed("<thi"), None);
+        assert_eq!(feed("<think>secret plan"), None);
+        assert_eq!(
+            feed("<think>secret plan</think>Your card\n"),
+            Some("Your card\n".into())
+        );
+        // The number is held back until its line is complete
+        assert_eq!(feed("<think>secret plan</think>Your card\nis 1234-"), None);
+        assert_eq!(
+            feed("<think>secret plan</think>Your card\nis 1234-5678\n"),
+            Some("is [redacted]\n".into())
+        );
+
+        // Shown when the chat shows reasoning
+        let showing = Context {
+            show_thinking: true,
+        };
+        assert_eq!(
+            preview("<think>plan", &[Step::StripThink], &[], &showing),
+            "<think>plan"
+        );
+    }
+}
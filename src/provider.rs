@@ -0,0 +1,230 @@
+//! Provider Module
+//!
+//! Request and response shapes of the supported chat APIs. `provider`
+//! selects `openai` (any OpenAI-compatible `/v1/chat/completions`, the
+//! default) or `anthropic` (`/v1/messages`). The rest of the bot works with
+//! the OpenAI types from `lm_types`; this module converts at the boundary.
+
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use std::str::FromStr;
+
+use crate::{
+    CONFIG,
+    lm_types::{Answer, AnthropicAnswer, Message},
+    system::{self, RoleNormalization},
+};
+
+/// API version sent to Anthropic unless `anthropic_version` is set
+const DEFAULT_ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// API family of the configured `url`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Provider {
+    #[default]
+    OpenAi,
+    Anthropic,
+}
+
+impl FromStr for Provider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "openai" => Ok(Provider::OpenAi),
+            "anthropic" => Ok(Provider::Anthropic),
+            other => Err(format!("Unknown provider: {}", other)),
+        }
+    }
+}
+
+impl Provider {
+    /// Reads `provider` from configuration, defaulting to `openai`
+    pub fn from_config() -> Self {
+        CONFIG
+            .get_string("provider")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Content type and authentication headers
+    ///
+    /// The key header is marked sensitive, so `Debug` output never shows it.
+    pub fn headers(self, api_key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+
+        match self {
+            Provider::OpenAi => {
+                if !api_key.is_empty() {
+                    if let Ok(mut value) = HeaderValue::from_str(&format!("Bearer {}", api_key)) {
+                        value.set_sensitive(true);
+                        headers.insert(header::AUTHORIZATION, value);
+                    }
+                }
+            }
+            Provider::Anthropic => {
+                if !api_key.is_empty() {
+                    if let Ok(mut value) = HeaderValue::from_str(api_key) {
+                        value.set_sensitive(true);
+                        headers.insert("x-api-key", value);
+                    }
+                }
+                let version = CONFIG
+                    .get_string("anthropic_version")
+                    .unwrap_or_else(|_| DEFAULT_ANTHROPIC_VERSION.to_string());
+                if let Ok(value) = HeaderValue::from_str(&version) {
+                    headers.insert("anthropic-version", value);
+                }
+            }
+        }
+        headers
+    }
+
+    /// Builds the request body for a completion
+    ///
+    /// For Anthropic, system messages are joined into the top-level `system`
+    /// field and the remaining turns are merged so roles alternate, starting
+    /// with the user.
+    pub fn request_body(
+        self,
+        model: &str,
+        messages: &[Message],
+        temperature: f32,
+        max_tokens: usize,
+    ) -> serde_json::Value {
+        match self {
+            Provider::OpenAi => serde_json::json!({
+                "model": model,
+                "messages": messages,
+                "temperature": temperature,
+                "max_tokens": max_tokens,
+                "stream": false
+            }),
+            Provider::Anthropic => {
+                let (system, turns) = anthropic_messages(messages);
+                let mut body = serde_json::json!({
+                    "model": model,
+                    "messages": turns,
+                    "temperature": temperature,
+                    "max_tokens": max_tokens,
+                    "stream": false
+                });
+                if !system.is_empty() {
+                    body["system"] = serde_json::json!(system);
+                }
+                body
+            }
+        }
+    }
+
+    /// Parses a response body into the OpenAI envelope
+    pub fn parse_answer(self, raw: &str) -> Result<Answer, serde_json::Error> {
+        match self {
+            Provider::OpenAi => system::parse_answer(raw),
+            Provider::Anthropic => serde_json::from_str::<AnthropicAnswer>(raw).map(Answer::from),
+        }
+    }
+
+    /// Whether the API accepts OpenAI's `logit_bias`
+    pub fn supports_logit_bias(self) -> bool {
+        self == Provider::OpenAi
+    }
+
+    /// Whether answers can be streamed as OpenAI `chat.completion.chunk` frames
+    pub fn supports_streaming(self) -> bool {
+        self == Provider::OpenAi
+    }
+}
+
+/// Splits messages into Anthropic's `system` string and alternating turns
+fn anthropic_messages(messages: &[Message]) -> (String, Vec<serde_json::Value>) {
+    let (system, turns): (Vec<Message>, Vec<Message>) = messages
+        .iter()
+        .cloned()
+        .partition(|message| message.role == "system");
+    let system = system
+        .into_iter()
+        .map(|message| message.content)
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    let mut turns = system::normalize_roles(turns, RoleNormalization::Merge);
+    if turns.first().is_some_and(|message| message.role != "user") {
+        turns.insert(
+            0,
+            Message {
+                role: "user".to_string(),
+                content: "...".to_string(),
+                reasoning: None,
+            },
+        );
+    }
+
+    let turns = turns
+        .into_iter()
+        .map(|message| serde_json::json!({"role": message.role, "content": message.content}))
+        .collect();
+    (system, turns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            reasoning: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_anthropic_answer() {
+        let raw = r#"{
+            "id": "msg_01XFDUDYJgAACzvnptvVoYEL",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-5-sonnet-20241022",
+            "content": [
+                {"type": "thinking", "thinking": "A greeting.", "signature": "EqQBCgIYAhIM"},
+                {"type": "text", "text": "Hello! How can I help you today?"}
+            ],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 12, "output_tokens": 15}
+        }"#;
+
+        let answer = Provider::Anthropic.parse_answer(raw).unwrap();
+        assert_eq!(answer.model, "claude-3-5-sonnet-20241022");
+        assert_eq!(answer.choices[0].finish_reason, "end_turn");
+        let message = &answer.choices[0].message;
+        assert_eq!(message.content, "Hello! How can I help you today?");
+        assert_eq!(message.reasoning.as_deref(), Some("A greeting."));
+        assert_eq!(answer.usage.total_tokens, 27);
+    }
+
+    #[test]
+    fn test_anthropic_request_body() {
+        let messages = [
+            message("system", "You are terse."),
+            message("system", "Reply in English."),
+            message("assistant", "Hi!"),
+            message("user", "note"),
+            message("user", "question"),
+        ];
+
+        let body = Provider::Anthropic.request_body("claude", &messages, 0.5, 1024);
+        assert_eq!(body["system"], "You are terse.\n\nReply in English.");
+        assert_eq!(body["max_tokens"], 1024);
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([
+                {"role": "user", "content": "..."},
+                {"role": "assistant", "content": "Hi!"},
+                {"role": "user", "content": "note\n\nquestion"}
+            ])
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Raw Answer Module
+//!
+//! Debugging aid for bot owners. After `/raw on`, the next request of a chat
+//! also sends the model's answer as a document, exactly as it came back:
+//! before `<think>` stripping, post-processing and splitting into messages.
+//! The mode covers one request and is kept in memory only.
+
+use dashmap::{DashMap, DashSet};
+use once_cell::sync::Lazy;
+
+/// File name of the document with the raw answer
+pub const RAW_FILE_NAME: &str = "raw_answer.txt";
+
+/// Chats waiting for their raw answer, and what their requests captured
+pub static RAW_ANSWERS: Lazy<RawAnswers> = Lazy::new(RawAnswers::default);
+
+#[derive(Default)]
+pub struct RawAnswers {
+    /// Chats whose next request captures its answers
+    armed: DashSet<i64>,
+    /// Answers of the request, one per attempt (retries, schema repair)
+    captured: DashMap<i64, Vec<String>>,
+}
+
+impl RawAnswers {
+    /// Turns raw mode on for the chat's next request, or off again
+    pub fn arm(&self, chat_id: i64, on: bool) {
+        if on {
+            self.armed.insert(chat_id);
+        } else {
+            self.armed.remove(&chat_id);
+            self.captured.remove(&chat_id);
+        }
+    }
+
+    pub fn is_armed(&self, chat_id: i64) -> bool {
+        self.armed.contains(&chat_id)
+    }
+
+    /// Called when a request reaches the model; resets the one-shot flag
+    ///
+    /// # Returns
+    /// `true` when the request should capture its answers
+    pub fn start(&self, chat_id: i64) -> bool {
+        self.armed.remove(&chat_id).is_some()
+    }
+
+    /// Keeps an answer as the model sent it
+    pub fn capture(&self, chat_id: i64, content: &str) {
+        self.captured
+            .entry(chat_id)
+            .or_default()
+            .push(content.to_string());
+    }
+
+    /// Removes the captured answers of the chat, ready to be sent
+    ///
+    /// # Returns
+    /// `None` when nothing was captured; with several attempts, each is
+    /// numbered
+    pub fn take(&self, chat_id: i64) -> Option<String> {
+        let (_, answers) = self.captured.remove(&chat_id)?;
+        if answers.len() == 1 {
+            return answers.into_iter().next();
+        }
+        Some(
+            answers
+                .iter()
+                .enumerate()
+                .map(|(i, answer)| format!("=== Attempt {} ===\n{}", i + 1, answer))
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_mode_is_one_shot() {
+        let raw = RawAnswers::default();
+        assert!(!raw.start(1));
+
+        raw.arm(1, true);
+        assert!(raw.is_armed(1));
+        assert!(raw.start(1));
+        raw.capture(1, "<think>hm</think>Answer");
+        assert!(!raw.start(1));
+        assert_eq!(raw.take(1).as_deref(), Some("<think>hm</think>Answer"));
+        assert_eq!(raw.take(1), None);
+
+        raw.arm(2, true);
+        raw.arm(2, false);
+        assert!(!raw.start(2));
+    }
+
+    #[test]
+    fn test_attempts_are_numbered() {
+        let raw = RawAnswers::default();
+        raw.capture(1, "<think>only thinking</think>");
+        raw.capture(1, "Answer");
+        assert_eq!(
+            raw.take(1).unwrap(),
+            "=== Attempt 1 ===\n<think>only thinking</think>\n\n=== Attempt 2 ===\nAnswer"
+        );
+    }
+}
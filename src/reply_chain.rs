@@ -0,0 +1,145 @@
+//! Reply Chain Module
+//!
+//! In groups, a reply to one of the bot's answers continues that exchange,
+//! not everything said in the chat. Telegram only tells us the message a
+//! reply answers, not what that one answered in turn, so the requests the
+//! bot handles in groups and the messages of its answers are remembered
+//! here with their parent. A follow-up is then answered with the
+//! sub-conversation its reply chain leads to, at most
+//! `max_reply_chain_depth` messages, instead of the shared group history.
+//! The links are kept in memory only; after a restart a chain ends at the
+//! message it replies to.
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use teloxide::types::MessageId;
+
+use crate::{CONFIG, lm_types::Message};
+
+/// Messages remembered per chat; the oldest are forgotten first
+const MAX_LINKS_PER_CHAT: usize = 1000;
+
+/// Reply links of the group chats the bot answers in
+pub static REPLY_CHAINS: Lazy<ReplyChains> = Lazy::new(ReplyChains::default);
+
+/// Most messages a reply chain contributes, from `max_reply_chain_depth`
+///
+/// 0 turns reply chains off: replies get the group history like any other
+/// message.
+pub fn max_depth() -> usize {
+    CONFIG.get("max_reply_chain_depth").unwrap_or(10)
+}
+
+/// A message of a conversation and the message it replies to
+struct Link {
+    message_id: MessageId,
+    parent: Option<MessageId>,
+    role: String,
+    content: String,
+}
+
+#[derive(Default)]
+pub struct ReplyChains {
+    chats: DashMap<i64, VecDeque<Link>>,
+}
+
+impl ReplyChains {
+    /// Remembers a message of the conversation with the message it replies to
+    ///
+    /// A message that is already known keeps its link.
+    pub fn record(
+        &self,
+        chat_id: i64,
+        message_id: MessageId,
+        parent: Option<MessageId>,
+        role: &str,
+        content: &str,
+    ) {
+        let mut links = self.chats.entry(chat_id).or_default();
+        if links.iter().any(|link| link.message_id == message_id) {
+            return;
+        }
+        if links.len() >= MAX_LINKS_PER_CHAT {
+            links.pop_front();
+        }
+        links.push_back(Link {
+            message_id,
+            parent,
+            role: role.to_string(),
+            content: content.to_string(),
+        });
+    }
+
+    /// The conversation leading to `message_id`, oldest message first
+    ///
+    /// Follows replies back from `message_id` itself until a message that
+    /// isn't remembered, or `max_depth` messages.
+    pub fn chain(&self, chat_id: i64, message_id: MessageId, max_depth: usize) -> Vec<Message> {
+        let Some(links) = self.chats.get(&chat_id) else {
+            return Vec::new();
+        };
+        let mut turns = Vec::new();
+        let mut next = Some(message_id);
+        while let Some(id) = next.filter(|_| turns.len() < max_depth) {
+            let Some(link) = links.iter().find(|link| link.message_id == id) else {
+                break;
+            };
+            turns.push(Message {
+                role: link.role.clone(),
+                content: link.content.clone(),
+                reasoning: None,
+            });
+            next = link.parent;
+        }
+        turns.reverse();
+        turns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_three_deep_chain_is_followed_and_bounded() {
+        let chains = ReplyChains::default();
+        let id = MessageId;
+        // Unrelated chatter in between is not part of the chain
+        chains.record(-1, id(1), None, "user", "What is Rust?");
+        chains.record(-1, id(2), Some(id(1)), "assistant", "A language.");
+        chains.record(-1, id(3), None, "user", "Lunch anyone?");
+        chains.record(-1, id(4), Some(id(2)), "user", "Who made it?");
+        chains.record(-1, id(5), Some(id(4)), "assistant", "Mozilla.");
+        chains.record(-1, id(6), Some(id(5)), "user", "When?");
+        chains.record(-1, id(7), Some(id(6)), "assistant", "In 2010.");
+        chains.record(-1, id(8), Some(id(7)), "user", "Is it fast?");
+
+        let contents = |turns: Vec<Message>| -> Vec<String> {
+            turns.into_iter().map(|turn| turn.content).collect()
+        };
+        assert_eq!(
+            contents(chains.chain(-1, id(8), 10)),
+            [
+                "What is Rust?",
+                "A language.",
+                "Who made it?",
+                "Mozilla.",
+                "When?",
+                "In 2010.",
+                "Is it fast?"
+            ]
+        );
+        assert_eq!(
+            contents(chains.chain(-1, id(8), 3)),
+            ["When?", "In 2010.", "Is it fast?"]
+        );
+        assert_eq!(chains.chain(-1, id(8), 0).len(), 0);
+        // Other chats have chains of their own
+        assert!(chains.chain(-2, id(8), 10).is_empty());
+
+        // A known message keeps its link
+        chains.record(-1, id(8), None, "user", "Changed");
+        assert_eq!(chains.chain(-1, id(8), 10).len(), 7);
+    }
+}
@@ -1,20 +1,30 @@
-use sqlx::{Executor, Pool, Sqlite, query};
-use std::sync::Arc;
+use sqlx::{Execute, Executor, Pool, Sqlite, query, sqlite::SqliteQueryResult};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use teloxide::types::ThreadId;
 use tracing::{Level, event};
 
 use async_trait::async_trait;
 
 use crate::{
-    CONFIG, Error, db,
+    CONFIG, Error,
+    answer_format::AnswerFormat,
+    db,
     lm_types::Message,
-    storage::{Note, Storage},
+    storage::{
+        ChatLogEntry, ChatSettings, ChatSlots, Note, NoteFilter, Storage,
+        groups_enabled_by_default, toggled,
+    },
+    system::Brevity,
 };
 
 pub struct DbStorage {
     // Структура для работы с БД
     db: Arc<Pool<Sqlite>>,
     max_conv_len: usize,
+    /// Context rows kept per chat, as a multiple of `max_conv_len` (0 keeps all)
+    max_stored_context: usize,
+    /// Seconds within which a user's notes merge, see `Note::should_merge`
+    note_merge_window: i64,
 }
 
 impl DbStorage {
@@ -26,6 +36,8 @@ impl DbStorage {
             let db = Self {
                 db: Arc::new(db),
                 max_conv_len: CONFIG.get("max_conversation_len").unwrap_or(20),
+                max_stored_context: max_stored_context(),
+                note_merge_window: CONFIG.get("note_merge_window_secs").unwrap_or(0),
             };
             event!(Level::INFO, "init_db return self!");
             return Ok(db);
@@ -33,16 +45,166 @@ impl DbStorage {
             panic!("Failed to initialize database: {:?}", db.err());
         }
     }
+
+    /// Creates a storage on top of an already initialized pool
+    pub fn with_pool(db: Pool<Sqlite>, max_conv_len: usize) -> Self {
+        Self {
+            db: Arc::new(db),
+            max_conv_len,
+            max_stored_context: max_stored_context(),
+            note_merge_window: CONFIG.get("note_merge_window_secs").unwrap_or(0),
+        }
+    }
+
+    /// Deletes old context rows so the table does not grow without bound
+    ///
+    /// Keeps the newest `max_conv_len * max_stored_context` rows of the chat
+    /// and its pinned message.
+    async fn prune_context(&self, chat_id: i64) {
+        if self.max_stored_context == 0 {
+            return;
+        }
+        let keep = (self.max_conv_len * self.max_stored_context) as i64;
+        match self
+            .execute_with_retry(|| {
+                query!(
+                    "DELETE FROM context 
+                WHERE user_id = $1 
+                    AND id <= (SELECT id FROM context WHERE user_id = $1 ORDER BY id DESC LIMIT 1 OFFSET $2) 
+                    AND id IS NOT (SELECT pinned_id FROM users WHERE user_id = $1)",
+                    chat_id,
+                    keep
+                )
+            })
+            .await
+        {
+            Ok(result) if result.rows_affected() > 0 => event!(
+                Level::DEBUG,
+                "Pruned {} context rows of chat {}",
+                result.rows_affected(),
+                chat_id
+            ),
+            Ok(_) => {}
+            Err(e) => event!(Level::WARN, "Failed to prune context of chat {}: {}", chat_id, e),
+        }
+    }
+
+    /// Inserts a note as is, keeping an existing note with the same id
+    async fn insert_note(&self, note: &Note) -> Result<SqliteQueryResult, sqlx::Error> {
+        let user_id = note.user_id as i64;
+        let embedding = note
+            .embedding
+            .as_ref()
+            .and_then(|embedding| serde_json::to_string(embedding).ok());
+        self.execute_with_retry(|| {
+            query!(
+                "INSERT INTO notes (note_id, chat_id, user_id, text, created_at, embedding) 
+                VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT(chat_id, note_id) DO NOTHING",
+                note.note_id,
+                note.chat_id,
+                user_id,
+                note.text,
+                note.created_at,
+                embedding
+            )
+        })
+        .await
+    }
+
+    /// `/enable` and `/disable` state of a chat, `None` until either was used
+    async fn chat_settings(&self, chat_id: i64) -> Option<ChatSettings> {
+        let qr = query!("SELECT settings FROM chat_settings WHERE chat_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok().and_then(|row| serde_json::from_str(&row.settings).ok())
+    }
+
+    async fn save_chat_settings(&self, chat_id: i64, settings: &ChatSettings) {
+        let Ok(json) = serde_json::to_string(settings) else {
+            return;
+        };
+        event!(
+            Level::INFO,
+            "Save_chat_settings: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO chat_settings(chat_id, settings) 
+                VALUES ($1, $2) 
+            ON CONFLICT(chat_id) 
+                DO UPDATE SET settings = $2 
+                WHERE chat_id = $1",
+                chat_id,
+                json
+            ))
+            .await
+        );
+    }
+
+    /// Executes a write query, retrying while SQLite reports the database as busy
+    ///
+    /// `busy_timeout` covers most contention; this is the safety net for
+    /// the cases where SQLite gives up immediately (e.g. lock upgrades).
+    async fn execute_with_retry<'q, E, F>(
+        &self,
+        make_query: F,
+    ) -> Result<SqliteQueryResult, sqlx::Error>
+    where
+        F: Fn() -> E,
+        E: Execute<'q, Sqlite> + 'q,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.db.execute(make_query()).await {
+                Err(err) if attempt < WRITE_ATTEMPTS && is_busy(&err) => {
+                    event!(
+                        Level::WARN,
+                        "Database busy, retrying write (attempt {}): {}",
+                        attempt,
+                        err
+                    );
+                    tokio::time::sleep(Duration::from_millis(50 * attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Reads `max_stored_context`, defaulting to twice the conversation length
+fn max_stored_context() -> usize {
+    CONFIG.get("max_stored_context").unwrap_or(2)
+}
+
+/// Maximum number of attempts for a single write query
+const WRITE_ATTEMPTS: u64 = 5;
+
+/// Checks whether an error is SQLITE_BUSY or SQLITE_LOCKED (including extended codes)
+fn is_busy(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .and_then(|e| e.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| matches!(code & 0xff, 5 | 6))
 }
 
 // Реализация трейта для DbStorage
 #[async_trait]
 impl Storage for DbStorage {
+    fn backend_name(&self) -> &'static str {
+        "sqlite"
+    }
+
     // Реализация методов с использованием БД
     async fn get_conversation_context(&self, user_id: i64) -> Vec<Message> {
-        let qr = query!("SELECT context_len FROM users WHERE user_id = $1", user_id)
-            .fetch_one(&*self.db)
-            .await;
+        self.get_conversation_with_pin(user_id).await.0
+    }
+
+    async fn get_conversation_with_pin(&self, user_id: i64) -> (Vec<Message>, Option<usize>) {
+        let qr = query!(
+            "SELECT context_len, pinned_id FROM users WHERE user_id = $1",
+            user_id
+        )
+        .fetch_one(&*self.db)
+        .await;
 
         let max_conversation_len = self.max_conv_len as i64;
         if let Ok(row) = qr {
@@ -52,47 +214,77 @@ impl Storage for DbStorage {
                 } else {
                     row.context_len
                 };
+                let pinned_id = row.pinned_id;
                 let qr = query!(
-                    "SELECT message, responder FROM context WHERE user_id = $1 ORDER BY id DESC LIMIT $2",
+                    "SELECT id, message, responder FROM context WHERE user_id = $1 ORDER BY id DESC LIMIT $2",
                     user_id,
                     len
                 ).fetch_all(&*self.db).await;
                 if let Ok(rows) = qr {
                     let mut messages = Vec::new();
-                    for row in rows {
+                    let mut ids = Vec::new();
+                    for row in &rows {
                         messages.push(Message {
-                            content: row.message,
-                            role: row.responder,
+                            content: row.message.clone(),
+                            role: row.responder.clone(),
                             reasoning: None,
                         });
+                        ids.push(row.id);
+                    }
+
+                    // The pinned message replaces the oldest one once it falls out of the window
+                    let oldest = rows.last().map(|row| row.id);
+                    if let (Some(pinned_id), Some(oldest)) = (pinned_id, oldest) {
+                        if pinned_id < oldest {
+                            let pinned = query!(
+                                "SELECT message, responder FROM context WHERE id = $1",
+                                pinned_id
+                            )
+                            .fetch_one(&*self.db)
+                            .await;
+                            if let Ok(pinned) = pinned {
+                                if messages.len() as i64 >= len {
+                                    messages.pop();
+                                    ids.pop();
+                                }
+                                messages.push(Message {
+                                    content: pinned.message,
+                                    role: pinned.responder,
+                                    reasoning: None,
+                                });
+                                ids.push(pinned_id);
+                            }
+                        }
                     }
+
                     messages.reverse();
-                    return messages;
+                    ids.reverse();
+                    let pinned =
+                        pinned_id.and_then(|pinned_id| ids.iter().position(|id| *id == pinned_id));
+                    return (messages, pinned);
                 }
             }
         }
-        vec![]
+        (vec![], None)
     }
 
     async fn set_conversation_context(&self, chat_id: i64, context: Message) {
         event!(
             Level::INFO,
             "Set conversation 1: {:?}",
-            self.db
-                .execute(query!(
-                    "INSERT INTO context (user_id, message, responder) VALUES ($1, $2, $3)",
-                    chat_id,
-                    context.content,
-                    context.role
-                ))
-                .await
+            self.execute_with_retry(|| query!(
+                "INSERT INTO context (user_id, message, responder) VALUES ($1, $2, $3)",
+                chat_id,
+                context.content,
+                context.role
+            ))
+            .await
         );
         event!(
             Level::INFO,
             "Update user context_len: {:?}",
-            self.db
-                .execute(query!(
-                    "INSERT INTO users (user_id, context_len) 
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users (user_id, context_len) 
                 VALUES ($1, 1) 
             ON CONFLICT(user_id)
             DO UPDATE SET context_len = context_len + 1 WHERE user_id = $1",
@@ -100,23 +292,71 @@ impl Storage for DbStorage {
                 ))
                 .await
         );
+        self.prune_context(chat_id).await;
     }
 
     async fn clear_conversation_context(&self, chat_id: i64) {
         event!(
             Level::INFO,
             "clear_conversation: {:?}",
-            self.db
-                .execute(query!(
-                    "INSERT INTO users (user_id, context_len) 
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users (user_id, context_len) 
                 VALUES ($1, $2) 
             ON CONFLICT(user_id) 
-                DO UPDATE SET context_len = 0 
+                DO UPDATE SET context_len = 0, pinned_id = NULL 
                 WHERE user_id = $1",
-                    chat_id,
-                    0
-                ))
-                .await
+                chat_id,
+                0
+            ))
+            .await
+        );
+    }
+
+    async fn pin_last_user_message(&self, chat_id: i64) -> bool {
+        let context_len = query!("SELECT context_len FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await
+            .map(|row| row.context_len)
+            .unwrap_or(0);
+
+        // Only messages since the last clear belong to the conversation
+        let last_user = query!(
+            "SELECT id FROM (SELECT id, responder FROM context WHERE user_id = $1 ORDER BY id DESC LIMIT $2) 
+            WHERE responder = 'user' 
+            LIMIT 1",
+            chat_id,
+            context_len
+        )
+        .fetch_optional(&*self.db)
+        .await
+        .ok()
+        .flatten();
+        let Some(row) = last_user else {
+            return false;
+        };
+
+        event!(
+            Level::INFO,
+            "Pin_message: {:?}",
+            self.execute_with_retry(|| query!(
+                "UPDATE users SET pinned_id = $2 WHERE user_id = $1",
+                chat_id,
+                row.id
+            ))
+            .await
+        );
+        true
+    }
+
+    async fn unpin_message(&self, chat_id: i64) {
+        event!(
+            Level::INFO,
+            "Unpin_message: {:?}",
+            self.execute_with_retry(|| query!(
+                "UPDATE users SET pinned_id = NULL WHERE user_id = $1",
+                chat_id
+            ))
+            .await
         );
     }
 
@@ -135,9 +375,8 @@ impl Storage for DbStorage {
         event!(
             Level::INFO,
             "set_sestem_fingerprint: {:?}",
-            self.db
-                .execute(query!(
-                    "INSERT INTO users(user_id, system, context_len) 
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, system, context_len) 
                 VALUES ($1, $2, 0) 
             ON CONFLICT(user_id) 
                 DO UPDATE SET system = $2 
@@ -149,24 +388,21 @@ impl Storage for DbStorage {
         );
     }
 
-    async fn get_temperature(&self, chat_id: i64) -> f32 {
+    async fn get_chat_temperature(&self, chat_id: i64) -> Option<f32> {
         let qr = query!("SELECT temperature FROM users WHERE user_id = $1", chat_id)
             .fetch_one(&*self.db)
             .await;
-        if let Ok(row) = qr {
-            return row.temperature.unwrap_or(0.7) as f32;
-        } else {
-            return 0.7;
-        }
+        qr.ok()
+            .and_then(|row| row.temperature)
+            .map(|temperature| temperature as f32)
     }
 
     async fn set_temperature(&self, chat_id: i64, temperature: f32) {
         event!(
             Level::INFO,
             "Set_temperature: {:?}",
-            self.db
-                .execute(query!(
-                    "INSERT INTO users(user_id, temperature, context_len) 
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, temperature, context_len) 
                 VALUES ($1, $2, 0) 
             ON CONFLICT(user_id) 
                 DO UPDATE SET temperature = $2 
@@ -178,25 +414,975 @@ impl Storage for DbStorage {
         );
     }
 
+    async fn get_chat_model(&self, chat_id: i64) -> Option<String> {
+        let qr = query!("SELECT model FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok().and_then(|row| row.model)
+    }
+
+    async fn set_model(&self, chat_id: i64, model: Option<String>) {
+        event!(
+            Level::INFO,
+            "Set_model: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, model, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET model = $2 
+                WHERE user_id = $1",
+                chat_id,
+                model
+            ))
+            .await
+        );
+    }
+
+    async fn get_max_tokens(&self, chat_id: i64) -> Option<u32> {
+        let qr = query!("SELECT max_tokens FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok()
+            .and_then(|row| row.max_tokens)
+            .and_then(|value| u32::try_from(value).ok())
+    }
+
+    async fn set_max_tokens(&self, chat_id: i64, max_tokens: Option<u32>) {
+        let max_tokens = max_tokens.map(i64::from);
+        event!(
+            Level::INFO,
+            "Set_max_tokens: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, max_tokens, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET max_tokens = $2 
+                WHERE user_id = $1",
+                chat_id,
+                max_tokens
+            ))
+            .await
+        );
+    }
+
+    async fn get_thinking(&self, chat_id: i64) -> Option<bool> {
+        let qr = query!("SELECT thinking FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok().and_then(|row| row.thinking)
+    }
+
+    async fn set_thinking(&self, chat_id: i64, thinking: bool) {
+        event!(
+            Level::INFO,
+            "Set_thinking: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, thinking, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET thinking = $2 
+                WHERE user_id = $1",
+                    chat_id,
+                    thinking
+                ))
+                .await
+        );
+    }
+
+    async fn get_brevity(&self, chat_id: i64) -> Brevity {
+        let qr = query!("SELECT brevity FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok()
+            .and_then(|row| row.brevity)
+            .and_then(|brevity| brevity.parse().ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_brevity(&self, chat_id: i64, brevity: Brevity) {
+        let brevity = brevity.as_str();
+        event!(
+            Level::INFO,
+            "Set_brevity: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, brevity, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET brevity = $2 
+                WHERE user_id = $1",
+                chat_id,
+                brevity
+            ))
+            .await
+        );
+    }
+
+    async fn get_answer_format(&self, chat_id: i64) -> AnswerFormat {
+        let qr = query!(
+            "SELECT answer_format FROM users WHERE user_id = $1",
+            chat_id
+        )
+        .fetch_one(&*self.db)
+        .await;
+        qr.ok()
+            .and_then(|row| row.answer_format)
+            .and_then(|format| format.parse().ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_answer_format(&self, chat_id: i64, format: AnswerFormat) {
+        let format = format.as_str();
+        event!(
+            Level::INFO,
+            "Set_answer_format: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, answer_format, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET answer_format = $2 
+                WHERE user_id = $1",
+                chat_id,
+                format
+            ))
+            .await
+        );
+    }
+
+    async fn get_json_schema(&self, chat_id: i64) -> Option<String> {
+        let qr = query!("SELECT json_schema FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok().and_then(|row| row.json_schema)
+    }
+
+    async fn set_json_schema(&self, chat_id: i64, schema: Option<String>) {
+        event!(
+            Level::INFO,
+            "Set_json_schema: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, json_schema, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET json_schema = $2 
+                WHERE user_id = $1",
+                chat_id,
+                schema
+            ))
+            .await
+        );
+    }
+
+    async fn mark_started(&self, chat_id: i64) -> bool {
+        let qr = query!("SELECT started FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        if qr.ok().and_then(|row| row.started).unwrap_or(false) {
+            return false;
+        }
+
+        event!(
+            Level::INFO,
+            "Mark_started: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, started, context_len) 
+                VALUES ($1, 1, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET started = 1 
+                WHERE user_id = $1",
+                chat_id
+            ))
+            .await
+        );
+        true
+    }
+
+    async fn mark_disabled_hinted(&self, chat_id: i64) -> bool {
+        let qr = query!(
+            "SELECT disabled_hinted FROM users WHERE user_id = $1",
+            chat_id
+        )
+        .fetch_one(&*self.db)
+        .await;
+        if qr.ok().and_then(|row| row.disabled_hinted).unwrap_or(false) {
+            return false;
+        }
+
+        event!(
+            Level::INFO,
+            "Mark_disabled_hinted: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, disabled_hinted, context_len) 
+                VALUES ($1, 1, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET disabled_hinted = 1 
+                WHERE user_id = $1",
+                chat_id
+            ))
+            .await
+        );
+        true
+    }
+
+    async fn touch_last_active(&self, chat_id: i64, at: i64) -> Option<i64> {
+        let qr = query!("SELECT last_active FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        event!(
+            Level::DEBUG,
+            "Touch_last_active: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, last_active, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET last_active = $2 
+                WHERE user_id = $1",
+                chat_id,
+                at
+            ))
+            .await
+        );
+        qr.ok().and_then(|row| row.last_active)
+    }
+
+    async fn get_auto_clear_mins(&self, chat_id: i64) -> Option<u32> {
+        let qr = query!(
+            "SELECT auto_clear_mins FROM users WHERE user_id = $1",
+            chat_id
+        )
+        .fetch_one(&*self.db)
+        .await;
+        qr.ok()
+            .and_then(|row| row.auto_clear_mins)
+            .and_then(|minutes| u32::try_from(minutes).ok())
+    }
+
+    async fn set_auto_clear_mins(&self, chat_id: i64, minutes: Option<u32>) {
+        let minutes = minutes.map(i64::from);
+        event!(
+            Level::INFO,
+            "Set_auto_clear_mins: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, auto_clear_mins, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET auto_clear_mins = $2 
+                WHERE user_id = $1",
+                chat_id,
+                minutes
+            ))
+            .await
+        );
+    }
+
+    async fn is_chat_active(&self, chat_id: i64) -> bool {
+        let qr = query!("SELECT inactive FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        !qr.ok().and_then(|row| row.inactive).unwrap_or(false)
+    }
+
+    async fn set_chat_active(&self, chat_id: i64, active: bool) {
+        let inactive = !active;
+        event!(
+            Level::INFO,
+            "Set_chat_active: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, inactive, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET inactive = $2 
+                WHERE user_id = $1",
+                chat_id,
+                inactive
+            ))
+            .await
+        );
+    }
+
+    async fn get_extra_headers(&self, chat_id: i64) -> HashMap<String, String> {
+        let qr = query!(
+            "SELECT extra_headers FROM users WHERE user_id = $1",
+            chat_id
+        )
+        .fetch_one(&*self.db)
+        .await;
+        qr.ok()
+            .and_then(|row| row.extra_headers)
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_extra_headers(&self, chat_id: i64, headers: HashMap<String, String>) {
+        let headers = if headers.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&headers).ok()
+        };
+        event!(
+            Level::INFO,
+            "Set_extra_headers: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, extra_headers, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET extra_headers = $2 
+                WHERE user_id = $1",
+                chat_id,
+                headers
+            ))
+            .await
+        );
+    }
+
+    async fn get_api_key(&self, chat_id: i64) -> Option<String> {
+        let qr = query!("SELECT api_key FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok().and_then(|row| row.api_key)
+    }
+
+    async fn set_api_key(&self, chat_id: i64, api_key: Option<String>) {
+        // Only the outcome is logged, never the key
+        event!(
+            Level::INFO,
+            "Set_api_key: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, api_key, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET api_key = $2 
+                WHERE user_id = $1",
+                chat_id,
+                api_key
+            ))
+            .await
+        );
+    }
+
+    async fn get_logit_bias(&self, chat_id: i64) -> HashMap<u32, i32> {
+        let qr = query!("SELECT logit_bias FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok()
+            .and_then(|row| row.logit_bias)
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_logit_bias(&self, chat_id: i64, bias: HashMap<u32, i32>) {
+        let bias = if bias.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&bias).ok()
+        };
+        event!(
+            Level::INFO,
+            "Set_logit_bias: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, logit_bias, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET logit_bias = $2 
+                WHERE user_id = $1",
+                chat_id,
+                bias
+            ))
+            .await
+        );
+    }
+
+    async fn get_chat_slots(&self, chat_id: i64) -> ChatSlots {
+        let qr = query!("SELECT chat_slots FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok()
+            .and_then(|row| row.chat_slots)
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_chat_slots(&self, chat_id: i64, slots: ChatSlots) {
+        let slots = if slots == ChatSlots::default() {
+            None
+        } else {
+            serde_json::to_string(&slots).ok()
+        };
+        event!(
+            Level::INFO,
+            "Set_chat_slots: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, chat_slots, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET chat_slots = $2 
+                WHERE user_id = $1",
+                chat_id,
+                slots
+            ))
+            .await
+        );
+    }
+
+    async fn save_persona(&self, chat_id: i64, name: String, fingerprint: String) {
+        event!(
+            Level::INFO,
+            "Save_persona: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO personas (chat_id, name, fingerprint) 
+                VALUES ($1, $2, $3) 
+            ON CONFLICT(chat_id, name) 
+                DO UPDATE SET fingerprint = $3",
+                chat_id,
+                name,
+                fingerprint
+            ))
+            .await
+        );
+    }
+
+    async fn get_persona(&self, chat_id: i64, name: &str) -> Option<String> {
+        query!(
+            "SELECT fingerprint FROM personas WHERE chat_id = $1 AND name = $2",
+            chat_id,
+            name
+        )
+        .fetch_optional(&*self.db)
+        .await
+        .ok()
+        .flatten()
+        .map(|row| row.fingerprint)
+    }
+
+    async fn list_personas(&self, chat_id: i64) -> Vec<String> {
+        query!(
+            "SELECT name FROM personas WHERE chat_id = $1 ORDER BY name",
+            chat_id
+        )
+        .fetch_all(&*self.db)
+        .await
+        .map(|rows| rows.into_iter().map(|row| row.name).collect())
+        .unwrap_or_default()
+    }
+
+    async fn ban_user(&self, chat_id: i64, user_id: u64) -> bool {
+        let user_id = user_id as i64;
+        let result = self
+            .execute_with_retry(|| {
+                query!(
+                    "INSERT INTO banned_users (chat_id, user_id) VALUES ($1, $2) 
+                ON CONFLICT(chat_id, user_id) DO NOTHING",
+                    chat_id,
+                    user_id
+                )
+            })
+            .await;
+        event!(Level::INFO, "Ban_user: {:?}", result);
+        result.is_ok_and(|done| done.rows_affected() > 0)
+    }
+
+    async fn unban_user(&self, chat_id: i64, user_id: u64) -> bool {
+        let user_id = user_id as i64;
+        let result = self
+            .execute_with_retry(|| {
+                query!(
+                    "DELETE FROM banned_users WHERE chat_id = $1 AND user_id = $2",
+                    chat_id,
+                    user_id
+                )
+            })
+            .await;
+        event!(Level::INFO, "Unban_user: {:?}", result);
+        result.is_ok_and(|done| done.rows_affected() > 0)
+    }
+
+    async fn is_banned(&self, chat_id: i64, user_id: u64) -> bool {
+        let user_id = user_id as i64;
+        query!(
+            "SELECT user_id FROM banned_users WHERE chat_id = $1 AND user_id = $2",
+            chat_id,
+            user_id
+        )
+        .fetch_optional(&*self.db)
+        .await
+        .ok()
+        .flatten()
+        .is_some()
+    }
+
+    async fn list_banned(&self, chat_id: i64) -> Vec<u64> {
+        query!(
+            "SELECT user_id FROM banned_users WHERE chat_id = $1 ORDER BY user_id",
+            chat_id
+        )
+        .fetch_all(&*self.db)
+        .await
+        .map(|rows| rows.into_iter().map(|row| row.user_id as u64).collect())
+        .unwrap_or_default()
+    }
+
+    async fn append_chat_log(&self, chat_id: i64, entry: ChatLogEntry) {
+        event!(
+            Level::INFO,
+            "Append_chat_log: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO chat_logs (chat_id, created_at, prompt, answer) 
+                VALUES ($1, $2, $3, $4)",
+                chat_id,
+                entry.created_at,
+                entry.prompt,
+                entry.answer
+            ))
+            .await
+        );
+    }
+
+    async fn get_chat_log(
+        &self,
+        chat_id: i64,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<ChatLogEntry>, usize) {
+        let total = query!(
+            "SELECT COUNT(*) AS \"count!: i64\" FROM chat_logs WHERE chat_id = $1",
+            chat_id
+        )
+        .fetch_one(&*self.db)
+        .await
+        .map(|row| row.count as usize)
+        .unwrap_or(0);
+
+        let (offset, limit) = (offset as i64, limit as i64);
+        let entries = query!(
+            "SELECT created_at, prompt, answer FROM chat_logs 
+            WHERE chat_id = $1 ORDER BY id DESC LIMIT $2 OFFSET $3",
+            chat_id,
+            limit,
+            offset
+        )
+        .fetch_all(&*self.db)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|row| ChatLogEntry {
+                    created_at: row.created_at,
+                    prompt: row.prompt,
+                    answer: row.answer,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+        (entries, total)
+    }
+
     async fn add_note(&self, note: Note) {
-        todo!()
+        let latest = self.list_notes(note.chat_id).await.into_iter().next();
+        if let Some(mut latest) =
+            latest.filter(|last| last.should_merge(&note, self.note_merge_window))
+        {
+            latest.merge(note);
+            event!(
+                Level::INFO,
+                "Merge_note: {:?}",
+                self.execute_with_retry(|| query!(
+                    "UPDATE notes SET text = $1, created_at = $2, embedding = NULL 
+                    WHERE chat_id = $3 AND note_id = $4",
+                    latest.text,
+                    latest.created_at,
+                    latest.chat_id,
+                    latest.note_id
+                ))
+                .await
+            );
+            return;
+        }
+        event!(Level::INFO, "Add_note: {:?}", self.insert_note(&note).await);
     }
     async fn remove_note(&self, chat_id: i64, note_id: i64) {
-        todo!()
+        event!(
+            Level::INFO,
+            "Remove_note: {:?}",
+            self.execute_with_retry(|| query!(
+                "DELETE FROM notes WHERE chat_id = $1 AND note_id = $2",
+                chat_id,
+                note_id
+            ))
+            .await
+        );
     }
     async fn list_notes(&self, chat_id: i64) -> Vec<Note> {
-        todo!()
+        query!(
+            "SELECT note_id, user_id, text, created_at, embedding FROM notes 
+            WHERE chat_id = $1 ORDER BY created_at DESC, note_id DESC",
+            chat_id
+        )
+        .fetch_all(&*self.db)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|row| Note {
+                    note_id: row.note_id,
+                    chat_id,
+                    user_id: row.user_id as u64,
+                    text: row.text,
+                    created_at: row.created_at,
+                    embedding: row
+                        .embedding
+                        .and_then(|json| serde_json::from_str(&json).ok()),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
     }
     async fn erase_notes(&self, chat_id: i64) {
-        todo!()
+        event!(
+            Level::INFO,
+            "Erase_notes: {:?}",
+            self.execute_with_retry(|| query!("DELETE FROM notes WHERE chat_id = $1", chat_id))
+                .await
+        );
+    }
+    async fn get_inject_notes(&self, chat_id: i64) -> bool {
+        let qr = query!("SELECT inject_notes FROM users WHERE user_id = $1", chat_id)
+            .fetch_one(&*self.db)
+            .await;
+        qr.ok().and_then(|row| row.inject_notes).unwrap_or(true)
+    }
+
+    async fn set_inject_notes(&self, chat_id: i64, inject: bool) {
+        event!(
+            Level::INFO,
+            "Set_inject_notes: {:?}",
+            self.execute_with_retry(|| query!(
+                "INSERT INTO users(user_id, inject_notes, context_len) 
+                VALUES ($1, $2, 0) 
+            ON CONFLICT(user_id) 
+                DO UPDATE SET inject_notes = $2 
+                WHERE user_id = $1",
+                chat_id,
+                inject
+            ))
+            .await
+        );
+    }
+
+    async fn replace_notes(&self, chat_id: i64, notes: Vec<Note>) {
+        let mut tx = match self.db.begin().await {
+            Ok(tx) => tx,
+            Err(e) => {
+                event!(Level::ERROR, "Replace_notes: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = query!("DELETE FROM notes WHERE chat_id = $1", chat_id)
+            .execute(&mut *tx)
+            .await
+        {
+            event!(Level::ERROR, "Replace_notes: {:?}", e);
+            return;
+        }
+        for note in notes {
+            let note = Note { chat_id, ..note };
+            let user_id = note.user_id as i64;
+            let embedding = note
+                .embedding
+                .as_ref()
+                .and_then(|embedding| serde_json::to_string(embedding).ok());
+            if let Err(e) = query!(
+                "INSERT INTO notes (note_id, chat_id, user_id, text, created_at, embedding) 
+                VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT(chat_id, note_id) DO NOTHING",
+                note.note_id,
+                note.chat_id,
+                user_id,
+                note.text,
+                note.created_at,
+                embedding
+            )
+            .execute(&mut *tx)
+            .await
+            {
+                event!(Level::ERROR, "Replace_notes: {:?}", e);
+                return;
+            }
+        }
+        event!(Level::INFO, "Replace_notes: {:?}", tx.commit().await);
+    }
+    async fn remove_notes_where(&self, chat_id: i64, filter: &NoteFilter) -> usize {
+        let user_id = filter.user_id.map(|user_id| user_id as i64);
+        let result = self
+            .execute_with_retry(|| {
+                query!(
+                    "DELETE FROM notes WHERE chat_id = $1 
+                    AND ($2 IS NULL OR user_id = $2) 
+                    AND ($3 IS NULL OR created_at >= $3) 
+                    AND ($4 IS NULL OR created_at < $4)",
+                    chat_id,
+                    user_id,
+                    filter.after,
+                    filter.before
+                )
+            })
+            .await;
+        event!(Level::INFO, "Remove_notes_where: {:?}", result);
+        result.map_or(0, |done| done.rows_affected() as usize)
     }
     async fn enable(&self, chat_id: i64, thread_id: Option<i64>, is_super: bool) {
-        todo!()
+        let settings = toggled(self.chat_settings(chat_id).await, thread_id, is_super, true);
+        self.save_chat_settings(chat_id, &settings).await;
     }
     async fn disable(&self, chat_id: i64, thread_id: Option<i64>, is_super: bool) {
-        todo!()
+        let settings = toggled(
+            self.chat_settings(chat_id).await,
+            thread_id,
+            is_super,
+            false,
+        );
+        self.save_chat_settings(chat_id, &settings).await;
     }
-    async fn is_enabled(&self, chat_id: i64, thread_id: Option<ThreadId>, is_super: bool) -> bool {
-        todo!()
+    async fn is_enabled(&self, chat_id: i64, thread_id: Option<ThreadId>, _is_super: bool) -> bool {
+        let Some(chat) = self.chat_settings(chat_id).await else {
+            return groups_enabled_by_default();
+        };
+        match thread_id {
+            Some(thread_id) if chat.is_supergroup => chat.thread_enabled(thread_id.0.0 as i64),
+            _ => chat.enabled,
+        }
+    }
+    async fn set_auto_enable_threads(&self, chat_id: i64, auto_enable: bool) {
+        let mut settings = self.chat_settings(chat_id).await.unwrap_or(ChatSettings {
+            is_supergroup: true,
+            threads: HashMap::new(),
+            enabled: groups_enabled_by_default(),
+            auto_enable_threads: None,
+        });
+        settings.auto_enable_threads = Some(auto_enable);
+        self.save_chat_settings(chat_id, &settings).await;
+    }
+    async fn has_chat_settings(&self, chat_id: i64) -> bool {
+        self.chat_settings(chat_id).await.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_context_writes_are_not_lost() {
+        let path = std::env::temp_dir().join(format!("busy_test_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = db::sqlite::init_db_at(path.to_str().unwrap())
+            .await
+            .expect("test database");
+        let storage = Arc::new(DbStorage::with_pool(pool, 1000));
+
+        let writers: Vec<_> = (0..50)
+            .map(|i| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    storage
+                        .set_conversation_context(
+                            42,
+                            Message {
+                                role: "user".to_string(),
+                                content: format!("message {}", i),
+                                reasoning: None,
+                            },
+                        )
+                        .await;
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        // Both the rows and the context_len counter must account for every write
+        assert_eq!(storage.get_conversation_context(42).await.len(), 50);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_message_survives_eviction() {
+        let path = std::env::temp_dir().join(format!("pin_test_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = db::sqlite::init_db_at(path.to_str().unwrap())
+            .await
+            .expect("test database");
+        let storage = DbStorage::with_pool(pool, 3);
+
+        let message = |role: &str, content: String| Message {
+            role: role.to_string(),
+            content,
+            reasoning: None,
+        };
+        storage
+            .set_conversation_context(7, message("user", "goal".to_string()))
+            .await;
+        assert!(storage.pin_last_user_message(7).await);
+        for i in 0..5 {
+            storage
+                .set_conversation_context(7, message("assistant", i.to_string()))
+                .await;
+        }
+
+        let history: Vec<String> = storage
+            .get_conversation_context(7)
+            .await
+            .into_iter()
+            .map(|message| message.content)
+            .collect();
+        assert_eq!(history, vec!["goal", "3", "4"]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_old_context_rows_are_pruned() {
+        let path = std::env::temp_dir().join(format!("prune_test_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = db::sqlite::init_db_at(path.to_str().unwrap())
+            .await
+            .expect("test database");
+        let mut storage = DbStorage::with_pool(pool, 3);
+        storage.max_stored_context = 2;
+
+        let message = |content: String| Message {
+            role: "user".to_string(),
+            content,
+            reasoning: None,
+        };
+        storage
+            .set_conversation_context(9, message("goal".to_string()))
+            .await;
+        assert!(storage.pin_last_user_message(9).await);
+        for i in 0..10 {
+            storage
+                .set_conversation_context(9, message(i.to_string()))
+                .await;
+        }
+        // Another chat's rows are left alone
+        storage
+            .set_conversation_context(10, message("other".to_string()))
+            .await;
+
+        let stored: Vec<String> =
+            sqlx::query_scalar("SELECT message FROM context WHERE user_id = 9 ORDER BY id")
+                .fetch_all(&*storage.db)
+                .await
+                .unwrap();
+        assert_eq!(stored, vec!["goal", "4", "5", "6", "7", "8", "9"]);
+        assert_eq!(storage.get_conversation_context(10).await.len(), 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_hint_is_marked_once() {
+        let path = std::env::temp_dir().join(format!("hint_test_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = db::sqlite::init_db_at(path.to_str().unwrap())
+            .await
+            .expect("test database");
+        let storage = DbStorage::with_pool(pool, 10);
+
+        assert!(storage.mark_disabled_hinted(-100).await);
+        assert!(!storage.mark_disabled_hinted(-100).await);
+        assert!(storage.mark_disabled_hinted(-200).await);
+        // The flag is independent of the warm-start one
+        assert!(storage.mark_started(-100).await);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_notes_round_trip() {
+        let path = std::env::temp_dir().join(format!("notes_test_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = db::sqlite::init_db_at(path.to_str().unwrap())
+            .await
+            .expect("test database");
+        let storage = DbStorage::with_pool(pool, 10);
+
+        let note = |note_id: i64, user_id: u64, created_at: i64| Note {
+            note_id,
+            chat_id: 5,
+            user_id,
+            text: format!("note {}", note_id),
+            created_at,
+            embedding: Some(vec![0.5, 1.0]),
+        };
+        storage.add_note(note(1, 7, 100)).await;
+        storage.add_note(note(2, 8, 200)).await;
+        storage.add_note(note(3, 7, 300)).await;
+        storage
+            .add_note(Note {
+                chat_id: 6,
+                ..note(4, 7, 400)
+            })
+            .await;
+
+        let notes = storage.list_notes(5).await;
+        let ids: Vec<i64> = notes.iter().map(|note| note.note_id).collect();
+        assert_eq!(ids, vec![3, 2, 1]);
+        assert_eq!(notes[0].user_id, 7);
+        assert_eq!(notes[0].embedding, Some(vec![0.5, 1.0]));
+
+        storage.remove_note(5, 2).await;
+        storage.remove_note(5, 99).await;
+        let filter = NoteFilter {
+            before: Some(200),
+            ..Default::default()
+        };
+        assert_eq!(storage.remove_notes_where(5, &filter).await, 1);
+        let ids: Vec<i64> = storage
+            .list_notes(5)
+            .await
+            .iter()
+            .map(|note| note.note_id)
+            .collect();
+        assert_eq!(ids, vec![3]);
+
+        storage
+            .replace_notes(5, vec![note(8, 7, 800), note(9, 7, 900)])
+            .await;
+        assert_eq!(storage.list_notes(5).await.len(), 2);
+
+        storage.erase_notes(5).await;
+        assert!(storage.list_notes(5).await.is_empty());
+        assert_eq!(storage.list_notes(6).await.len(), 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_thread_in_enabled_supergroup() {
+        let path = std::env::temp_dir().join(format!("enable_test_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = db::sqlite::init_db_at(path.to_str().unwrap())
+            .await
+            .expect("test database");
+        let storage = DbStorage::with_pool(pool, 3);
+        let topic = |id: i32| Some(ThreadId(teloxide::types::MessageId(id)));
+
+        // Unknown chats are enabled
+        assert!(storage.is_enabled(-100, topic(3), true).await);
+        assert!(!storage.has_chat_settings(-100).await);
+
+        storage.enable(-100, None, true).await;
+        assert!(storage.has_chat_settings(-100).await);
+        storage.disable(-100, Some(3), true).await;
+        assert!(storage.is_enabled(-100, None, true).await);
+        assert!(!storage.is_enabled(-100, topic(3), true).await);
+        assert!(storage.is_enabled(-100, topic(4), true).await);
+
+        // Disabling the chat keeps the topic's own setting
+        storage.enable(-100, Some(3), true).await;
+        storage.disable(-100, None, true).await;
+        assert!(!storage.is_enabled(-100, None, true).await);
+        assert!(storage.is_enabled(-100, topic(3), true).await);
+        assert!(!storage.is_enabled(-100, topic(4), true).await);
+
+        let _ = std::fs::remove_file(&path);
     }
 }
@@ -1,6 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 
 use async_trait::async_trait;
 use teloxide::types::ThreadId;
@@ -8,8 +8,12 @@ use tracing::info;
 
 use crate::{
     CONFIG,
+    answer_format::AnswerFormat,
     lm_types::Message,
-    storage::{ChatSettings, Note, Storage},
+    storage::{
+        ChatLogEntry, ChatSettings, ChatSlots, Note, NoteFilter, Storage, groups_enabled_by_default,
+    },
+    system::Brevity,
 };
 
 /// In-memory storage implementation using DashMap for thread safety
@@ -19,17 +23,58 @@ use crate::{
 ///
 /// # Data Structures
 /// - `context`: Conversation history per chat
+/// - `pinned`: Position of the pinned message in the chat's history
 /// - `fingerprint`: AI personality settings per chat
 /// - `temperature`: Creativity settings per chat
+/// - `max_tokens`: Completion token limit per chat
+/// - `models`: Model chosen with `/model` per chat
+/// - `thinking`: Per-chat override for showing `<think>` blocks
+/// - `brevity`: Answer length preference per chat
+/// - `answer_format`: Answer format preset per chat
+/// - `json_schema`: JSON schema of answers per chat
+/// - `started`: Chats that already received the warm-start greeting
+/// - `disabled_hinted`: Chats that were already told how to `/enable` the bot
+/// - `inactive`: Chats where the bot was blocked
+/// - `last_active`: Time of the latest request per chat
+/// - `auto_clear_mins`: Idle minutes before a fresh conversation, per chat
+/// - `extra_headers`: Custom request headers per chat
+/// - `api_keys`: API key overrides per chat
+/// - `logit_bias`: Token bias map per chat
+/// - `slots`: Named conversation slots per private chat
+/// - `personas`: Named fingerprints per chat
+/// - `chat_log`: Request log per chat, oldest first
+/// - `banned`: Users the bot ignores, per chat
 /// - `notes`: User notes organized by chat
+/// - `notes_not_injected`: Chats whose notes are kept out of prompts
 /// - `chats`: Chat configuration settings
 pub struct MemoryStorage {
     context: DashMap<i64, Vec<Message>>,
+    pinned: DashMap<i64, usize>,
     fingerprint: DashMap<i64, String>,
     temperature: DashMap<i64, f32>,
+    max_tokens: DashMap<i64, u32>,
+    models: DashMap<i64, String>,
+    thinking: DashMap<i64, bool>,
+    brevity: DashMap<i64, Brevity>,
+    answer_format: DashMap<i64, AnswerFormat>,
+    json_schema: DashMap<i64, String>,
+    started: DashSet<i64>,
+    disabled_hinted: DashSet<i64>,
+    inactive: DashSet<i64>,
+    last_active: DashMap<i64, i64>,
+    auto_clear_mins: DashMap<i64, u32>,
+    extra_headers: DashMap<i64, HashMap<String, String>>,
+    api_keys: DashMap<i64, String>,
+    logit_bias: DashMap<i64, HashMap<u32, i32>>,
+    slots: DashMap<i64, ChatSlots>,
+    personas: DashMap<i64, BTreeMap<String, String>>,
+    chat_log: DashMap<i64, Vec<ChatLogEntry>>,
+    banned: DashMap<i64, BTreeSet<u64>>,
     notes: DashMap<i64, Vec<Note>>, // chat_id -> (note_id -> Note)
+    notes_not_injected: DashSet<i64>,
     chats: DashMap<i64, ChatSettings>,
     max_conv_len: usize,
+    note_merge_window: i64,
 }
 
 impl MemoryStorage {
@@ -40,11 +85,32 @@ impl MemoryStorage {
     pub fn new() -> Self {
         Self {
             context: DashMap::with_capacity(100),
+            pinned: DashMap::new(),
             fingerprint: DashMap::with_capacity(100),
             temperature: DashMap::with_capacity(100),
+            max_tokens: DashMap::new(),
+            models: DashMap::new(),
+            thinking: DashMap::with_capacity(100),
+            brevity: DashMap::with_capacity(100),
+            answer_format: DashMap::new(),
+            json_schema: DashMap::new(),
+            started: DashSet::with_capacity(100),
+            disabled_hinted: DashSet::new(),
+            inactive: DashSet::new(),
+            last_active: DashMap::new(),
+            auto_clear_mins: DashMap::new(),
+            extra_headers: DashMap::new(),
+            api_keys: DashMap::new(),
+            logit_bias: DashMap::new(),
+            slots: DashMap::new(),
+            personas: DashMap::new(),
+            chat_log: DashMap::new(),
+            banned: DashMap::new(),
             notes: DashMap::with_capacity(100),
+            notes_not_injected: DashSet::new(),
             chats: DashMap::with_capacity(100),
             max_conv_len: CONFIG.get("max_conversation_len").unwrap_or(20),
+            note_merge_window: CONFIG.get("note_merge_window_secs").unwrap_or(0),
         }
     }
 }
@@ -52,6 +118,10 @@ impl MemoryStorage {
 // Реализация трейта для MemoryStorage
 #[async_trait]
 impl Storage for MemoryStorage {
+    fn backend_name(&self) -> &'static str {
+        "memory"
+    }
+
     // Реализация методов с использованием текущей логики хранения в памяти
     async fn get_conversation_context(&self, user_id: i64) -> Vec<Message> {
         self.context
@@ -60,13 +130,38 @@ impl Storage for MemoryStorage {
             .unwrap_or_default()
     }
 
+    async fn get_conversation_with_pin(&self, user_id: i64) -> (Vec<Message>, Option<usize>) {
+        let history = self.get_conversation_context(user_id).await;
+        let pinned = self
+            .pinned
+            .get(&user_id)
+            .map(|pinned| *pinned)
+            .filter(|pinned| *pinned < history.len());
+        (history, pinned)
+    }
+
     async fn set_conversation_context(&self, user_id: i64, context: Message) {
         self.context
             .entry(user_id)
             .and_modify(|history| {
                 history.push(context.clone());
                 if history.len() > self.max_conv_len {
-                    history.drain(..history.len() - self.max_conv_len);
+                    let excess = history.len() - self.max_conv_len;
+                    match self.pinned.get_mut(&user_id) {
+                        // Evict around the pinned message and keep it first
+                        Some(mut pinned) if *pinned < excess && self.max_conv_len > 0 => {
+                            history.drain(*pinned + 1..=excess);
+                            history.drain(..*pinned);
+                            *pinned = 0;
+                        }
+                        Some(mut pinned) => {
+                            history.drain(..excess);
+                            *pinned = pinned.saturating_sub(excess);
+                        }
+                        None => {
+                            history.drain(..excess);
+                        }
+                    }
                 }
             })
             .or_insert_with(|| vec![context]);
@@ -74,6 +169,25 @@ impl Storage for MemoryStorage {
 
     async fn clear_conversation_context(&self, user_id: i64) {
         self.context.remove(&user_id);
+        self.pinned.remove(&user_id);
+    }
+
+    async fn pin_last_user_message(&self, chat_id: i64) -> bool {
+        let position = self
+            .context
+            .get(&chat_id)
+            .and_then(|history| history.iter().rposition(|message| message.role == "user"));
+        match position {
+            Some(position) => {
+                self.pinned.insert(chat_id, position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn unpin_message(&self, chat_id: i64) {
+        self.pinned.remove(&chat_id);
     }
 
     async fn get_system_fingerprint(&self, user_id: i64) -> String {
@@ -87,19 +201,266 @@ impl Storage for MemoryStorage {
         self.fingerprint.insert(user_id, fingerprint);
     }
 
-    async fn get_temperature(&self, user_id: i64) -> f32 {
-        self.temperature.get(&user_id).map(|v| *v).unwrap_or(0.7)
+    async fn get_chat_temperature(&self, user_id: i64) -> Option<f32> {
+        self.temperature.get(&user_id).map(|v| *v)
     }
 
     async fn set_temperature(&self, user_id: i64, temperature: f32) {
         self.temperature.insert(user_id, temperature);
     }
 
+    async fn get_chat_model(&self, chat_id: i64) -> Option<String> {
+        self.models.get(&chat_id).map(|model| model.clone())
+    }
+
+    async fn set_model(&self, chat_id: i64, model: Option<String>) {
+        match model {
+            Some(model) => {
+                self.models.insert(chat_id, model);
+            }
+            None => {
+                self.models.remove(&chat_id);
+            }
+        }
+    }
+
+    async fn get_max_tokens(&self, chat_id: i64) -> Option<u32> {
+        self.max_tokens.get(&chat_id).map(|value| *value)
+    }
+
+    async fn set_max_tokens(&self, chat_id: i64, max_tokens: Option<u32>) {
+        match max_tokens {
+            Some(value) => {
+                self.max_tokens.insert(chat_id, value);
+            }
+            None => {
+                self.max_tokens.remove(&chat_id);
+            }
+        }
+    }
+
+    async fn get_thinking(&self, user_id: i64) -> Option<bool> {
+        self.thinking.get(&user_id).map(|v| *v)
+    }
+
+    async fn set_thinking(&self, user_id: i64, thinking: bool) {
+        self.thinking.insert(user_id, thinking);
+    }
+
+    async fn get_brevity(&self, user_id: i64) -> Brevity {
+        self.brevity.get(&user_id).map(|v| *v).unwrap_or_default()
+    }
+
+    async fn set_brevity(&self, user_id: i64, brevity: Brevity) {
+        self.brevity.insert(user_id, brevity);
+    }
+
+    async fn get_answer_format(&self, chat_id: i64) -> AnswerFormat {
+        self.answer_format
+            .get(&chat_id)
+            .map(|v| *v)
+            .unwrap_or_default()
+    }
+
+    async fn set_answer_format(&self, chat_id: i64, format: AnswerFormat) {
+        self.answer_format.insert(chat_id, format);
+    }
+
+    async fn get_json_schema(&self, chat_id: i64) -> Option<String> {
+        self.json_schema.get(&chat_id).map(|schema| schema.clone())
+    }
+
+    async fn set_json_schema(&self, chat_id: i64, schema: Option<String>) {
+        match schema {
+            Some(schema) => {
+                self.json_schema.insert(chat_id, schema);
+            }
+            None => {
+                self.json_schema.remove(&chat_id);
+            }
+        }
+    }
+
+    async fn mark_started(&self, chat_id: i64) -> bool {
+        self.started.insert(chat_id)
+    }
+
+    async fn mark_disabled_hinted(&self, chat_id: i64) -> bool {
+        self.disabled_hinted.insert(chat_id)
+    }
+
+    async fn touch_last_active(&self, chat_id: i64, at: i64) -> Option<i64> {
+        self.last_active.insert(chat_id, at)
+    }
+
+    async fn get_auto_clear_mins(&self, chat_id: i64) -> Option<u32> {
+        self.auto_clear_mins.get(&chat_id).map(|minutes| *minutes)
+    }
+
+    async fn set_auto_clear_mins(&self, chat_id: i64, minutes: Option<u32>) {
+        match minutes {
+            Some(minutes) => {
+                self.auto_clear_mins.insert(chat_id, minutes);
+            }
+            None => {
+                self.auto_clear_mins.remove(&chat_id);
+            }
+        }
+    }
+
+    async fn is_chat_active(&self, chat_id: i64) -> bool {
+        !self.inactive.contains(&chat_id)
+    }
+
+    async fn set_chat_active(&self, chat_id: i64, active: bool) {
+        if active {
+            self.inactive.remove(&chat_id);
+        } else {
+            self.inactive.insert(chat_id);
+        }
+    }
+
+    async fn get_extra_headers(&self, chat_id: i64) -> HashMap<String, String> {
+        self.extra_headers
+            .get(&chat_id)
+            .map(|headers| headers.clone())
+            .unwrap_or_default()
+    }
+
+    async fn set_extra_headers(&self, chat_id: i64, headers: HashMap<String, String>) {
+        if headers.is_empty() {
+            self.extra_headers.remove(&chat_id);
+        } else {
+            self.extra_headers.insert(chat_id, headers);
+        }
+    }
+
+    async fn get_api_key(&self, chat_id: i64) -> Option<String> {
+        self.api_keys.get(&chat_id).map(|key| key.clone())
+    }
+
+    async fn set_api_key(&self, chat_id: i64, api_key: Option<String>) {
+        match api_key {
+            Some(api_key) => {
+                self.api_keys.insert(chat_id, api_key);
+            }
+            None => {
+                self.api_keys.remove(&chat_id);
+            }
+        }
+    }
+
+    async fn get_logit_bias(&self, chat_id: i64) -> HashMap<u32, i32> {
+        self.logit_bias
+            .get(&chat_id)
+            .map(|bias| bias.clone())
+            .unwrap_or_default()
+    }
+
+    async fn set_logit_bias(&self, chat_id: i64, bias: HashMap<u32, i32>) {
+        if bias.is_empty() {
+            self.logit_bias.remove(&chat_id);
+        } else {
+            self.logit_bias.insert(chat_id, bias);
+        }
+    }
+
+    async fn get_chat_slots(&self, chat_id: i64) -> ChatSlots {
+        self.slots
+            .get(&chat_id)
+            .map(|slots| slots.clone())
+            .unwrap_or_default()
+    }
+
+    async fn set_chat_slots(&self, chat_id: i64, slots: ChatSlots) {
+        if slots == ChatSlots::default() {
+            self.slots.remove(&chat_id);
+        } else {
+            self.slots.insert(chat_id, slots);
+        }
+    }
+
+    async fn save_persona(&self, chat_id: i64, name: String, fingerprint: String) {
+        self.personas
+            .entry(chat_id)
+            .or_default()
+            .insert(name, fingerprint);
+    }
+
+    async fn get_persona(&self, chat_id: i64, name: &str) -> Option<String> {
+        self.personas
+            .get(&chat_id)
+            .and_then(|personas| personas.get(name).cloned())
+    }
+
+    async fn list_personas(&self, chat_id: i64) -> Vec<String> {
+        self.personas
+            .get(&chat_id)
+            .map(|personas| personas.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    async fn ban_user(&self, chat_id: i64, user_id: u64) -> bool {
+        self.banned.entry(chat_id).or_default().insert(user_id)
+    }
+
+    async fn unban_user(&self, chat_id: i64, user_id: u64) -> bool {
+        let Some(mut banned) = self.banned.get_mut(&chat_id) else {
+            return false;
+        };
+        let removed = banned.remove(&user_id);
+        if banned.is_empty() {
+            drop(banned);
+            self.banned.remove(&chat_id);
+        }
+        removed
+    }
+
+    async fn is_banned(&self, chat_id: i64, user_id: u64) -> bool {
+        self.banned
+            .get(&chat_id)
+            .is_some_and(|banned| banned.contains(&user_id))
+    }
+
+    async fn list_banned(&self, chat_id: i64) -> Vec<u64> {
+        self.banned
+            .get(&chat_id)
+            .map(|banned| banned.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    async fn append_chat_log(&self, chat_id: i64, entry: ChatLogEntry) {
+        self.chat_log.entry(chat_id).or_default().push(entry);
+    }
+
+    async fn get_chat_log(
+        &self,
+        chat_id: i64,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<ChatLogEntry>, usize) {
+        self.chat_log
+            .get(&chat_id)
+            .map(|log| {
+                let page = log.iter().rev().skip(offset).take(limit).cloned().collect();
+                (page, log.len())
+            })
+            .unwrap_or_default()
+    }
+
     async fn add_note(&self, note: Note) {
         self.notes
             .entry(note.chat_id)
             .and_modify(|notes| {
-                notes.push(note.clone());
+                match notes
+                    .iter_mut()
+                    .max_by_key(|existing| (existing.created_at, existing.note_id))
+                {
+                    Some(last) if last.should_merge(&note, self.note_merge_window) => {
+                        last.merge(note.clone());
+                    }
+                    _ => notes.push(note.clone()),
+                }
             })
             .or_insert_with(|| vec![note]);
     }
@@ -109,21 +470,53 @@ impl Storage for MemoryStorage {
             notes.retain(|note| note.note_id != note_id);
             if notes.is_empty() {
                 drop(notes);
-                self.notes.remove(&note_id);
+                self.notes.remove(&chat_id);
             }
         }
     }
 
     async fn list_notes(&self, chat_id: i64) -> Vec<Note> {
-        self.notes
+        let mut notes = self
+            .notes
             .get(&chat_id)
             .map(|entry| entry.clone())
-            .unwrap_or_default()
+            .unwrap_or_default();
+        notes.sort_by_key(|note| std::cmp::Reverse((note.created_at, note.note_id)));
+        notes
     }
     async fn erase_notes(&self, chat_id: i64) {
         self.notes.remove(&chat_id);
     }
 
+    async fn get_inject_notes(&self, chat_id: i64) -> bool {
+        !self.notes_not_injected.contains(&chat_id)
+    }
+
+    async fn set_inject_notes(&self, chat_id: i64, inject: bool) {
+        if inject {
+            self.notes_not_injected.remove(&chat_id);
+        } else {
+            self.notes_not_injected.insert(chat_id);
+        }
+    }
+
+    async fn replace_notes(&self, chat_id: i64, notes: Vec<Note>) {
+        if notes.is_empty() {
+            self.notes.remove(&chat_id);
+        } else {
+            self.notes.insert(chat_id, notes);
+        }
+    }
+
+    async fn remove_notes_where(&self, chat_id: i64, filter: &NoteFilter) -> usize {
+        let Some(mut notes) = self.notes.get_mut(&chat_id) else {
+            return 0;
+        };
+        let before = notes.len();
+        notes.retain(|note| !filter.matches(note));
+        before - notes.len()
+    }
+
     async fn enable(&self, chat_id: i64, thread_id: Option<i64>, is_super: bool) {
         info!("enable: {:?} {:?}", chat_id, thread_id);
         self.chats
@@ -145,6 +538,7 @@ impl Storage for MemoryStorage {
                     HashMap::new()
                 },
                 enabled: true,
+                auto_enable_threads: None,
             });
 
         info!("enable2: {:?}", self.chats);
@@ -170,6 +564,7 @@ impl Storage for MemoryStorage {
                     HashMap::new()
                 },
                 enabled: false,
+                auto_enable_threads: None,
             });
 
         info!("disable2: {:?}", self.chats);
@@ -186,14 +581,62 @@ impl Storage for MemoryStorage {
             if let Some(thread_id) = thread_id {
                 info!("Thread id: {:?}", thread_id);
                 let tid = thread_id.0.0 as i64;
-                let chat_thread = chat.threads.get(&tid).unwrap_or(&true);
+                let chat_thread = chat.thread_enabled(tid);
                 info!("Thread info: {:?}", chat_thread);
-                return *chat_thread;
+                return chat_thread;
             } else {
                 return chat.enabled;
             }
         } else {
-            return true;
+            return groups_enabled_by_default();
         }
     }
+    async fn set_auto_enable_threads(&self, chat_id: i64, auto_enable: bool) {
+        self.chats
+            .entry(chat_id)
+            .and_modify(|settings| settings.auto_enable_threads = Some(auto_enable))
+            .or_insert_with(|| ChatSettings {
+                is_supergroup: true,
+                threads: HashMap::new(),
+                enabled: groups_enabled_by_default(),
+                auto_enable_threads: Some(auto_enable),
+            });
+    }
+    async fn has_chat_settings(&self, chat_id: i64) -> bool {
+        self.chats.contains_key(&chat_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            reasoning: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pinned_message_survives_eviction() {
+        let mut storage = MemoryStorage::new();
+        storage.max_conv_len = 3;
+
+        storage
+            .set_conversation_context(1, message("user", "goal"))
+            .await;
+        assert!(storage.pin_last_user_message(1).await);
+        for i in 0..5 {
+            storage
+                .set_conversation_context(1, message("assistant", &i.to_string()))
+                .await;
+        }
+
+        let (history, pinned) = storage.get_conversation_with_pin(1).await;
+        let history: Vec<String> = history.into_iter().map(|message| message.content).collect();
+        assert_eq!(history, vec!["goal", "3", "4"]);
+        assert_eq!(pinned, Some(0));
+    }
 }
@@ -1,17 +1,25 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use teloxide::types::ThreadId;
+use teloxide::types::{MessageId, ThreadId};
 use tracing::{Level, event};
 
 mod db_storage;
 mod memory_storage;
+#[cfg(feature = "postgres")]
+mod pg_storage;
 
 use crate::{
-    CONFIG, db,
+    CONFIG,
+    answer_format::AnswerFormat,
+    db,
     lm_types::Message,
     storage::{db_storage::DbStorage, memory_storage::MemoryStorage},
+    system::Brevity,
 };
 
 /// Represents a user note stored in the system
@@ -31,6 +39,116 @@ pub struct Note {
 
     /// Content of the note
     pub text: String,
+
+    /// Unix timestamp (seconds) of creation or of the last merged append
+    #[serde(default)]
+    pub created_at: i64,
+
+    /// Embedding vector used for semantic recall, if one was computed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+}
+
+impl Note {
+    /// Checks whether `next` should be appended to this note instead of stored separately
+    ///
+    /// True when both come from the same user and `next` was created at most
+    /// `window_secs` after this note. A window of 0 disables merging.
+    pub fn should_merge(&self, next: &Note, window_secs: i64) -> bool {
+        window_secs > 0
+            && self.user_id == next.user_id
+            && (0..=window_secs).contains(&(next.created_at - self.created_at))
+    }
+
+    /// Appends `next` to this note
+    pub fn merge(&mut self, next: Note) {
+        self.text.push('\n');
+        self.text.push_str(&next.text);
+        self.created_at = next.created_at;
+        // The stored vector no longer matches the combined text
+        self.embedding = None;
+    }
+}
+
+/// Parses notes exported with `/exportnotes` for import into a chat
+///
+/// Entries that are not valid notes or have empty text are rejected; valid
+/// ones are moved to `chat_id`.
+///
+/// # Returns
+/// Accepted notes and the number of rejected entries
+pub fn parse_notes_json(json: &str, chat_id: i64) -> Result<(Vec<Note>, usize), String> {
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_str(json).map_err(|_| "Expected a JSON array of notes".to_string())?;
+
+    let mut rejected = 0;
+    let notes = entries
+        .into_iter()
+        .filter_map(|entry| match serde_json::from_value::<Note>(entry) {
+            Ok(note) if !note.text.trim().is_empty() => Some(Note { chat_id, ..note }),
+            _ => {
+                rejected += 1;
+                None
+            }
+        })
+        .collect();
+    Ok((notes, rejected))
+}
+
+/// Selects notes for bulk removal
+///
+/// Parsed from space-separated terms that must all match:
+/// `user:<id>`, `after:<YYYY-MM-DD>` (inclusive) and `before:<YYYY-MM-DD>`
+/// (exclusive). Dates are UTC days compared with `created_at`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NoteFilter {
+    /// Only notes by this user
+    pub user_id: Option<u64>,
+    /// Only notes created at or after this Unix timestamp
+    pub after: Option<i64>,
+    /// Only notes created before this Unix timestamp
+    pub before: Option<i64>,
+}
+
+impl NoteFilter {
+    /// A filter without terms would match every note
+    pub fn is_empty(&self) -> bool {
+        self == &NoteFilter::default()
+    }
+
+    pub fn matches(&self, note: &Note) -> bool {
+        self.user_id.is_none_or(|user_id| note.user_id == user_id)
+            && self.after.is_none_or(|after| note.created_at >= after)
+            && self.before.is_none_or(|before| note.created_at < before)
+    }
+}
+
+impl std::str::FromStr for NoteFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let day_start = |value: &str| {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp())
+                .map_err(|_| format!("Invalid date '{}', expected YYYY-MM-DD", value))
+        };
+
+        let mut filter = NoteFilter::default();
+        for term in s.split_whitespace() {
+            match term.split_once(':') {
+                Some(("user", id)) => {
+                    filter.user_id = Some(
+                        id.parse()
+                            .map_err(|_| format!("Invalid user id '{}'", id))?,
+                    );
+                }
+                Some(("after", date)) => filter.after = Some(day_start(date)?),
+                Some(("before", date)) => filter.before = Some(day_start(date)?),
+                _ => return Err(format!("Unknown filter term '{}'", term)),
+            }
+        }
+        Ok(filter)
+    }
 }
 
 impl ToString for Note {
@@ -49,6 +167,18 @@ impl ToString for Note {
     }
 }
 
+/// One AI exchange in a chat's request log (`enable_chat_log`)
+///
+/// Unlike the conversation context, entries are never edited or trimmed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatLogEntry {
+    /// Unix timestamp of the answer
+    pub created_at: i64,
+    /// Prompt as sent to the model, including the sender envelope
+    pub prompt: String,
+    pub answer: String,
+}
+
 /// Represents chat-specific configuration settings
 ///
 /// Controls bot functionality at both chat and thread levels.
@@ -66,6 +196,323 @@ pub struct ChatSettings {
 
     /// Global bot enablement status for the chat
     pub enabled: bool,
+
+    /// Whether topics without their own setting follow the chat
+    ///
+    /// `None` falls back to the `auto_enable_threads` config value.
+    #[serde(default)]
+    pub auto_enable_threads: Option<bool>,
+}
+
+impl ChatSettings {
+    /// Whether the bot answers in a forum topic
+    ///
+    /// An explicit `/enable` or `/disable` of the topic wins. Other topics
+    /// are enabled only when the chat is enabled and thread auto-enablement
+    /// is on for it.
+    pub fn thread_enabled(&self, thread_id: i64) -> bool {
+        match self.threads.get(&thread_id) {
+            Some(enabled) => *enabled,
+            None => {
+                self.enabled
+                    && self
+                        .auto_enable_threads
+                        .unwrap_or_else(|| CONFIG.get_bool("auto_enable_threads").unwrap_or(true))
+            }
+        }
+    }
+}
+
+/// Whether the bot answers in groups nobody has used `/enable` or `/disable` in
+///
+/// From `groups_enabled_by_default`, on unless configured otherwise.
+pub(crate) fn groups_enabled_by_default() -> bool {
+    CONFIG.get_bool("groups_enabled_by_default").unwrap_or(true)
+}
+
+/// Chat settings after `/enable` (`enabled`) or `/disable` of the chat or one of its topics
+///
+/// Chats without settings start out as unknown chats are, see
+/// `groups_enabled_by_default`.
+pub(crate) fn toggled(
+    settings: Option<ChatSettings>,
+    thread_id: Option<i64>,
+    is_super: bool,
+    enabled: bool,
+) -> ChatSettings {
+    let mut settings = settings.unwrap_or(ChatSettings {
+        is_supergroup: is_super,
+        threads: HashMap::new(),
+        enabled: groups_enabled_by_default(),
+        auto_enable_threads: None,
+    });
+    match thread_id {
+        Some(thread_id) => {
+            settings.threads.insert(thread_id, enabled);
+        }
+        None => settings.enabled = enabled,
+    }
+    settings
+}
+
+/// Temperature used when neither the chat nor its model sets one
+pub const DEFAULT_TEMPERATURE: f32 = 0.7;
+
+/// Where the effective value of a setting comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SettingSource {
+    /// Set for the chat with a command
+    Chat,
+    /// Per-model default from the configuration
+    ModelDefault,
+    /// Global configuration value
+    Config,
+    /// Compiled-in default
+    BuiltIn,
+}
+
+impl std::fmt::Display for SettingSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SettingSource::Chat => "set per-chat",
+            SettingSource::ModelDefault => "model default",
+            SettingSource::Config => "config default",
+            SettingSource::BuiltIn => "built-in default",
+        })
+    }
+}
+
+/// Effective value of a setting along with its source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved<T> {
+    pub value: T,
+    pub source: SettingSource,
+}
+
+/// Temperature of a chat that hasn't set one, from `model_default_temperatures`
+pub fn default_temperature(model: &str) -> f32 {
+    resolve_default_temperature(model).value
+}
+
+/// Like `default_temperature`, telling whether the model has its own default
+pub fn resolve_default_temperature(model: &str) -> Resolved<f32> {
+    let defaults = CONFIG
+        .get::<HashMap<String, f32>>("model_default_temperatures")
+        .unwrap_or_default();
+    model_temperature(&defaults, model)
+}
+
+fn model_temperature(defaults: &HashMap<String, f32>, model: &str) -> Resolved<f32> {
+    match defaults.get(model) {
+        Some(temperature) => Resolved {
+            value: *temperature,
+            source: SettingSource::ModelDefault,
+        },
+        None => Resolved {
+            value: DEFAULT_TEMPERATURE,
+            source: SettingSource::BuiltIn,
+        },
+    }
+}
+
+/// Completion tokens requested per answer when `max_tokens` is not set
+const DEFAULT_MAX_TOKENS: u32 = 2048;
+
+/// Largest `max_tokens` a chat may set, from `max_tokens_limit`
+pub fn max_tokens_ceiling() -> u32 {
+    CONFIG
+        .get::<u32>("max_tokens_limit")
+        .unwrap_or(32768)
+        .max(1)
+}
+
+/// Checks a `max_tokens` value a chat wants to set
+///
+/// # Returns
+/// * `Ok(u32)` - The value, between 1 and `max_tokens_ceiling`
+/// * `Err(String)` - User-facing reason the value was rejected
+pub fn validate_max_tokens(value: u32) -> Result<u32, String> {
+    let ceiling = max_tokens_ceiling();
+    if (1..=ceiling).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("max_tokens must be between 1 and {}", ceiling))
+    }
+}
+
+/// `max_tokens` of a chat that hasn't set its own
+pub fn resolve_default_max_tokens() -> Resolved<u32> {
+    match CONFIG.get::<u32>("max_tokens") {
+        Ok(value) => Resolved {
+            value: value.max(1),
+            source: SettingSource::Config,
+        },
+        Err(_) => Resolved {
+            value: DEFAULT_MAX_TOKENS,
+            source: SettingSource::BuiltIn,
+        },
+    }
+}
+
+/// Name of the conversation every private chat starts in
+pub const MAIN_SLOT: &str = "main";
+
+/// Largest key index a slot can get; keeps slot keys within their range
+const MAX_SLOT_INDEX: u32 = 1023;
+
+/// Context key of a named conversation slot
+///
+/// Telegram chat ids fit in 52 bits, so `i64::MIN + (chat_id << 10) + index`
+/// lies far below any real chat id and is unique per chat and index.
+pub fn slot_context_key(chat_id: i64, index: u32) -> i64 {
+    i64::MIN + (chat_id << 10) + index as i64
+}
+
+/// Context key of the `/chat` conversation kept apart from plain messages
+///
+/// Telegram chat ids fit in 52 bits, so `2^62 + chat_id` lies far above any
+/// real chat id and away from slot keys, for private chats and groups alike.
+pub fn command_context_key(chat_id: i64) -> i64 {
+    (1 << 62) + chat_id
+}
+
+/// Which conversation of a chat a request reads and extends
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ContextScope {
+    /// The chat's active conversation, shared by plain messages
+    #[default]
+    Main,
+    /// The `/chat` scratch conversation, with `separate_command_context`
+    Command,
+    /// A group reply to the bot: reads the reply chain ending in the message,
+    /// see `reply_chain`, and extends the active conversation
+    Reply(MessageId),
+}
+
+impl ContextScope {
+    /// Scope of a `/chat` request: its own unless `separate_command_context` is off
+    pub fn for_command() -> Self {
+        if CONFIG.get_bool("separate_command_context").unwrap_or(false) {
+            ContextScope::Command
+        } else {
+            ContextScope::Main
+        }
+    }
+}
+
+/// Conversations `/clear` empties
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClearTarget {
+    /// The main conversation, as `/clear` alone
+    Main,
+    /// The `/chat` conversation, as `/clear chat`
+    Command,
+    /// Both, as `/clear all`
+    All,
+}
+
+impl ClearTarget {
+    pub fn scopes(self) -> &'static [ContextScope] {
+        match self {
+            ClearTarget::Main => &[ContextScope::Main],
+            ClearTarget::Command => &[ContextScope::Command],
+            ClearTarget::All => &[ContextScope::Main, ContextScope::Command],
+        }
+    }
+}
+
+impl std::str::FromStr for ClearTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "main" => Ok(ClearTarget::Main),
+            "chat" | "command" => Ok(ClearTarget::Command),
+            "all" | "both" => Ok(ClearTarget::All),
+            other => Err(format!("Unknown conversation: {}", other)),
+        }
+    }
+}
+
+/// Named parallel conversations of a private chat
+///
+/// Each slot keeps its own history under `slot_context_key`; the main
+/// conversation uses the chat id itself. Settings and notes are shared.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatSlots {
+    /// Slot receiving new messages, `None` for the main conversation
+    pub active: Option<String>,
+
+    /// Slot names with their context key index
+    pub slots: BTreeMap<String, u32>,
+}
+
+impl ChatSlots {
+    /// Slot names in alphabetical order, main conversation first
+    pub fn list(&self) -> Vec<&str> {
+        std::iter::once(MAIN_SLOT)
+            .chain(self.slots.keys().map(String::as_str))
+            .collect()
+    }
+
+    /// Name of the active slot
+    pub fn active_name(&self) -> &str {
+        self.active.as_deref().unwrap_or(MAIN_SLOT)
+    }
+
+    /// Creates a slot and makes it active
+    ///
+    /// # Errors
+    /// When the name is taken or the chat already has `max_slots` slots
+    pub fn create(&mut self, name: &str, max_slots: usize) -> Result<(), String> {
+        if name == MAIN_SLOT || self.slots.contains_key(name) {
+            return Err(format!("A chat named '{}' already exists", name));
+        }
+        if self.slots.len() >= max_slots {
+            return Err(format!("You can have at most {} extra chats", max_slots));
+        }
+        let index = (1..=MAX_SLOT_INDEX)
+            .find(|index| !self.slots.values().any(|used| used == index))
+            .ok_or_else(|| "No free chat slots left".to_string())?;
+        self.slots.insert(name.to_string(), index);
+        self.active = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Makes a slot active
+    ///
+    /// # Returns
+    /// `false` if no slot has that name
+    pub fn switch(&mut self, name: &str) -> bool {
+        if name == MAIN_SLOT {
+            self.active = None;
+        } else if self.slots.contains_key(name) {
+            self.active = Some(name.to_string());
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Removes a slot; the main conversation becomes active if it was
+    ///
+    /// # Returns
+    /// Context key of the removed slot, whose history should be cleared
+    pub fn delete(&mut self, chat_id: i64, name: &str) -> Option<i64> {
+        let index = self.slots.remove(name)?;
+        if self.active.as_deref() == Some(name) {
+            self.active = None;
+        }
+        Some(slot_context_key(chat_id, index))
+    }
+
+    /// Context key of the active slot
+    pub fn context_key(&self, chat_id: i64) -> i64 {
+        self.active
+            .as_ref()
+            .and_then(|name| self.slots.get(name))
+            .map_or(chat_id, |index| slot_context_key(chat_id, *index))
+    }
 }
 
 /// Defines the interface for conversation storage implementations
@@ -75,15 +522,23 @@ pub struct ChatSettings {
 /// thread-safe (Send + Sync) and support asynchronous operations.
 #[async_trait]
 pub trait Storage: Send + Sync {
+    /// Short name of the storage backend, e.g. for diagnostics
+    fn backend_name(&self) -> &'static str;
+
     /// Retrieves conversation history for a chat
     ///
     /// # Arguments
     /// * `chat_id` - Unique identifier for the chat session
     ///
     /// # Returns
-    /// Vector of messages representing the conversation history
+    /// Vector of messages representing the conversation history; a pinned
+    /// message stays first even after it falls out of `max_conversation_len`
     async fn get_conversation_context(&self, chat_id: i64) -> Vec<Message>;
 
+    /// Same as `get_conversation_context`, with the position of the pinned
+    /// message in the history, if it is part of it
+    async fn get_conversation_with_pin(&self, chat_id: i64) -> (Vec<Message>, Option<usize>);
+
     /// Adds a message to the conversation history
     ///
     /// # Arguments
@@ -91,12 +546,24 @@ pub trait Storage: Send + Sync {
     /// * `context` - Message to add to the conversation history
     async fn set_conversation_context(&self, chat_id: i64, context: Message);
 
-    /// Clears all conversation history for a chat
+    /// Clears all conversation history for a chat, including the pin
     ///
     /// # Arguments
     /// * `chat_id` - Unique identifier for the chat session
     async fn clear_conversation_context(&self, chat_id: i64);
 
+    /// Pins the latest user message of the conversation
+    ///
+    /// The pinned message is kept at the front of the history when older
+    /// messages are evicted, replacing any previous pin.
+    ///
+    /// # Returns
+    /// `false` if the conversation has no user message
+    async fn pin_last_user_message(&self, chat_id: i64) -> bool;
+
+    /// Removes the pin; the message is then evicted like any other
+    async fn unpin_message(&self, chat_id: i64);
+
     /// Retrieves the system fingerprint for a chat
     ///
     /// The system fingerprint defines the AI personality and behavior characteristics
@@ -115,16 +582,37 @@ pub trait Storage: Send + Sync {
     /// * `fingerprint` - New system fingerprint configuration
     async fn set_system_fingerprint(&self, chat_id: i64, fingerprint: String);
 
+    /// Retrieves the temperature the chat has set itself
+    ///
+    /// # Returns
+    /// `None` when the chat follows the model or global default
+    async fn get_chat_temperature(&self, chat_id: i64) -> Option<f32>;
+
     /// Retrieves the temperature setting for a chat
     ///
     /// Temperature controls the creativity/randomness of AI responses (0.0-2.0)
     ///
     /// # Arguments
     /// * `chat_id` - Unique identifier for the chat session
+    /// * `model` - Model the chat uses, whose default applies when the chat
+    ///   hasn't set a temperature
     ///
     /// # Returns
     /// Current temperature value as f32
-    async fn get_temperature(&self, chat_id: i64) -> f32;
+    async fn get_temperature(&self, chat_id: i64, model: &str) -> f32 {
+        self.resolve_temperature(chat_id, model).await.value
+    }
+
+    /// Like `get_temperature`, along with where the value comes from
+    async fn resolve_temperature(&self, chat_id: i64, model: &str) -> Resolved<f32> {
+        match self.get_chat_temperature(chat_id).await {
+            Some(temperature) => Resolved {
+                value: temperature,
+                source: SettingSource::Chat,
+            },
+            None => resolve_default_temperature(model),
+        }
+    }
 
     /// Updates the temperature setting for a chat
     ///
@@ -133,6 +621,262 @@ pub trait Storage: Send + Sync {
     /// * `temperature` - New temperature value (0.0-2.0)
     async fn set_temperature(&self, chat_id: i64, temperature: f32);
 
+    /// Retrieves the model the chat has switched to with `/model`
+    ///
+    /// # Returns
+    /// `None` when the chat uses the configured `model`
+    async fn get_chat_model(&self, chat_id: i64) -> Option<String>;
+
+    /// Model of a chat's requests: its own, else `model` from configuration
+    ///
+    /// # Returns
+    /// `None` only when neither is set
+    async fn get_model(&self, chat_id: i64) -> Option<String> {
+        match self.get_chat_model(chat_id).await {
+            Some(model) => Some(model),
+            None => CONFIG.get_string("model").ok(),
+        }
+    }
+
+    /// Like `get_model`, along with where the value comes from
+    async fn resolve_model(&self, chat_id: i64) -> Resolved<String> {
+        match self.get_chat_model(chat_id).await {
+            Some(model) => Resolved {
+                value: model,
+                source: SettingSource::Chat,
+            },
+            None => Resolved {
+                value: CONFIG.get_string("model").unwrap_or_default(),
+                source: SettingSource::Config,
+            },
+        }
+    }
+
+    /// Switches the chat to a model or, with `None`, back to the configured one
+    ///
+    /// # Arguments
+    /// * `chat_id` - Unique identifier for the chat session
+    /// * `model` - Model name already checked against `allowed_models`
+    async fn set_model(&self, chat_id: i64, model: Option<String>);
+
+    /// Retrieves the completion token limit the chat has set itself
+    ///
+    /// # Returns
+    /// `None` when the chat follows the global default
+    async fn get_max_tokens(&self, chat_id: i64) -> Option<u32>;
+
+    /// Sets or, with `None`, removes the chat's completion token limit
+    ///
+    /// # Arguments
+    /// * `chat_id` - Unique identifier for the chat session
+    /// * `max_tokens` - Limit already checked by `validate_max_tokens`
+    async fn set_max_tokens(&self, chat_id: i64, max_tokens: Option<u32>);
+
+    /// Completion token limit of a chat's requests, along with where it comes from
+    ///
+    /// A chat's own value is capped at `max_tokens_limit`, which may have
+    /// been lowered since it was set.
+    async fn resolve_max_tokens(&self, chat_id: i64) -> Resolved<u32> {
+        match self.get_max_tokens(chat_id).await {
+            Some(value) => Resolved {
+                value: value.clamp(1, max_tokens_ceiling()),
+                source: SettingSource::Chat,
+            },
+            None => resolve_default_max_tokens(),
+        }
+    }
+
+    /// Retrieves the per-chat override for showing `<think>` blocks
+    ///
+    /// # Returns
+    /// `None` when the chat follows the global `thinking` setting
+    async fn get_thinking(&self, chat_id: i64) -> Option<bool>;
+
+    /// Overrides whether `<think>` blocks are shown in a chat
+    ///
+    /// # Arguments
+    /// * `chat_id` - Unique identifier for the chat session
+    /// * `thinking` - `true` to show model reasoning, `false` to strip it
+    async fn set_thinking(&self, chat_id: i64, thinking: bool);
+
+    /// Retrieves the preferred answer length for a chat
+    ///
+    /// # Returns
+    /// `Brevity::Normal` when nothing was set
+    async fn get_brevity(&self, chat_id: i64) -> Brevity;
+
+    /// Updates the preferred answer length for a chat
+    ///
+    /// # Arguments
+    /// * `chat_id` - Unique identifier for the chat session
+    /// * `brevity` - New answer length preference
+    async fn set_brevity(&self, chat_id: i64, brevity: Brevity);
+
+    /// Retrieves the answer format preset of a chat
+    ///
+    /// # Returns
+    /// `AnswerFormat::Chat` when nothing was set
+    async fn get_answer_format(&self, chat_id: i64) -> AnswerFormat;
+
+    /// Updates the answer format preset of a chat
+    ///
+    /// # Arguments
+    /// * `chat_id` - Unique identifier for the chat session
+    /// * `format` - New answer format preset
+    async fn set_answer_format(&self, chat_id: i64, format: AnswerFormat);
+
+    /// Retrieves the JSON schema answers of a chat must match, if set
+    async fn get_json_schema(&self, chat_id: i64) -> Option<String>;
+
+    /// Sets or, with `None`, clears the JSON schema of a chat
+    ///
+    /// # Arguments
+    /// * `chat_id` - Unique identifier for the chat session
+    /// * `schema` - Schema as JSON text, already checked by `answer_schema::parse`
+    async fn set_json_schema(&self, chat_id: i64, schema: Option<String>);
+
+    /// Marks a chat as started
+    ///
+    /// # Returns
+    /// `true` only the first time this is called for a chat
+    async fn mark_started(&self, chat_id: i64) -> bool;
+
+    /// Marks a chat as told how to `/enable` the bot
+    ///
+    /// # Returns
+    /// `true` only the first time this is called for a chat
+    async fn mark_disabled_hinted(&self, chat_id: i64) -> bool;
+
+    /// Records a request of a chat at `at` (Unix seconds)
+    ///
+    /// # Returns
+    /// The time of the chat's previous request, `None` for its first one
+    async fn touch_last_active(&self, chat_id: i64, at: i64) -> Option<i64>;
+
+    /// Retrieves the chat's own `auto_clear_after_mins`, 0 meaning off
+    ///
+    /// # Returns
+    /// `None` when the chat follows the configured default
+    async fn get_auto_clear_mins(&self, chat_id: i64) -> Option<u32>;
+
+    /// Sets the chat's own `auto_clear_after_mins`; `None` restores the default
+    async fn set_auto_clear_mins(&self, chat_id: i64, minutes: Option<u32>);
+
+    /// Checks whether the bot can still reach a chat
+    ///
+    /// # Returns
+    /// `false` once the user has blocked the bot; `true` by default
+    async fn is_chat_active(&self, chat_id: i64) -> bool;
+
+    /// Marks a chat as reachable or not (e.g. after the bot was blocked)
+    ///
+    /// Inactive chats should be skipped by anything the bot sends unprompted.
+    async fn set_chat_active(&self, chat_id: i64, active: bool);
+
+    /// Retrieves the custom request headers of a chat
+    ///
+    /// These are added on top of the configured `extra_headers`.
+    async fn get_extra_headers(&self, chat_id: i64) -> HashMap<String, String>;
+
+    /// Replaces the custom request headers of a chat; an empty map clears them
+    async fn set_extra_headers(&self, chat_id: i64, headers: HashMap<String, String>);
+
+    /// Retrieves the API key a chat's requests are billed to, if it has its own
+    ///
+    /// The key is a credential: it must never be logged or shown to users.
+    async fn get_api_key(&self, chat_id: i64) -> Option<String>;
+
+    /// Sets or, with `None`, removes the API key of a chat
+    async fn set_api_key(&self, chat_id: i64, api_key: Option<String>);
+
+    /// Retrieves the logit bias of a chat (token id -> bias), empty when unset
+    async fn get_logit_bias(&self, chat_id: i64) -> HashMap<u32, i32>;
+
+    /// Replaces the logit bias of a chat; an empty map clears it
+    async fn set_logit_bias(&self, chat_id: i64, bias: HashMap<u32, i32>);
+
+    // --- Conversation Slots ---
+
+    /// Retrieves the conversation slots of a chat, empty when none were created
+    async fn get_chat_slots(&self, chat_id: i64) -> ChatSlots;
+
+    /// Replaces the conversation slots of a chat
+    async fn set_chat_slots(&self, chat_id: i64, slots: ChatSlots);
+
+    /// Key under which the chat's active conversation is stored
+    ///
+    /// Pass it to the conversation context methods instead of the chat id.
+    async fn context_key(&self, chat_id: i64) -> i64 {
+        self.get_chat_slots(chat_id).await.context_key(chat_id)
+    }
+
+    /// Key of the conversation a scope reads and extends
+    ///
+    /// `ContextScope::Main` and `ContextScope::Reply` use the active slot as
+    /// in `context_key`.
+    async fn scoped_context_key(&self, chat_id: i64, scope: ContextScope) -> i64 {
+        match scope {
+            ContextScope::Main | ContextScope::Reply(_) => self.context_key(chat_id).await,
+            ContextScope::Command => command_context_key(chat_id),
+        }
+    }
+
+    // --- Persona Library ---
+
+    /// Saves a fingerprint under a name, replacing a persona with the same name
+    ///
+    /// # Arguments
+    /// * `chat_id` - Chat owning the persona
+    /// * `name` - Persona name (already normalized by the caller)
+    /// * `fingerprint` - System fingerprint to store
+    async fn save_persona(&self, chat_id: i64, name: String, fingerprint: String);
+
+    /// Retrieves the fingerprint of a stored persona
+    async fn get_persona(&self, chat_id: i64, name: &str) -> Option<String>;
+
+    /// Lists persona names of a chat in alphabetical order
+    async fn list_personas(&self, chat_id: i64) -> Vec<String>;
+
+    // --- Banned Users ---
+
+    /// Stops the bot from answering a user in a chat
+    ///
+    /// # Returns
+    /// `false` if the user was already banned
+    async fn ban_user(&self, chat_id: i64, user_id: u64) -> bool;
+
+    /// Lets a banned user use the bot in the chat again
+    ///
+    /// # Returns
+    /// `false` if the user wasn't banned
+    async fn unban_user(&self, chat_id: i64, user_id: u64) -> bool;
+
+    /// Checks whether a user is banned in a chat
+    async fn is_banned(&self, chat_id: i64, user_id: u64) -> bool;
+
+    /// Lists the users banned in a chat in ascending id order
+    async fn list_banned(&self, chat_id: i64) -> Vec<u64>;
+
+    // --- Chat Log ---
+
+    /// Appends an exchange to the chat's request log
+    async fn append_chat_log(&self, chat_id: i64, entry: ChatLogEntry);
+
+    /// Retrieves a page of the chat's request log
+    ///
+    /// # Arguments
+    /// * `offset` - Number of newest entries to skip
+    /// * `limit` - Maximum number of entries to return
+    ///
+    /// # Returns
+    /// Entries sorted newest first, and the total number of entries
+    async fn get_chat_log(
+        &self,
+        chat_id: i64,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<ChatLogEntry>, usize);
+
     // --- Note Management ---
 
     /// Adds a new note to storage
@@ -143,6 +887,8 @@ pub trait Storage: Send + Sync {
     /// # Implementation Notes
     /// - Should generate unique note_id if not set
     /// - Should validate note ownership
+    /// - Should append to the chat's latest note when `Note::should_merge`
+    ///   allows it for `note_merge_window_secs`
     async fn add_note(&self, note: Note);
 
     /// Removes a specific note
@@ -165,6 +911,24 @@ pub trait Storage: Send + Sync {
 
     /// Deletes all notes in a chat
     async fn erase_notes(&self, chat_id: i64);
+
+    /// Checks whether the chat's notes are added to prompts
+    ///
+    /// # Returns
+    /// `true` by default; `false` when notes are only stored for people
+    async fn get_inject_notes(&self, chat_id: i64) -> bool;
+
+    /// Sets whether the chat's notes are added to prompts
+    async fn set_inject_notes(&self, chat_id: i64, inject: bool);
+
+    /// Replaces all notes of a chat
+    async fn replace_notes(&self, chat_id: i64, notes: Vec<Note>);
+
+    /// Deletes the notes of a chat that match a filter
+    ///
+    /// # Returns
+    /// Number of removed notes
+    async fn remove_notes_where(&self, chat_id: i64, filter: &NoteFilter) -> usize;
     // --- Chat Configuration ---
 
     /// Enables bot functionality in a chat/thread
@@ -191,32 +955,69 @@ pub trait Storage: Send + Sync {
     /// `true` if bot is enabled in the specified context
     ///
     /// # Evaluation Order
-    /// 1. If thread_id provided, check thread-specific setting
-    /// 2. If not enabled in thread, check global chat setting
-    /// 3. Returns false if both not enabled
+    /// 1. Unknown chats follow `groups_enabled_by_default`
+    /// 2. Without a thread (or outside supergroups), the chat setting applies
+    /// 3. In a topic, `ChatSettings::thread_enabled` decides
     async fn is_enabled(&self, chat_id: i64, thread_id: Option<ThreadId>, is_super: bool) -> bool;
+
+    /// Sets whether new forum topics follow the chat's enablement
+    ///
+    /// See `ChatSettings::thread_enabled`.
+    async fn set_auto_enable_threads(&self, chat_id: i64, auto_enable: bool);
+
+    /// Checks whether the chat has enablement settings of its own
+    ///
+    /// # Returns
+    /// `false` until `/enable`, `/disable` or `/autothreads` is used in the chat
+    async fn has_chat_settings(&self, chat_id: i64) -> bool;
 }
 
 /// Creates the appropriate storage implementation based on configuration
 ///
 /// This factory function determines which storage backend to use based on the
-/// `enable_db` configuration setting. It provides automatic fallback to in-memory
-/// storage if database initialization fails.
+/// `storage_backend` and `enable_db` configuration settings. It provides
+/// automatic fallback to in-memory storage if database initialization fails.
 ///
 /// # Returns
 /// Thread-safe storage implementation wrapped in Arc
 ///
 /// # Behavior
-/// 1. Checks `enable_db` configuration:
+/// 1. With `storage_backend = "postgres"` and a `database_url`, connects to
+///    Postgres (requires the `postgres` cargo feature)
+/// 2. Otherwise checks `enable_db` configuration:
 ///    - If false: uses in-memory storage
 ///    - If true: attempts database initialization
-/// 2. Database initialization:
+/// 3. Database initialization:
 ///    - On success: creates database-backed storage
 ///    - On failure: falls back to in-memory storage
-/// 3. Returns storage instance with timing metrics
+/// 4. Returns storage instance with timing metrics
 pub async fn create_storage() -> Arc<dyn Storage> {
     let start_time = std::time::Instant::now();
 
+    if CONFIG
+        .get_string("storage_backend")
+        .is_ok_and(|backend| backend.eq_ignore_ascii_case("postgres"))
+    {
+        return match try_create_pg_storage().await {
+            Ok(storage) => {
+                event!(
+                    Level::INFO,
+                    "Postgres storage initialized in {:?}",
+                    start_time.elapsed()
+                );
+                storage
+            }
+            Err(e) => {
+                event!(
+                    Level::ERROR,
+                    "Postgres storage failed: {}. Falling back to memory",
+                    e
+                );
+                create_memory_storage(start_time).await
+            }
+        };
+    }
+
     // Determine storage type from configuration
     let storage_type = match CONFIG.get_bool("enable_db") {
         Ok(true) => "database",
@@ -275,6 +1076,27 @@ async fn try_create_db_storage() -> Result<Arc<dyn Storage>, String> {
         .map_err(|e| format!("Failed to create DB storage: {}", e))
 }
 
+/// Attempts to create a Postgres-backed storage instance from `database_url`
+async fn try_create_pg_storage() -> Result<Arc<dyn Storage>, String> {
+    let url = crate::system::secret("database_url")
+        .ok_or_else(|| "`database_url` is not set".to_string())?;
+    connect_pg(&url).await
+}
+
+#[cfg(feature = "postgres")]
+async fn connect_pg(url: &str) -> Result<Arc<dyn Storage>, String> {
+    event!(Level::INFO, "Initializing Postgres storage...");
+    pg_storage::PgStorage::new(url)
+        .await
+        .map(|storage| Arc::new(storage) as Arc<dyn Storage>)
+        .map_err(|e| format!("Failed to create Postgres storage: {}", e))
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn connect_pg(_url: &str) -> Result<Arc<dyn Storage>, String> {
+    Err("the bot was built without the `postgres` feature".to_string())
+}
+
 /// Creates an in-memory storage instance
 async fn create_memory_storage(start_time: std::time::Instant) -> Arc<dyn Storage> {
     let storage = Arc::new(MemoryStorage::new());
@@ -285,3 +1107,291 @@ async fn create_memory_storage(start_time: std::time::Instant) -> Arc<dyn Storag
     );
     storage
 }
+
+/// Empty in-memory storage for handler tests
+#[cfg(test)]
+pub(crate) fn memory_storage() -> Arc<dyn Storage> {
+    Arc::new(MemoryStorage::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_temperature_falls_back_to_global_default() {
+        let defaults = HashMap::from([("coder".to_string(), 0.2)]);
+        assert_eq!(
+            model_temperature(&defaults, "coder"),
+            Resolved {
+                value: 0.2,
+                source: SettingSource::ModelDefault
+            }
+        );
+        assert_eq!(
+            model_temperature(&defaults, "other"),
+            Resolved {
+                value: DEFAULT_TEMPERATURE,
+                source: SettingSource::BuiltIn
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat_max_tokens_wins_and_is_capped() {
+        let storage = memory_storage();
+        assert_eq!(
+            storage.resolve_max_tokens(1).await,
+            resolve_default_max_tokens()
+        );
+
+        storage.set_max_tokens(1, Some(512)).await;
+        assert_eq!(
+            storage.resolve_max_tokens(1).await,
+            Resolved {
+                value: 512,
+                source: SettingSource::Chat
+            }
+        );
+
+        // Stored before the limit was lowered
+        storage.set_max_tokens(1, Some(u32::MAX)).await;
+        assert_eq!(
+            storage.resolve_max_tokens(1).await.value,
+            max_tokens_ceiling()
+        );
+
+        storage.set_max_tokens(1, None).await;
+        assert_eq!(storage.get_max_tokens(1).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_chat_model_overrides_configured_one() {
+        let storage = memory_storage();
+        assert_eq!(storage.resolve_model(1).await.source, SettingSource::Config);
+
+        storage.set_model(1, Some("qwen2.5-7b".to_string())).await;
+        assert_eq!(storage.get_model(1).await.as_deref(), Some("qwen2.5-7b"));
+        assert_eq!(storage.resolve_model(1).await.source, SettingSource::Chat);
+        assert_eq!(storage.get_chat_model(2).await, None);
+
+        storage.set_model(1, None).await;
+        assert_eq!(storage.get_chat_model(1).await, None);
+    }
+
+    #[test]
+    fn test_max_tokens_is_validated() {
+        assert_eq!(validate_max_tokens(1), Ok(1));
+        assert!(validate_max_tokens(0).is_err());
+        assert!(validate_max_tokens(max_tokens_ceiling() + 1).is_err());
+    }
+
+    fn note(user_id: u64, created_at: i64) -> Note {
+        Note {
+            note_id: created_at * 1000,
+            chat_id: 1,
+            user_id,
+            text: format!("at {}", created_at),
+            created_at,
+            embedding: None,
+        }
+    }
+
+    #[test]
+    fn test_note_merge_window_boundaries() {
+        let first = note(7, 100);
+        assert!(first.should_merge(&note(7, 100), 30));
+        assert!(first.should_merge(&note(7, 130), 30));
+        assert!(!first.should_merge(&note(7, 131), 30));
+        assert!(!first.should_merge(&note(8, 110), 30));
+        assert!(!first.should_merge(&note(7, 110), 0));
+    }
+
+    #[test]
+    fn test_note_filter_parses_and_matches() {
+        let filter: NoteFilter = "user:7 after:1970-01-01 before:1970-01-02".parse().unwrap();
+        assert_eq!(filter.user_id, Some(7));
+        assert_eq!(filter.after, Some(0));
+        assert_eq!(filter.before, Some(86_400));
+
+        assert!(filter.matches(&note(7, 0)));
+        assert!(filter.matches(&note(7, 86_399)));
+        assert!(!filter.matches(&note(7, 86_400)));
+        assert!(!filter.matches(&note(8, 100)));
+
+        assert!("".parse::<NoteFilter>().unwrap().is_empty());
+        assert!("tag:work".parse::<NoteFilter>().is_err());
+        assert!("after:yesterday".parse::<NoteFilter>().is_err());
+    }
+
+    #[test]
+    fn test_unknown_threads_follow_chat_when_auto_enabled() {
+        let mut settings = ChatSettings {
+            is_supergroup: true,
+            threads: HashMap::from([(1, false), (2, true)]),
+            enabled: true,
+            auto_enable_threads: Some(true),
+        };
+        assert!(!settings.thread_enabled(1));
+        assert!(settings.thread_enabled(2));
+        assert!(settings.thread_enabled(3));
+
+        settings.auto_enable_threads = Some(false);
+        assert!(!settings.thread_enabled(3));
+        assert!(settings.thread_enabled(2));
+
+        settings.auto_enable_threads = Some(true);
+        settings.enabled = false;
+        assert!(!settings.thread_enabled(3));
+    }
+
+    #[test]
+    fn test_parse_notes_json_rejects_invalid_entries() {
+        let json = r#"[
+            {"note_id": 1, "chat_id": 5, "user_id": 7, "text": "keep"},
+            {"note_id": 2, "chat_id": 5, "user_id": 7, "text": "  "},
+            {"note_id": "x", "text": "bad id"},
+            "not a note"
+        ]"#;
+        let (notes, rejected) = parse_notes_json(json, 9).unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].chat_id, 9);
+        assert_eq!(notes[0].text, "keep");
+        assert_eq!(rejected, 3);
+
+        assert!(parse_notes_json("{}", 9).is_err());
+    }
+
+    #[test]
+    fn test_note_merge_appends_text() {
+        let mut first = note(7, 100);
+        first.merge(note(7, 120));
+        assert_eq!(first.text, "at 100\nat 120");
+        assert_eq!(first.created_at, 120);
+        assert_eq!(first.note_id, 100_000);
+    }
+
+    #[test]
+    fn test_chat_slots_switch_and_delete() {
+        let chat_id = 42;
+        let mut slots = ChatSlots::default();
+        assert_eq!(slots.context_key(chat_id), chat_id);
+
+        slots.create("work", 2).unwrap();
+        let work = slots.context_key(chat_id);
+        assert_ne!(work, chat_id);
+        assert!(slots.create("work", 2).is_err());
+        assert!(slots.create(MAIN_SLOT, 2).is_err());
+
+        slots.create("travel", 2).unwrap();
+        assert!(slots.create("third", 2).is_err());
+        assert_ne!(slots.context_key(chat_id), work);
+        assert_eq!(slots.list(), vec![MAIN_SLOT, "travel", "work"]);
+
+        assert!(slots.switch("work"));
+        assert_eq!(slots.context_key(chat_id), work);
+        assert!(!slots.switch("missing"));
+        assert_eq!(slots.active_name(), "work");
+
+        assert_eq!(slots.delete(chat_id, "work"), Some(work));
+        assert_eq!(slots.context_key(chat_id), chat_id);
+        assert_eq!(slots.delete(chat_id, "work"), None);
+
+        // Freed indexes are reused, keys of other chats never collide
+        slots.create("work", 2).unwrap();
+        assert_eq!(slots.context_key(chat_id), work);
+        assert_ne!(slot_context_key(chat_id + 1, 1), work);
+        assert!(work < -(1 << 52));
+    }
+
+    #[tokio::test]
+    async fn test_chat_log_pages_newest_first() {
+        let storage = memory_storage();
+        for created_at in 1..=5 {
+            storage
+                .append_chat_log(
+                    1,
+                    ChatLogEntry {
+                        created_at,
+                        prompt: format!("q{}", created_at),
+                        answer: format!("a{}", created_at),
+                    },
+                )
+                .await;
+        }
+
+        let (page, total) = storage.get_chat_log(1, 0, 2).await;
+        assert_eq!(total, 5);
+        let prompts: Vec<_> = page.iter().map(|entry| entry.prompt.as_str()).collect();
+        assert_eq!(prompts, ["q5", "q4"]);
+        let (page, _) = storage.get_chat_log(1, 4, 2).await;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].prompt, "q1");
+        assert_eq!(storage.get_chat_log(2, 0, 2).await, (Vec::new(), 0));
+    }
+
+    #[tokio::test]
+    async fn test_banned_users_are_per_chat() {
+        let storage = memory_storage();
+        assert!(storage.ban_user(1, 42).await);
+        assert!(!storage.ban_user(1, 42).await);
+        assert!(storage.ban_user(1, 7).await);
+
+        assert!(storage.is_banned(1, 42).await);
+        assert!(!storage.is_banned(2, 42).await);
+        assert_eq!(storage.list_banned(1).await, [7, 42]);
+
+        assert!(storage.unban_user(1, 42).await);
+        assert!(!storage.unban_user(1, 42).await);
+        assert_eq!(storage.list_banned(1).await, [7]);
+    }
+
+    #[tokio::test]
+    async fn test_command_context_is_kept_apart() {
+        async fn contents(storage: &Arc<dyn Storage>, key: i64) -> Vec<String> {
+            storage
+                .get_conversation_context(key)
+                .await
+                .into_iter()
+                .map(|message| message.content)
+                .collect()
+        }
+        let message = |content: &str| Message {
+            role: "user".to_string(),
+            content: content.to_string(),
+            reasoning: None,
+        };
+
+        let storage = memory_storage();
+        for chat_id in [42, -1001234567890] {
+            let main = storage
+                .scoped_context_key(chat_id, ContextScope::Main)
+                .await;
+            let command = storage
+                .scoped_context_key(chat_id, ContextScope::Command)
+                .await;
+            assert_eq!(main, chat_id);
+            assert_ne!(main, command);
+
+            storage
+                .set_conversation_context(main, message("ambient"))
+                .await;
+            storage
+                .set_conversation_context(command, message("scratch"))
+                .await;
+            assert_eq!(contents(&storage, main).await, ["ambient"]);
+            assert_eq!(contents(&storage, command).await, ["scratch"]);
+
+            storage.clear_conversation_context(command).await;
+            assert!(contents(&storage, command).await.is_empty());
+            assert_eq!(contents(&storage, main).await, ["ambient"]);
+        }
+
+        assert_eq!("".parse(), Ok(ClearTarget::Main));
+        assert_eq!(
+            "all".parse::<ClearTarget>().unwrap().scopes(),
+            [ContextScope::Main, ContextScope::Command]
+        );
+        assert!("everything".parse::<ClearTarget>().is_err());
+    }
+}
@@ -0,0 +1,816 @@
+use sqlx::{Decode, Encode, Pool, Postgres, Type, query, query_as, query_scalar};
+use std::{collections::HashMap, sync::Arc};
+use teloxide::types::ThreadId;
+use tracing::{Level, event};
+
+use async_trait::async_trait;
+
+use crate::{
+    CONFIG, Error,
+    answer_format::AnswerFormat,
+    db,
+    lm_types::Message,
+    storage::{
+        ChatLogEntry, ChatSettings, ChatSlots, Note, NoteFilter, Storage,
+        groups_enabled_by_default, toggled,
+    },
+    system::Brevity,
+};
+
+/// Postgres storage, for deployments that share one database across replicas
+///
+/// Mirrors `DbStorage` table by table; the schema is created by the
+/// migrations in `db::postgres`. Per-chat settings live in `users`, keyed by
+/// chat id like in SQLite.
+pub struct PgStorage {
+    db: Arc<Pool<Postgres>>,
+    max_conv_len: usize,
+    /// Context rows kept per chat, as a multiple of `max_conv_len` (0 keeps all)
+    max_stored_context: usize,
+    /// Seconds within which a user's notes merge, see `Note::should_merge`
+    note_merge_window: i64,
+}
+
+impl PgStorage {
+    /// Connects to `database_url` with `pg_max_connections` connections and migrates
+    pub async fn new(url: &str) -> Result<Self, Error> {
+        let max_connections = CONFIG.get::<u32>("pg_max_connections").unwrap_or(5);
+        let db = db::postgres::init_db(url, max_connections).await?;
+        Ok(Self {
+            db: Arc::new(db),
+            max_conv_len: CONFIG.get("max_conversation_len").unwrap_or(20),
+            max_stored_context: CONFIG.get("max_stored_context").unwrap_or(2),
+            note_merge_window: CONFIG.get("note_merge_window_secs").unwrap_or(0),
+        })
+    }
+
+    /// Reads a nullable column of the chat's `users` row
+    async fn user_column<T>(&self, chat_id: i64, column: &str) -> Option<T>
+    where
+        T: for<'r> Decode<'r, Postgres> + Type<Postgres> + Send + Unpin,
+    {
+        query_scalar::<_, Option<T>>(&format!("SELECT {} FROM users WHERE user_id = $1", column))
+            .bind(chat_id)
+            .fetch_optional(&*self.db)
+            .await
+            .ok()
+            .flatten()
+            .flatten()
+    }
+
+    /// Sets a column of the chat's `users` row, creating the row if needed
+    async fn set_user_column<T>(&self, chat_id: i64, column: &str, value: T)
+    where
+        T: for<'q> Encode<'q, Postgres> + Type<Postgres> + Send,
+    {
+        let result = query(&format!(
+            "INSERT INTO users (user_id, {0}, context_len) VALUES ($1, $2, 0)
+            ON CONFLICT (user_id) DO UPDATE SET {0} = EXCLUDED.{0}",
+            column
+        ))
+        .bind(chat_id)
+        .bind(value)
+        .execute(&*self.db)
+        .await;
+        event!(Level::INFO, "Set {}: {:?}", column, result);
+    }
+
+    /// Deletes old context rows, as `DbStorage::prune_context` does
+    async fn prune_context(&self, chat_id: i64) {
+        if self.max_stored_context == 0 {
+            return;
+        }
+        let keep = (self.max_conv_len * self.max_stored_context) as i64;
+        match query(
+            "DELETE FROM context
+            WHERE user_id = $1
+                AND id <= (SELECT id FROM context WHERE user_id = $1 ORDER BY id DESC LIMIT 1 OFFSET $2)
+                AND id IS DISTINCT FROM (SELECT pinned_id FROM users WHERE user_id = $1)",
+        )
+        .bind(chat_id)
+        .bind(keep)
+        .execute(&*self.db)
+        .await
+        {
+            Ok(result) if result.rows_affected() > 0 => event!(
+                Level::DEBUG,
+                "Pruned {} context rows of chat {}",
+                result.rows_affected(),
+                chat_id
+            ),
+            Ok(_) => {}
+            Err(e) => event!(Level::WARN, "Failed to prune context of chat {}: {}", chat_id, e),
+        }
+    }
+
+    async fn chat_settings(&self, chat_id: i64) -> Option<ChatSettings> {
+        query_scalar::<_, String>("SELECT settings FROM chat_settings WHERE chat_id = $1")
+            .bind(chat_id)
+            .fetch_optional(&*self.db)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|json| serde_json::from_str(&json).ok())
+    }
+
+    async fn save_chat_settings(&self, chat_id: i64, settings: &ChatSettings) {
+        let Ok(json) = serde_json::to_string(settings) else {
+            return;
+        };
+        let result = query(
+            "INSERT INTO chat_settings (chat_id, settings) VALUES ($1, $2)
+            ON CONFLICT (chat_id) DO UPDATE SET settings = EXCLUDED.settings",
+        )
+        .bind(chat_id)
+        .bind(json)
+        .execute(&*self.db)
+        .await;
+        event!(Level::INFO, "Save_chat_settings: {:?}", result);
+    }
+
+    /// Replaces the notes of a chat in one transaction
+    async fn try_replace_notes(&self, chat_id: i64, notes: Vec<Note>) -> Result<(), sqlx::Error> {
+        let mut tx = self.db.begin().await?;
+        query("DELETE FROM notes WHERE chat_id = $1")
+            .bind(chat_id)
+            .execute(&mut *tx)
+            .await?;
+        for note in notes {
+            query(
+                "INSERT INTO notes (note_id, chat_id, user_id, text, created_at, embedding)
+                VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (chat_id, note_id) DO NOTHING",
+            )
+            .bind(note.note_id)
+            .bind(chat_id)
+            .bind(note.user_id as i64)
+            .bind(note.text)
+            .bind(note.created_at)
+            .bind(
+                note.embedding
+                    .and_then(|embedding| serde_json::to_string(&embedding).ok()),
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn insert_note(&self, note: &Note) -> Result<(), sqlx::Error> {
+        query(
+            "INSERT INTO notes (note_id, chat_id, user_id, text, created_at, embedding)
+            VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (chat_id, note_id) DO NOTHING",
+        )
+        .bind(note.note_id)
+        .bind(note.chat_id)
+        .bind(note.user_id as i64)
+        .bind(&note.text)
+        .bind(note.created_at)
+        .bind(
+            note.embedding
+                .as_ref()
+                .and_then(|embedding| serde_json::to_string(embedding).ok()),
+        )
+        .execute(&*self.db)
+        .await
+        .map(|_| ())
+    }
+}
+
+/// Row of `notes` as read by `list_notes`
+type NoteRow = (i64, i64, String, i64, Option<String>);
+
+#[async_trait]
+impl Storage for PgStorage {
+    fn backend_name(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn get_conversation_context(&self, chat_id: i64) -> Vec<Message> {
+        self.get_conversation_with_pin(chat_id).await.0
+    }
+
+    async fn get_conversation_with_pin(&self, chat_id: i64) -> (Vec<Message>, Option<usize>) {
+        let Ok(Some((context_len, pinned_id))) = query_as::<_, (i64, Option<i64>)>(
+            "SELECT context_len, pinned_id FROM users WHERE user_id = $1",
+        )
+        .bind(chat_id)
+        .fetch_optional(&*self.db)
+        .await
+        else {
+            return (vec![], None);
+        };
+        let len = context_len.min(self.max_conv_len as i64);
+        if len <= 0 {
+            return (vec![], None);
+        }
+
+        let Ok(rows) = query_as::<_, (i64, String, String)>(
+            "SELECT id, message, responder FROM context WHERE user_id = $1 ORDER BY id DESC LIMIT $2",
+        )
+        .bind(chat_id)
+        .bind(len)
+        .fetch_all(&*self.db)
+        .await
+        else {
+            return (vec![], None);
+        };
+        let oldest = rows.last().map(|(id, _, _)| *id);
+        let mut ids: Vec<i64> = rows.iter().map(|(id, _, _)| *id).collect();
+        let mut messages: Vec<Message> = rows
+            .into_iter()
+            .map(|(_, content, role)| Message {
+                content,
+                role,
+                reasoning: None,
+            })
+            .collect();
+
+        // The pinned message replaces the oldest one once it falls out of the window
+        if let (Some(pinned_id), Some(oldest)) = (pinned_id, oldest) {
+            if pinned_id < oldest {
+                let pinned = query_as::<_, (String, String)>(
+                    "SELECT message, responder FROM context WHERE id = $1",
+                )
+                .bind(pinned_id)
+                .fetch_one(&*self.db)
+                .await;
+                if let Ok((content, role)) = pinned {
+                    if messages.len() as i64 >= len {
+                        messages.pop();
+                        ids.pop();
+                    }
+                    messages.push(Message {
+                        content,
+                        role,
+                        reasoning: None,
+                    });
+                    ids.push(pinned_id);
+                }
+            }
+        }
+
+        messages.reverse();
+        ids.reverse();
+        let pinned = pinned_id.and_then(|pinned_id| ids.iter().position(|id| *id == pinned_id));
+        (messages, pinned)
+    }
+
+    async fn set_conversation_context(&self, chat_id: i64, context: Message) {
+        event!(
+            Level::INFO,
+            "Set conversation 1: {:?}",
+            query("INSERT INTO context (user_id, message, responder) VALUES ($1, $2, $3)")
+                .bind(chat_id)
+                .bind(&context.content)
+                .bind(&context.role)
+                .execute(&*self.db)
+                .await
+        );
+        event!(
+            Level::INFO,
+            "Update user context_len: {:?}",
+            query(
+                "INSERT INTO users (user_id, context_len) VALUES ($1, 1)
+                ON CONFLICT (user_id) DO UPDATE SET context_len = users.context_len + 1"
+            )
+            .bind(chat_id)
+            .execute(&*self.db)
+            .await
+        );
+        self.prune_context(chat_id).await;
+    }
+
+    async fn clear_conversation_context(&self, chat_id: i64) {
+        event!(
+            Level::INFO,
+            "clear_conversation: {:?}",
+            query(
+                "INSERT INTO users (user_id, context_len) VALUES ($1, 0)
+                ON CONFLICT (user_id) DO UPDATE SET context_len = 0, pinned_id = NULL"
+            )
+            .bind(chat_id)
+            .execute(&*self.db)
+            .await
+        );
+    }
+
+    async fn pin_last_user_message(&self, chat_id: i64) -> bool {
+        let context_len = self
+            .user_column::<i64>(chat_id, "context_len")
+            .await
+            .unwrap_or(0);
+
+        // Only messages since the last clear belong to the conversation
+        let last_user = query_scalar::<_, i64>(
+            "SELECT id FROM (SELECT id, responder FROM context WHERE user_id = $1 ORDER BY id DESC LIMIT $2) AS recent
+            WHERE responder = 'user'
+            ORDER BY id DESC
+            LIMIT 1",
+        )
+        .bind(chat_id)
+        .bind(context_len)
+        .fetch_optional(&*self.db)
+        .await
+        .ok()
+        .flatten();
+        let Some(id) = last_user else {
+            return false;
+        };
+
+        self.set_user_column(chat_id, "pinned_id", id).await;
+        true
+    }
+
+    async fn unpin_message(&self, chat_id: i64) {
+        self.set_user_column(chat_id, "pinned_id", None::<i64>)
+            .await;
+    }
+
+    async fn get_system_fingerprint(&self, chat_id: i64) -> String {
+        self.user_column(chat_id, "system")
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn set_system_fingerprint(&self, chat_id: i64, fingerprint: String) {
+        self.set_user_column(chat_id, "system", fingerprint).await;
+    }
+
+    async fn get_chat_temperature(&self, chat_id: i64) -> Option<f32> {
+        self.user_column(chat_id, "temperature").await
+    }
+
+    async fn set_temperature(&self, chat_id: i64, temperature: f32) {
+        self.set_user_column(chat_id, "temperature", temperature)
+            .await;
+    }
+
+    async fn get_chat_model(&self, chat_id: i64) -> Option<String> {
+        self.user_column::<String>(chat_id, "model").await
+    }
+
+    async fn set_model(&self, chat_id: i64, model: Option<String>) {
+        self.set_user_column(chat_id, "model", model).await;
+    }
+
+    async fn get_max_tokens(&self, chat_id: i64) -> Option<u32> {
+        self.user_column::<i64>(chat_id, "max_tokens")
+            .await
+            .and_then(|value| u32::try_from(value).ok())
+    }
+
+    async fn set_max_tokens(&self, chat_id: i64, max_tokens: Option<u32>) {
+        self.set_user_column(chat_id, "max_tokens", max_tokens.map(i64::from))
+            .await;
+    }
+
+    async fn get_thinking(&self, chat_id: i64) -> Option<bool> {
+        self.user_column(chat_id, "thinking").await
+    }
+
+    async fn set_thinking(&self, chat_id: i64, thinking: bool) {
+        self.set_user_column(chat_id, "thinking", thinking).await;
+    }
+
+    async fn get_brevity(&self, chat_id: i64) -> Brevity {
+        self.user_column::<String>(chat_id, "brevity")
+            .await
+            .and_then(|brevity| brevity.parse().ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_brevity(&self, chat_id: i64, brevity: Brevity) {
+        self.set_user_column(chat_id, "brevity", brevity.as_str())
+            .await;
+    }
+
+    async fn get_answer_format(&self, chat_id: i64) -> AnswerFormat {
+        self.user_column::<String>(chat_id, "answer_format")
+            .await
+            .and_then(|format| format.parse().ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_answer_format(&self, chat_id: i64, format: AnswerFormat) {
+        self.set_user_column(chat_id, "answer_format", format.as_str())
+            .await;
+    }
+
+    async fn get_json_schema(&self, chat_id: i64) -> Option<String> {
+        self.user_column::<String>(chat_id, "json_schema").await
+    }
+
+    async fn set_json_schema(&self, chat_id: i64, schema: Option<String>) {
+        self.set_user_column(chat_id, "json_schema", schema).await;
+    }
+
+    async fn mark_started(&self, chat_id: i64) -> bool {
+        if self
+            .user_column::<bool>(chat_id, "started")
+            .await
+            .unwrap_or(false)
+        {
+            return false;
+        }
+        self.set_user_column(chat_id, "started", true).await;
+        true
+    }
+
+    async fn mark_disabled_hinted(&self, chat_id: i64) -> bool {
+        if self
+            .user_column::<bool>(chat_id, "disabled_hinted")
+            .await
+            .unwrap_or(false)
+        {
+            return false;
+        }
+        self.set_user_column(chat_id, "disabled_hinted", true).await;
+        true
+    }
+
+    async fn touch_last_active(&self, chat_id: i64, at: i64) -> Option<i64> {
+        let previous = self.user_column::<i64>(chat_id, "last_active").await;
+        self.set_user_column(chat_id, "last_active", at).await;
+        previous
+    }
+
+    async fn get_auto_clear_mins(&self, chat_id: i64) -> Option<u32> {
+        self.user_column::<i64>(chat_id, "auto_clear_mins")
+            .await
+            .and_then(|minutes| u32::try_from(minutes).ok())
+    }
+
+    async fn set_auto_clear_mins(&self, chat_id: i64, minutes: Option<u32>) {
+        self.set_user_column(chat_id, "auto_clear_mins", minutes.map(i64::from))
+            .await;
+    }
+
+    async fn is_chat_active(&self, chat_id: i64) -> bool {
+        !self
+            .user_column::<bool>(chat_id, "inactive")
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn set_chat_active(&self, chat_id: i64, active: bool) {
+        self.set_user_column(chat_id, "inactive", !active).await;
+    }
+
+    async fn get_extra_headers(&self, chat_id: i64) -> HashMap<String, String> {
+        self.user_column::<String>(chat_id, "extra_headers")
+            .await
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_extra_headers(&self, chat_id: i64, headers: HashMap<String, String>) {
+        let headers = if headers.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&headers).ok()
+        };
+        self.set_user_column(chat_id, "extra_headers", headers)
+            .await;
+    }
+
+    async fn get_api_key(&self, chat_id: i64) -> Option<String> {
+        self.user_column::<String>(chat_id, "api_key").await
+    }
+
+    async fn set_api_key(&self, chat_id: i64, api_key: Option<String>) {
+        self.set_user_column(chat_id, "api_key", api_key).await;
+    }
+
+    async fn get_logit_bias(&self, chat_id: i64) -> HashMap<u32, i32> {
+        self.user_column::<String>(chat_id, "logit_bias")
+            .await
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_logit_bias(&self, chat_id: i64, bias: HashMap<u32, i32>) {
+        let bias = if bias.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&bias).ok()
+        };
+        self.set_user_column(chat_id, "logit_bias", bias).await;
+    }
+
+    async fn get_chat_slots(&self, chat_id: i64) -> ChatSlots {
+        self.user_column::<String>(chat_id, "chat_slots")
+            .await
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    async fn set_chat_slots(&self, chat_id: i64, slots: ChatSlots) {
+        let slots = if slots == ChatSlots::default() {
+            None
+        } else {
+            serde_json::to_string(&slots).ok()
+        };
+        self.set_user_column(chat_id, "chat_slots", slots).await;
+    }
+
+    async fn save_persona(&self, chat_id: i64, name: String, fingerprint: String) {
+        event!(
+            Level::INFO,
+            "Save_persona: {:?}",
+            query(
+                "INSERT INTO personas (chat_id, name, fingerprint) VALUES ($1, $2, $3)
+                ON CONFLICT (chat_id, name) DO UPDATE SET fingerprint = EXCLUDED.fingerprint"
+            )
+            .bind(chat_id)
+            .bind(name)
+            .bind(fingerprint)
+            .execute(&*self.db)
+            .await
+        );
+    }
+
+    async fn get_persona(&self, chat_id: i64, name: &str) -> Option<String> {
+        query_scalar("SELECT fingerprint FROM personas WHERE chat_id = $1 AND name = $2")
+            .bind(chat_id)
+            .bind(name)
+            .fetch_optional(&*self.db)
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn list_personas(&self, chat_id: i64) -> Vec<String> {
+        query_scalar("SELECT name FROM personas WHERE chat_id = $1 ORDER BY name")
+            .bind(chat_id)
+            .fetch_all(&*self.db)
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn ban_user(&self, chat_id: i64, user_id: u64) -> bool {
+        let result = query(
+            "INSERT INTO banned_users (chat_id, user_id) VALUES ($1, $2)
+            ON CONFLICT (chat_id, user_id) DO NOTHING",
+        )
+        .bind(chat_id)
+        .bind(user_id as i64)
+        .execute(&*self.db)
+        .await;
+        event!(Level::INFO, "Ban_user: {:?}", result);
+        result.is_ok_and(|done| done.rows_affected() > 0)
+    }
+
+    async fn unban_user(&self, chat_id: i64, user_id: u64) -> bool {
+        let result = query("DELETE FROM banned_users WHERE chat_id = $1 AND user_id = $2")
+            .bind(chat_id)
+            .bind(user_id as i64)
+            .execute(&*self.db)
+            .await;
+        event!(Level::INFO, "Unban_user: {:?}", result);
+        result.is_ok_and(|done| done.rows_affected() > 0)
+    }
+
+    async fn is_banned(&self, chat_id: i64, user_id: u64) -> bool {
+        query_scalar::<_, i64>(
+            "SELECT user_id FROM banned_users WHERE chat_id = $1 AND user_id = $2",
+        )
+        .bind(chat_id)
+        .bind(user_id as i64)
+        .fetch_optional(&*self.db)
+        .await
+        .ok()
+        .flatten()
+        .is_some()
+    }
+
+    async fn list_banned(&self, chat_id: i64) -> Vec<u64> {
+        query_scalar::<_, i64>(
+            "SELECT user_id FROM banned_users WHERE chat_id = $1 ORDER BY user_id",
+        )
+        .bind(chat_id)
+        .fetch_all(&*self.db)
+        .await
+        .map(|ids| ids.into_iter().map(|id| id as u64).collect())
+        .unwrap_or_default()
+    }
+
+    async fn append_chat_log(&self, chat_id: i64, entry: ChatLogEntry) {
+        event!(
+            Level::INFO,
+            "Append_chat_log: {:?}",
+            query(
+                "INSERT INTO chat_logs (chat_id, created_at, prompt, answer) VALUES ($1, $2, $3, $4)"
+            )
+            .bind(chat_id)
+            .bind(entry.created_at)
+            .bind(entry.prompt)
+            .bind(entry.answer)
+            .execute(&*self.db)
+            .await
+        );
+    }
+
+    async fn get_chat_log(
+        &self,
+        chat_id: i64,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<ChatLogEntry>, usize) {
+        let total = query_scalar::<_, i64>("SELECT COUNT(*) FROM chat_logs WHERE chat_id = $1")
+            .bind(chat_id)
+            .fetch_one(&*self.db)
+            .await
+            .map(|count| count as usize)
+            .unwrap_or(0);
+
+        let entries = query_as::<_, (i64, String, String)>(
+            "SELECT created_at, prompt, answer FROM chat_logs
+            WHERE chat_id = $1 ORDER BY id DESC LIMIT $2 OFFSET $3",
+        )
+        .bind(chat_id)
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&*self.db)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|(created_at, prompt, answer)| ChatLogEntry {
+                    created_at,
+                    prompt,
+                    answer,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+        (entries, total)
+    }
+
+    async fn add_note(&self, note: Note) {
+        let latest = self.list_notes(note.chat_id).await.into_iter().next();
+        if let Some(mut latest) =
+            latest.filter(|last| last.should_merge(&note, self.note_merge_window))
+        {
+            latest.merge(note);
+            event!(
+                Level::INFO,
+                "Merge_note: {:?}",
+                query(
+                    "UPDATE notes SET text = $1, created_at = $2, embedding = NULL
+                    WHERE chat_id = $3 AND note_id = $4"
+                )
+                .bind(&latest.text)
+                .bind(latest.created_at)
+                .bind(latest.chat_id)
+                .bind(latest.note_id)
+                .execute(&*self.db)
+                .await
+            );
+            return;
+        }
+        event!(Level::INFO, "Add_note: {:?}", self.insert_note(&note).await);
+    }
+
+    async fn remove_note(&self, chat_id: i64, note_id: i64) {
+        event!(
+            Level::INFO,
+            "Remove_note: {:?}",
+            query("DELETE FROM notes WHERE chat_id = $1 AND note_id = $2")
+                .bind(chat_id)
+                .bind(note_id)
+                .execute(&*self.db)
+                .await
+        );
+    }
+
+    async fn list_notes(&self, chat_id: i64) -> Vec<Note> {
+        query_as::<_, NoteRow>(
+            "SELECT note_id, user_id, text, created_at, embedding FROM notes
+            WHERE chat_id = $1 ORDER BY created_at DESC, note_id DESC",
+        )
+        .bind(chat_id)
+        .fetch_all(&*self.db)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|(note_id, user_id, text, created_at, embedding)| Note {
+                    note_id,
+                    chat_id,
+                    user_id: user_id as u64,
+                    text,
+                    created_at,
+                    embedding: embedding.and_then(|json| serde_json::from_str(&json).ok()),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    async fn erase_notes(&self, chat_id: i64) {
+        event!(
+            Level::INFO,
+            "Erase_notes: {:?}",
+            query("DELETE FROM notes WHERE chat_id = $1")
+                .bind(chat_id)
+                .execute(&*self.db)
+                .await
+        );
+    }
+
+    async fn get_inject_notes(&self, chat_id: i64) -> bool {
+        self.user_column(chat_id, "inject_notes")
+            .await
+            .unwrap_or(true)
+    }
+
+    async fn set_inject_notes(&self, chat_id: i64, inject: bool) {
+        self.set_user_column(chat_id, "inject_notes", inject).await;
+    }
+
+    async fn replace_notes(&self, chat_id: i64, notes: Vec<Note>) {
+        event!(
+            Level::INFO,
+            "Replace_notes: {:?}",
+            self.try_replace_notes(chat_id, notes).await
+        );
+    }
+
+    async fn remove_notes_where(&self, chat_id: i64, filter: &NoteFilter) -> usize {
+        let result = query(
+            "DELETE FROM notes WHERE chat_id = $1
+            AND ($2::BIGINT IS NULL OR user_id = $2)
+            AND ($3::BIGINT IS NULL OR created_at >= $3)
+            AND ($4::BIGINT IS NULL OR created_at < $4)",
+        )
+        .bind(chat_id)
+        .bind(filter.user_id.map(|user_id| user_id as i64))
+        .bind(filter.after)
+        .bind(filter.before)
+        .execute(&*self.db)
+        .await;
+        event!(Level::INFO, "Remove_notes_where: {:?}", result);
+        result.map_or(0, |done| done.rows_affected() as usize)
+    }
+
+    async fn enable(&self, chat_id: i64, thread_id: Option<i64>, is_super: bool) {
+        let settings = toggled(self.chat_settings(chat_id).await, thread_id, is_super, true);
+        self.save_chat_settings(chat_id, &settings).await;
+    }
+
+    async fn disable(&self, chat_id: i64, thread_id: Option<i64>, is_super: bool) {
+        let settings = toggled(
+            self.chat_settings(chat_id).await,
+            thread_id,
+            is_super,
+            false,
+        );
+        self.save_chat_settings(chat_id, &settings).await;
+    }
+
+    async fn is_enabled(&self, chat_id: i64, thread_id: Option<ThreadId>, _is_super: bool) -> bool {
+        let Some(chat) = self.chat_settings(chat_id).await else {
+            return groups_enabled_by_default();
+        };
+        match thread_id {
+            Some(thread_id) if chat.is_supergroup => chat.thread_enabled(thread_id.0.0 as i64),
+            _ => chat.enabled,
+        }
+    }
+
+    async fn set_auto_enable_threads(&self, chat_id: i64, auto_enable: bool) {
+        let mut settings = self.chat_settings(chat_id).await.unwrap_or(ChatSettings {
+            is_supergroup: true,
+            threads: HashMap::new(),
+            enabled: groups_enabled_by_default(),
+            auto_enable_threads: None,
+        });
+        settings.auto_enable_threads = Some(auto_enable);
+        self.save_chat_settings(chat_id, &settings).await;
+    }
+
+    async fn has_chat_settings(&self, chat_id: i64) -> bool {
+        self.chat_settings(chat_id).await.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggled_keeps_other_settings() {
+        let settings = toggled(None, None, false, false);
+        assert!(!settings.enabled);
+        assert!(!settings.is_supergroup);
+
+        // A new chat with one topic disabled stays enabled elsewhere
+        let settings = toggled(None, Some(3), true, false);
+        assert!(settings.enabled);
+        assert!(!settings.thread_enabled(3));
+
+        let settings = toggled(Some(settings), Some(3), true, true);
+        assert!(settings.thread_enabled(3));
+        let settings = toggled(Some(settings), None, true, false);
+        assert!(!settings.enabled);
+        assert_eq!(settings.threads.get(&3), Some(&true));
+    }
+}
@@ -0,0 +1,215 @@
+//! Summary Note Module
+//!
+//! Keeps a single "conversation summary" note per chat up to date. With
+//! `summary_pin_every` set, every N answered requests a background call asks
+//! the model to rewrite the note in place; `/summarypin` refreshes it on
+//! demand. Like any note it is listed by `/listnotes` and injected into
+//! prompts, so it serves as long-term memory once old turns leave the context.
+//! Turn counts are kept in memory and start over when the bot restarts.
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::sync::Arc;
+use tracing::{Level, event};
+
+use crate::{
+    CONFIG,
+    lm_types::Message,
+    storage::{Note, Storage},
+    system,
+};
+
+/// First line of the summary note, which identifies it
+pub const SUMMARY_PREFIX: &str = "📌 Conversation summary:\n";
+
+/// Author id of the summary note, no Telegram user has it
+const SUMMARY_AUTHOR: u64 = 0;
+
+const SUMMARY_TEMPERATURE: f32 = 0.3;
+
+const SUMMARY_INSTRUCTION: &str = "Summarize the conversation in a short TL;DR for later reference. \
+Keep names, facts, decisions and open questions; drop small talk. \
+Answer with the summary only, in the language of the conversation.";
+
+/// Answered requests per chat since its last summary
+static TURNS: Lazy<DashMap<i64, u32>> = Lazy::new(DashMap::new);
+
+/// Counts an answered request of the chat
+///
+/// # Returns
+/// `true` every `every` turns, when the summary is due; never for 0
+fn count_turn(turns: &DashMap<i64, u32>, chat_id: i64, every: u32) -> bool {
+    if every == 0 {
+        return false;
+    }
+    let mut count = turns.entry(chat_id).or_insert(0);
+    *count += 1;
+    if *count >= every {
+        *count = 0;
+        return true;
+    }
+    false
+}
+
+pub fn is_summary(note: &Note) -> bool {
+    note.user_id == SUMMARY_AUTHOR && note.text.starts_with(SUMMARY_PREFIX)
+}
+
+/// Replaces the summary note among `notes`, keeping its id, or adds one
+///
+/// Any further summary notes are dropped, so the chat has exactly one.
+fn upsert_summary(notes: Vec<Note>, chat_id: i64, summary: &str, now: i64) -> Vec<Note> {
+    let note_id = notes
+        .iter()
+        .find(|note| is_summary(note))
+        .map(|note| note.note_id)
+        .unwrap_or(now * 1000);
+    let mut notes: Vec<Note> = notes.into_iter().filter(|note| !is_summary(note)).collect();
+    notes.push(Note {
+        note_id,
+        chat_id,
+        user_id: SUMMARY_AUTHOR,
+        text: format!("{}{}", SUMMARY_PREFIX, summary.trim()),
+        created_at: now,
+        embedding: None,
+    });
+    notes
+}
+
+/// Asks the model for a new summary of the chat and stores it
+///
+/// # Returns
+/// * `Ok(String)` - The new summary
+/// * `Err(String)` - User-facing reason nothing was stored
+pub async fn refresh(chat_id: i64, storage: &Arc<dyn Storage>) -> Result<String, String> {
+    let history: Vec<Message> = storage
+        .get_conversation_context(storage.context_key(chat_id).await)
+        .await
+        .into_iter()
+        .filter(|message| message.role != "system")
+        .collect();
+    if history.is_empty() {
+        return Err("Nothing to summarize yet".to_string());
+    }
+
+    let notes = storage.list_notes(chat_id).await;
+    let mut instruction = SUMMARY_INSTRUCTION.to_string();
+    if let Some(previous) = notes.iter().find(|note| is_summary(note)) {
+        instruction.push_str("\n\nUpdate this earlier summary:\n");
+        instruction.push_str(&previous.text[SUMMARY_PREFIX.len()..]);
+    }
+    let mut messages = vec![Message {
+        role: "system".to_string(),
+        content: instruction,
+        reasoning: None,
+    }];
+    messages.extend(history);
+    messages.push(Message {
+        role: "user".to_string(),
+        content: "Write the summary of the conversation above.".to_string(),
+        reasoning: None,
+    });
+
+    let summary = system::complete(chat_id, storage, &messages, SUMMARY_TEMPERATURE).await?;
+    if summary.trim().is_empty() {
+        return Err("The model returned an empty summary".to_string());
+    }
+    store_summary(chat_id, storage, &summary).await;
+    Ok(summary.trim().to_string())
+}
+
+/// Puts `summary` in place of the chat's summary note
+///
+/// Notes are read again here rather than before the model call, so notes
+/// added or removed while it was answering are kept as they are.
+async fn store_summary(chat_id: i64, storage: &Arc<dyn Storage>, summary: &str) {
+    let notes = storage.list_notes(chat_id).await;
+    let now = chrono::Utc::now().timestamp();
+    storage
+        .replace_notes(chat_id, upsert_summary(notes, chat_id, summary, now))
+        .await;
+}
+
+/// Counts an answered request and refreshes the summary in the background when due
+pub fn after_turn(chat_id: i64, storage: &Arc<dyn Storage>) {
+    let every = CONFIG.get::<u32>("summary_pin_every").unwrap_or(0);
+    if !count_turn(&TURNS, chat_id, every) {
+        return;
+    }
+    let storage = storage.clone();
+    tokio::spawn(async move {
+        match refresh(chat_id, &storage).await {
+            Ok(_) => event!(Level::INFO, "Updated summary note of chat {}", chat_id),
+            Err(e) => event!(
+                Level::WARN,
+                "Failed to update summary note of chat {}: {}",
+                chat_id,
+                e
+            ),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(note_id: i64, user_id: u64, text: &str) -> Note {
+        Note {
+            note_id,
+            chat_id: 1,
+            user_id,
+            text: text.to_string(),
+            created_at: 0,
+            embedding: None,
+        }
+    }
+
+    #[test]
+    fn test_count_turn_fires_every_n() {
+        let turns = DashMap::new();
+        let due: Vec<bool> = (0..6).map(|_| count_turn(&turns, 1, 3)).collect();
+        assert_eq!(due, [false, false, true, false, false, true]);
+        assert!(!count_turn(&turns, 2, 0));
+    }
+
+    #[test]
+    fn test_summary_is_updated_in_place() {
+        let summary = format!("{}old", SUMMARY_PREFIX);
+        let notes = vec![
+            note(5, 42, "buy milk"),
+            note(7, SUMMARY_AUTHOR, &summary),
+            // A user's note that merely quotes the prefix is not the summary
+            note(9, 42, &summary),
+        ];
+
+        let notes = upsert_summary(notes, 1, " new ", 100);
+        let summaries: Vec<&Note> = notes.iter().filter(|note| is_summary(note)).collect();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].note_id, 7);
+        assert_eq!(summaries[0].text, format!("{}new", SUMMARY_PREFIX));
+        assert_eq!(notes.len(), 3);
+
+        // Without one, a new summary is added
+        let notes = upsert_summary(vec![note(5, 42, "buy milk")], 1, "first", 100);
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[1].note_id, 100_000);
+    }
+
+    #[tokio::test]
+    async fn test_notes_changed_during_the_call_are_kept() {
+        let storage = crate::storage::memory_storage();
+        storage.add_note(note(5, 42, "buy milk")).await;
+
+        // While the model answers, one note is removed and another added
+        storage.remove_note(1, 5).await;
+        storage.add_note(note(6, 43, "call Bob")).await;
+        store_summary(1, &storage, "new").await;
+
+        let notes = storage.list_notes(1).await;
+        assert_eq!(notes.len(), 2);
+        assert!(notes.iter().any(|note| note.text == "call Bob"));
+        assert!(!notes.iter().any(|note| note.text == "buy milk"));
+        assert_eq!(notes.iter().filter(|note| is_summary(note)).count(), 1);
+    }
+}
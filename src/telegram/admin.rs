@@ -0,0 +1,142 @@
+//! Admin Check Module
+//!
+//! Resolves whether a user administers a chat. Administrator lists are cached
+//! per chat for `admin_cache_ttl` seconds and invalidated by `chat_member`
+//! updates, so admin-gated commands don't hit the Bot API every time.
+//! Bot owners are configured statically via `owner_ids`. Users banned from
+//! a group with `/ban` are ignored there.
+
+use dashmap::{DashMap, DashSet};
+use once_cell::sync::Lazy;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use teloxide::{
+    Bot,
+    prelude::*,
+    types::{ChatMemberUpdated, UserId},
+};
+use tracing::debug;
+
+use crate::{CONFIG, storage::Storage};
+
+/// Chat id -> (fetch time, administrator ids)
+static ADMIN_CACHE: Lazy<DashMap<ChatId, (Instant, Vec<UserId>)>> = Lazy::new(DashMap::new);
+
+/// Cache lifetime from configuration; zero disables caching
+fn admin_cache_ttl() -> Duration {
+    Duration::from_secs(CONFIG.get::<u64>("admin_cache_ttl").unwrap_or(300))
+}
+
+/// Checks whether a user is an administrator (or owner) of a chat
+///
+/// # Arguments
+/// * `bot` - Telegram Bot instance
+/// * `chat_id` - Chat to check
+/// * `user_id` - User to look up
+///
+/// # Returns
+/// `true` if the user is an administrator; `false` on API errors
+pub async fn is_admin(bot: &Bot, chat_id: ChatId, user_id: UserId) -> bool {
+    let ttl = admin_cache_ttl();
+    if let Some(entry) = ADMIN_CACHE.get(&chat_id) {
+        if entry.0.elapsed() < ttl {
+            return entry.1.contains(&user_id);
+        }
+    }
+
+    match bot.get_chat_administrators(chat_id).await {
+        Ok(admins) => {
+            let ids: Vec<UserId> = admins.iter().map(|m| m.user.id).collect();
+            let result = ids.contains(&user_id);
+            if !ttl.is_zero() {
+                ADMIN_CACHE.insert(chat_id, (Instant::now(), ids));
+            }
+            result
+        }
+        Err(_) => false,
+    }
+}
+
+/// Checks whether a user is one of the bot owners listed in `owner_ids`
+pub fn is_owner(user_id: UserId) -> bool {
+    CONFIG
+        .get::<Vec<u64>>("owner_ids")
+        .unwrap_or_default()
+        .contains(&user_id.0)
+}
+
+/// Drops the cached administrator list of a chat
+pub fn invalidate(chat_id: ChatId) {
+    ADMIN_CACHE.remove(&chat_id);
+}
+
+/// How a banned user's messages are answered, from `banned_notice`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BannedNotice {
+    /// Ignore them without a trace
+    Silent,
+    /// Tell the user once, then ignore them
+    Once,
+}
+
+impl BannedNotice {
+    fn from_config() -> Self {
+        match CONFIG
+            .get_string("banned_notice")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "once" => Self::Once,
+            _ => Self::Silent,
+        }
+    }
+}
+
+/// (chat, user) pairs that already got the ban notice since startup
+static BAN_NOTIFIED: Lazy<DashSet<(ChatId, UserId)>> = Lazy::new(DashSet::new);
+
+/// Checks whether the sender of a group message is banned there
+///
+/// Administrators are never treated as banned. With `banned_notice = "once"`
+/// the user is told about the ban the first time.
+pub async fn is_banned_sender(bot: &Bot, msg: &Message, storage: &Arc<dyn Storage>) -> bool {
+    let Some(user) = msg.from.as_ref() else {
+        return false;
+    };
+    if msg.chat.is_private()
+        || !storage.is_banned(msg.chat.id.0, user.id.0).await
+        || is_admin(bot, msg.chat.id, user.id).await
+    {
+        return false;
+    }
+
+    debug!("Ignoring banned user {} in chat {}", user.id, msg.chat.id);
+    if BannedNotice::from_config() == BannedNotice::Once
+        && BAN_NOTIFIED.insert((msg.chat.id, user.id))
+    {
+        let notice = format!("{}, you can't use the bot in this chat.", user.full_name());
+        if let Err(e) = bot.send_message(msg.chat.id, notice).await {
+            debug!("Failed to send ban notice in chat {}: {}", msg.chat.id, e);
+        }
+    }
+    true
+}
+
+/// Lets a user who is banned again get the notice again
+pub fn forget_ban_notice(chat_id: ChatId, user_id: UserId) {
+    BAN_NOTIFIED.remove(&(chat_id, user_id));
+}
+
+/// Handles `chat_member` updates
+///
+/// Invalidates the admin cache whenever a member gains or loses admin rights.
+pub async fn chat_member_handler(update: ChatMemberUpdated) -> ResponseResult<()> {
+    if update.old_chat_member.is_privileged() != update.new_chat_member.is_privileged() {
+        debug!("Admin list changed in chat {}", update.chat.id);
+        invalidate(update.chat.id);
+    }
+    Ok(())
+}
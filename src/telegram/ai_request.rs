@@ -3,15 +3,38 @@
 //! This module handles AI requests from Telegram users, managing the complete
 //! lifecycle from request to response delivery.
 
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use teloxide::{
+    ApiError, Bot, RequestError,
+    payloads::{
+        SendChatActionSetters, SendDocumentSetters, SendMessageSetters, SetMessageReactionSetters,
+    },
     prelude::Requester,
-    types::{ChatAction, ChatId},
-    Bot, RequestError,
+    types::{
+        ChatAction, ChatId, InputFile, MessageId, ReactionType, ReplyParameters, ThreadId, UserId,
+    },
 };
+use tokio::{sync::mpsc, time::MissedTickBehavior};
 use tracing::{error, info, warn, debug};
 
-use crate::{storage::Storage, system, telegram::message::BusySet};
+use crate::{
+    CONFIG,
+    answer_format::AnswerFormat,
+    events::{self, EventKind},
+    raw_answer::{RAW_ANSWERS, RAW_FILE_NAME},
+    reply_chain::{self, REPLY_CHAINS},
+    storage::{ContextScope, Storage},
+    system::{self, RateLimitNotice},
+    telegram::{
+        busy::{Enqueued, QueuedTask},
+        limiter,
+        message::BusySet,
+        rolling::RollingMessage,
+    },
+};
 
 /// Result type for AI request handling operations
 pub type AiRequestResult<T> = Result<T, AiRequestError>;
@@ -25,6 +48,10 @@ pub enum AiRequestError {
     AiProcessingError(String),
     #[error("Chat is busy processing another request")]
     ChatBusy,
+    #[error("Bot was blocked by the user")]
+    BotBlocked,
+    #[error("Request was cancelled")]
+    Cancelled,
 }
 
 /// Handles an AI request for a specific chat with comprehensive error handling
@@ -39,10 +66,13 @@ pub enum AiRequestError {
 /// # Arguments
 /// * `bot` - Telegram Bot instance for sending messages
 /// * `chat_id` - Unique identifier for the target chat
+/// * `thread_id` - Forum topic the request came from, if any
+/// * `message_id` - Message that triggered the request
+/// * `user_id` - Sender of the request, who may `/cancel` it
 /// * `text` - User's input text to process
+/// * `scope` - Conversation the request reads and extends
 /// * `storage` - Storage interface for maintaining conversation context
 /// * `busy` - Thread-safe set tracking currently active chat requests
-/// * `is_assistant_mode` - Whether to use assistant mode for responses
 ///
 /// # Returns
 /// * `AiRequestResult<()>` - Success or detailed error information
@@ -52,82 +82,564 @@ pub enum AiRequestError {
 /// let result = handle_ai_request(
 ///     bot,
 ///     chat_id,
+///     None,
+///     msg.id,
+///     msg.from.as_ref().map(|user| user.id),
 ///     "Hello AI!".to_string(),
+///     ContextScope::Main,
 ///     storage,
 ///     busy_set,
-///     false
 /// ).await;
 /// ```
 pub async fn handle_ai_request(
     bot: Bot,
     chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    message_id: MessageId,
+    user_id: Option<UserId>,
     text: String,
+    scope: ContextScope,
     storage: Arc<dyn Storage>,
     busy: BusySet,
-    is_assistant_mode: bool,
 ) -> AiRequestResult<()> {
-    debug!("Processing AI request for chat {}: {}", chat_id, text);
+    if CONFIG.get_bool("redact_prompts_in_logs").unwrap_or(true) {
+        debug!(
+            "Processing AI request for chat {} ({} chars)",
+            chat_id,
+            text.chars().count()
+        );
+    } else {
+        debug!("Processing AI request for chat {}: {}", chat_id, text);
+    }
 
     // Ensure this chat isn't already processing a request
-    if !busy.insert(chat_id.0) {
-        warn!("Chat {} is already busy, rejecting new request", chat_id);
-        send_busy_message(&bot, chat_id).await?;
+    if !busy.try_acquire(chat_id.0, user_id) {
+        if CONFIG.get_bool("queue_when_busy").unwrap_or(false) {
+            let max_queued = CONFIG.get::<usize>("max_queued_per_chat").unwrap_or(3);
+            let task = queued_request(
+                bot.clone(),
+                chat_id,
+                thread_id,
+                message_id,
+                user_id,
+                text,
+                scope,
+                storage,
+                busy.clone(),
+            );
+
+            match busy.enqueue(chat_id.0, user_id, task, max_queued) {
+                Enqueued::Queued(position) => {
+                    info!(
+                        "Queued request for chat {} at position {}",
+                        chat_id, position
+                    );
+                    topic_message(
+                        &bot,
+                        chat_id,
+                        thread_id,
+                        format!("📥 Your request is queued (position {}).", position),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                Enqueued::Idle(task) => {
+                    // The previous request finished while we were queueing
+                    task.await;
+                    return Ok(());
+                }
+                Enqueued::Full(_) => {
+                    warn!("Queue for chat {} is full, rejecting new request", chat_id);
+                }
+            }
+        } else {
+            warn!("Chat {} is already busy, rejecting new request", chat_id);
+        }
+        match BusyMessageMode::from_config() {
+            BusyMessageMode::Reply => send_busy_message(&bot, chat_id, thread_id).await?,
+            BusyMessageMode::Reaction => react(&bot, chat_id, message_id, Reaction::Busy).await,
+            BusyMessageMode::Silent => {}
+        }
         return Err(AiRequestError::ChatBusy);
     }
 
+    run_request(
+        bot, chat_id, thread_id, message_id, user_id, text, scope, storage, busy,
+    )
+    .await
+}
+
+/// Wraps a request so it can wait in the chat's busy queue
+fn queued_request(
+    bot: Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    message_id: MessageId,
+    user_id: Option<UserId>,
+    text: String,
+    scope: ContextScope,
+    storage: Arc<dyn Storage>,
+    busy: BusySet,
+) -> QueuedTask {
+    Box::pin(async move {
+        if let Err(e) = run_request(
+            bot, chat_id, thread_id, message_id, user_id, text, scope, storage, busy,
+        )
+        .await
+        {
+            error!("Queued request for chat {} failed: {}", chat_id, e);
+        }
+    })
+}
+
+/// Processes a request for a chat that has already been marked busy
+async fn run_request(
+    bot: Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    message_id: MessageId,
+    user_id: Option<UserId>,
+    text: String,
+    scope: ContextScope,
+    storage: Arc<dyn Storage>,
+    busy: BusySet,
+) -> AiRequestResult<()> {
+    let started = Instant::now();
     // Use RAII pattern to ensure cleanup on any exit path
     let _guard = BusyGuard::new(busy.clone(), chat_id.0);
+    // A `/raw on` capture never outlives its request, whichever way it ends
+    let _raw = RawCaptureGuard { chat_id: chat_id.0 };
+    // Held until the answer is sent; waits when `max_concurrent_requests` is reached
+    let _slot = limiter::acquire_slot(chat_id).await;
 
     info!("Starting AI request processing for chat {}", chat_id);
+    set_reaction(&bot, chat_id, message_id, Reaction::Processing).await;
+
+    // A chat that writes to us again is reachable again
+    if !storage.is_chat_active(chat_id.0).await {
+        storage.set_chat_active(chat_id.0, true).await;
+    }
+
+    // With `stream_answers`, plain-text answers are shown while they are generated
+    let (deltas, stream) = if CONFIG.get_bool("stream_answers").unwrap_or(false)
+        && storage
+            .get_answer_format(chat_id.0)
+            .await
+            .parse_mode()
+            .is_none()
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+
+    // With `rate_limit_notice`, waits for the AI service's rate limit are shown
+    let (notices, notice_updates) = if CONFIG.get_bool("rate_limit_notice").unwrap_or(true) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
 
     // Start typing indicator and AI processing concurrently
-    let typing_task = send_typing_indicator(&bot, chat_id);
-    let ai_task = process_ai_request(text, chat_id.0, storage, is_assistant_mode);
+    let typing_task = send_typing_indicator(&bot, chat_id, thread_id);
+    let ai_task = process_ai_request(text, chat_id.0, scope, storage.clone(), deltas, notices);
+    let reply_to = CONFIG
+        .get_bool("reply_to_request")
+        .unwrap_or(false)
+        .then_some(message_id);
+    let stream_task = show_stream(&bot, chat_id, thread_id, reply_to, stream);
+    let notice = NoticeCell::default();
+    let notice_task = show_rate_limit_notices(&bot, chat_id, thread_id, notice_updates, &notice);
+    let cancel = busy.cancel_signal(chat_id.0);
 
-    let (typing_result, ai_result) = tokio::join!(typing_task, ai_task);
+    let (typing_result, ai_result, streamed, ()) = tokio::select! {
+        results = async { tokio::join!(typing_task, ai_task, stream_task, notice_task) } => results,
+        _ = async {
+            match &cancel {
+                Some(cancel) => cancel.notified().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            info!("Request for chat {} was cancelled by {:?}", chat_id, user_id);
+            remove_rate_limit_notice(&bot, chat_id, &notice).await;
+            clear_reaction(&bot, chat_id, message_id).await;
+            return Err(AiRequestError::Cancelled);
+        }
+    };
 
     // Log typing indicator result (non-critical)
     if let Err(e) = typing_result {
         warn!("Failed to send typing indicator for chat {}: {}", chat_id, e);
     }
 
+    // The rate limit status gives way to the answer, or to the final error
+    remove_rate_limit_notice(&bot, chat_id, &notice).await;
+
     // Handle AI processing result
-    let response_chunks = ai_result.map_err(|e| {
-        error!("AI processing failed for chat {}: {}", chat_id, e);
-        AiRequestError::AiProcessingError(e)
-    })?;
+    let response_chunks = match ai_result {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            error!("AI processing failed for chat {}: {}", chat_id, e);
+            events::notify_event(chat_id.0, EventKind::Error { message: e.clone() });
+            set_reaction(&bot, chat_id, message_id, Reaction::Failed).await;
+            return Err(AiRequestError::AiProcessingError(e));
+        }
+    };
 
     // Send response chunks to user
-    send_response_chunks(&bot, chat_id, response_chunks).await?;
+    let answer = response_chunks.join("\n");
+    let sent = match streamed {
+        // The answer is already on screen, only its final form is put in place
+        Some(mut rolling) => rolling
+            .replace_with(&response_chunks)
+            .await
+            .map_err(AiRequestError::from),
+        None => {
+            pace_answer(&bot, chat_id, thread_id, started).await;
+            send_response_chunks(
+                &bot,
+                chat_id,
+                thread_id,
+                response_chunks,
+                reply_to,
+                &storage,
+                &busy,
+            )
+            .await
+        }
+    };
+    let sent = match sent {
+        Ok(sent) => sent,
+        Err(e) => {
+            events::notify_event(
+                chat_id.0,
+                EventKind::Error {
+                    message: e.to_string(),
+                },
+            );
+            set_reaction(&bot, chat_id, message_id, Reaction::Failed).await;
+            return Err(e);
+        }
+    };
+    // In groups, replies to any message of the answer continue this exchange
+    if !chat_id.is_user() && reply_chain::max_depth() > 0 {
+        for id in sent {
+            REPLY_CHAINS.record(chat_id.0, id, Some(message_id), "assistant", &answer);
+        }
+    }
+    send_raw_answer(&bot, chat_id, thread_id).await;
 
     info!("Successfully completed AI request for chat {}", chat_id);
+    set_reaction(&bot, chat_id, message_id, Reaction::Done).await;
+    events::notify_event(chat_id.0, EventKind::RequestCompleted);
     Ok(())
 }
 
+/// Sends the unprocessed answer captured with `/raw on`, if any
+///
+/// Failures are only logged, the answer itself has already been delivered.
+async fn send_raw_answer(bot: &Bot, chat_id: ChatId, thread_id: Option<ThreadId>) {
+    let Some(raw) = RAW_ANSWERS.take(chat_id.0) else {
+        return;
+    };
+    let file = InputFile::memory(raw.into_bytes()).file_name(RAW_FILE_NAME);
+    let mut document = bot.send_document(chat_id, file);
+    if let Some(thread_id) = thread_id {
+        document = document.message_thread_id(thread_id);
+    }
+    if let Err(e) = document.await {
+        warn!("Failed to send raw answer to chat {}: {}", chat_id, e);
+    }
+}
+
+/// How a request rejected because the chat is busy is acknowledged
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BusyMessageMode {
+    /// Answer with a "please wait" message
+    Reply,
+    /// Put a reaction on the rejected message
+    Reaction,
+    /// Drop the request without a trace
+    Silent,
+}
+
+impl BusyMessageMode {
+    /// Reads `busy_message_mode` from configuration, defaulting to `reply`
+    fn from_config() -> Self {
+        match CONFIG
+            .get_string("busy_message_mode")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "reaction" => Self::Reaction,
+            "silent" => Self::Silent,
+            _ => Self::Reply,
+        }
+    }
+}
+
 /// Sends a busy message to inform the user about ongoing processing
-async fn send_busy_message(bot: &Bot, chat_id: ChatId) -> Result<(), RequestError> {
-    bot.send_message(chat_id, "⏳ Please wait, I'm still processing your previous request...")
-        .await?;
+async fn send_busy_message(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+) -> Result<(), RequestError> {
+    topic_message(
+        bot,
+        chat_id,
+        thread_id,
+        "⏳ Please wait, I'm still processing your previous request...",
+    )
+    .await?;
     Ok(())
 }
 
+/// Starts a message to the chat, in the request's forum topic if it has one
+fn topic_message(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    text: impl Into<String>,
+) -> <Bot as Requester>::SendMessage {
+    let request = bot.send_message(chat_id, text);
+    match thread_id {
+        Some(thread_id) => request.message_thread_id(thread_id),
+        None => request,
+    }
+}
+
 /// Sends typing indicator to show the bot is processing
-async fn send_typing_indicator(bot: &Bot, chat_id: ChatId) -> Result<(), RequestError> {
-    bot.send_chat_action(chat_id, ChatAction::Typing).await?;
+///
+/// In forum topics the indicator goes to the topic, not the general chat.
+async fn send_typing_indicator(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+) -> Result<(), RequestError> {
+    let mut action = bot.send_chat_action(chat_id, ChatAction::Typing);
+    if let Some(thread_id) = thread_id {
+        action = action.message_thread_id(thread_id);
+    }
+    action.await?;
     Ok(())
 }
 
+/// Status shown as a reaction on the triggering message
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reaction {
+    Processing,
+    Done,
+    Failed,
+    /// The chat was busy and the message was not answered
+    Busy,
+}
+
+impl Reaction {
+    /// Emoji from the `reaction_emojis` table; empty removes the reaction
+    ///
+    /// Telegram only accepts emoji from its reaction set, which has no
+    /// ⏳, ✅ or ❌, so the defaults are 👀, 👍 and 👎.
+    fn emoji(self) -> String {
+        let (key, default) = match self {
+            Reaction::Processing => ("processing", "👀"),
+            Reaction::Done => ("done", "👍"),
+            Reaction::Failed => ("failed", "👎"),
+            Reaction::Busy => ("busy", "👀"),
+        };
+        CONFIG
+            .get_string(&format!("reaction_emojis.{}", key))
+            .unwrap_or_else(|_| default.to_string())
+    }
+}
+
+/// Replaces the bot's reaction on a message, if `processing_reactions` is on
+async fn set_reaction(bot: &Bot, chat_id: ChatId, message_id: MessageId, reaction: Reaction) {
+    if CONFIG.get_bool("processing_reactions").unwrap_or(false) {
+        react(bot, chat_id, message_id, reaction).await;
+    }
+}
+
+/// Replaces the bot's reaction on a message
+///
+/// Best effort: failures (e.g. reactions disabled in the chat) are only logged.
+async fn react(bot: &Bot, chat_id: ChatId, message_id: MessageId, reaction: Reaction) {
+    let emoji = reaction.emoji();
+    if emoji.is_empty() {
+        clear_reaction(bot, chat_id, message_id).await;
+        return;
+    }
+    if let Err(e) = bot
+        .set_message_reaction(chat_id, message_id)
+        .reaction(vec![ReactionType::Emoji { emoji }])
+        .await
+    {
+        debug!(
+            "Failed to set {:?} reaction in chat {}: {}",
+            reaction, chat_id, e
+        );
+    }
+}
+
+/// Removes the bot's reaction from a message, best effort
+async fn clear_reaction(bot: &Bot, chat_id: ChatId, message_id: MessageId) {
+    if let Err(e) = bot.set_message_reaction(chat_id, message_id).await {
+        debug!("Failed to clear reaction in chat {}: {}", chat_id, e);
+    }
+}
+
+/// Telegram shows a chat action for about 5 seconds
+const TYPING_REFRESH: Duration = Duration::from_secs(4);
+
+/// Time still to wait so that at least `min_delay` passes before answering
+fn remaining_delay(min_delay: Duration, elapsed: Duration) -> Option<Duration> {
+    min_delay
+        .checked_sub(elapsed)
+        .filter(|delay| !delay.is_zero())
+}
+
+/// Holds the answer back until `min_answer_delay_ms` has passed since `started`
+///
+/// The typing indicator is refreshed while waiting so it doesn't lapse.
+async fn pace_answer(bot: &Bot, chat_id: ChatId, thread_id: Option<ThreadId>, started: Instant) {
+    let min_delay = Duration::from_millis(CONFIG.get::<u64>("min_answer_delay_ms").unwrap_or(0));
+    while let Some(delay) = remaining_delay(min_delay, started.elapsed()) {
+        if let Err(e) = send_typing_indicator(bot, chat_id, thread_id).await {
+            debug!(
+                "Failed to refresh typing indicator for chat {}: {}",
+                chat_id, e
+            );
+        }
+        tokio::time::sleep(delay.min(TYPING_REFRESH)).await;
+    }
+}
+
+/// Shows a streamed answer in the chat while it is generated
+///
+/// The message is edited at most every `stream_edit_interval_ms`. Failed
+/// edits are only logged: the final answer is put in place once generation
+/// ends.
+///
+/// # Returns
+/// The messages showing the answer, `None` when nothing was streamed
+async fn show_stream(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    reply_to: Option<MessageId>,
+    deltas: Option<mpsc::UnboundedReceiver<String>>,
+) -> Option<RollingMessage> {
+    let mut deltas = deltas?;
+    let interval = CONFIG.get::<u64>("stream_edit_interval_ms").unwrap_or(700);
+    let mut ticker = tokio::time::interval(Duration::from_millis(interval.max(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut rolling = RollingMessage::new(bot.clone(), chat_id, thread_id, reply_to);
+    loop {
+        let result = tokio::select! {
+            delta = deltas.recv() => match delta {
+                Some(delta) => rolling.push(&delta).await,
+                None => break,
+            },
+            _ = ticker.tick() => rolling.flush().await,
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to update streamed answer in chat {}: {}",
+                chat_id, e
+            );
+        }
+    }
+    rolling.is_started().then_some(rolling)
+}
+
+/// The rate limit status message of a request, once one was sent
+///
+/// Shared between the task showing the notices and the request, which
+/// removes the message when it ends, even when it is cancelled while the
+/// task is still waiting.
+#[derive(Default)]
+struct NoticeCell(Mutex<Option<MessageId>>);
+
+impl NoticeCell {
+    fn get(&self) -> Option<MessageId> {
+        *self.0.lock().unwrap()
+    }
+
+    fn set(&self, id: MessageId) {
+        *self.0.lock().unwrap() = Some(id);
+    }
+
+    fn take(&self) -> Option<MessageId> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Keeps one status message up to date while the AI service rate-limits the request
+///
+/// The message is sent to the request's topic and recorded in `status`.
+async fn show_rate_limit_notices(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    notices: Option<mpsc::UnboundedReceiver<RateLimitNotice>>,
+    status: &NoticeCell,
+) {
+    let Some(mut notices) = notices else {
+        return;
+    };
+    while let Some(notice) = notices.recv().await {
+        let text = notice.to_string();
+        let result = match status.get() {
+            Some(id) => bot.edit_message_text(chat_id, id, text).await.map(|_| ()),
+            None => topic_message(bot, chat_id, thread_id, text)
+                .await
+                .map(|sent| status.set(sent.id)),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to show rate limit notice in chat {}: {}",
+                chat_id, e
+            );
+        }
+    }
+}
+
+/// Deletes the rate limit status message of a request, if one was sent
+async fn remove_rate_limit_notice(bot: &Bot, chat_id: ChatId, status: &NoticeCell) {
+    let Some(id) = status.take() else {
+        return;
+    };
+    if let Err(e) = bot.delete_message(chat_id, id).await {
+        warn!(
+            "Failed to remove rate limit notice in chat {}: {}",
+            chat_id, e
+        );
+    }
+}
+
 /// Processes the AI request and returns response chunks
+///
+/// With `deltas`, the answer is requested as a stream and its pieces are
+/// sent there as they arrive. Rate limit waits are reported to `notices`.
 async fn process_ai_request(
     text: String,
     chat_id: i64,
+    scope: ContextScope,
     storage: Arc<dyn Storage>,
-    _is_assistant_mode: bool, // Parameter kept for future use
+    deltas: Option<mpsc::UnboundedSender<String>>,
+    notices: Option<mpsc::UnboundedSender<RateLimitNotice>>,
 ) -> Result<Vec<String>, String> {
     debug!("Making AI request for chat {}", chat_id);
     
     // Call the system AI function - returns Vec<String> directly
-    let chunks = system::reqwest_ai(text, chat_id, storage).await;
+    let chunks = match deltas {
+        Some(deltas) => {
+            system::reqwest_ai_stream(text, chat_id, scope, storage, deltas, notices).await
+        }
+        None => system::reqwest_ai(text, chat_id, scope, storage, notices).await,
+    };
     
     if chunks.is_empty() {
         Err("AI returned empty response".to_string())
@@ -136,70 +648,236 @@ async fn process_ai_request(
     }
 }
 
+/// Checks whether a send failed because the user blocked the bot
+fn is_bot_blocked(error: &RequestError) -> bool {
+    matches!(error, RequestError::Api(ApiError::BotBlocked))
+}
+
 /// Sends response chunks to the user with error handling
+///
+/// If the user has blocked the bot, the chat is marked inactive and its
+/// queued requests are dropped instead of reporting a send failure.
+///
+/// # Returns
+/// The messages of the answer, in order
 async fn send_response_chunks(
     bot: &Bot,
     chat_id: ChatId,
+    thread_id: Option<ThreadId>,
     chunks: Vec<String>,
-) -> AiRequestResult<()> {
+    reply_to: Option<MessageId>,
+    storage: &Arc<dyn Storage>,
+    busy: &BusySet,
+) -> AiRequestResult<Vec<MessageId>> {
     if chunks.is_empty() {
         warn!("No response chunks to send for chat {}", chat_id);
-        bot.send_message(chat_id, "❌ Sorry, I couldn't generate a response. Please try again.")
-            .await?;
-        return Ok(());
+        topic_message(
+            bot,
+            chat_id,
+            thread_id,
+            "❌ Sorry, I couldn't generate a response. Please try again.",
+        )
+        .await?;
+        return Ok(Vec::new());
     }
 
+    let format = storage.get_answer_format(chat_id.0).await;
+    let mut sent = Vec::new();
     for (index, chunk) in chunks.iter().enumerate() {
-        debug!("Sending chunk {} of {} to chat {}", index + 1, chunks.len(), chat_id);
-        
-        if let Err(e) = bot.send_message(chat_id, chunk).await {
-            error!("Failed to send chunk {} to chat {}: {}", index + 1, chat_id, e);
-            
-            // Try to send an error message
-            let _ = bot.send_message(
-                chat_id,
-                "❌ Sorry, there was an error sending the response."
-            ).await;
-            
-            return Err(AiRequestError::TelegramError(e));
+        debug!(
+            "Sending chunk {} of {} to chat {}",
+            index + 1,
+            chunks.len(),
+            chat_id
+        );
+
+        // Only the first message replies, the rest follow it
+        let reply_to = reply_to.filter(|_| index == 0);
+        match send_chunk_with_retry(bot, chat_id, thread_id, chunk, format, reply_to).await {
+            Ok(id) => sent.push(id),
+            Err(e) if is_bot_blocked(&e) => {
+                info!("Bot was blocked in chat {}, marking it inactive", chat_id);
+                storage.set_chat_active(chat_id.0, false).await;
+                busy.clear_queue(chat_id.0);
+                return Err(AiRequestError::BotBlocked);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to send chunk {} to chat {}: {}",
+                    index + 1,
+                    chat_id,
+                    e
+                );
+
+                // Try to send an error message
+                let _ = topic_message(
+                    bot,
+                    chat_id,
+                    thread_id,
+                    "❌ Sorry, there was an error sending the response.",
+                )
+                .await;
+
+                return Err(AiRequestError::TelegramError(e));
+            }
         }
     }
 
-    debug!("Successfully sent {} chunks to chat {}", chunks.len(), chat_id);
-    Ok(())
+    debug!(
+        "Successfully sent {} chunks to chat {}",
+        chunks.len(),
+        chat_id
+    );
+    Ok(sent)
+}
+
+/// Delay before retrying a send that failed with `error`
+///
+/// `None` for errors a retry can't fix, like a blocked bot or a rejected
+/// message. Flood control says how long to wait; network and I/O errors back
+/// off one second per attempt.
+fn send_retry_delay(error: &RequestError, attempt: u32) -> Option<Duration> {
+    match error {
+        RequestError::RetryAfter(seconds) => Some(seconds.duration()),
+        RequestError::Network(_) | RequestError::Io(_) => Some(Duration::from_secs(attempt as u64)),
+        _ => None,
+    }
+}
+
+/// Sends one message of an answer, retrying transient failures
+///
+/// The answer is already generated (and paid for), so a Telegram hiccup is
+/// retried up to `send_retry_attempts` times in total before it is given up.
+/// The chat stays busy meanwhile, keeping the chunks in order.
+async fn send_chunk_with_retry(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    chunk: &str,
+    format: AnswerFormat,
+    reply_to: Option<MessageId>,
+) -> Result<MessageId, RequestError> {
+    let attempts = CONFIG.get::<u32>("send_retry_attempts").unwrap_or(3).max(1);
+    let mut attempt = 1;
+    loop {
+        let error = match send_chunk(bot, chat_id, thread_id, chunk, format, reply_to).await {
+            Err(e) if attempt < attempts => e,
+            result => return result,
+        };
+        let Some(delay) = send_retry_delay(&error, attempt) else {
+            return Err(error);
+        };
+        warn!(
+            "Sending to chat {} failed (attempt {} of {}), retrying in {:?}: {}",
+            chat_id, attempt, attempts, delay, error
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Sends one message of an answer in the chat's answer format
+///
+/// Falls back to plain text when Telegram rejects the formatted message,
+/// e.g. for unbalanced Markdown from the model. With `reply_to`, the message
+/// replies to the request, or is sent on its own when that was deleted.
+async fn send_chunk(
+    bot: &Bot,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    chunk: &str,
+    format: AnswerFormat,
+    reply_to: Option<MessageId>,
+) -> Result<MessageId, RequestError> {
+    let message = |text: String| {
+        let request = topic_message(bot, chat_id, thread_id, text);
+        match reply_to {
+            Some(id) => {
+                request.reply_parameters(ReplyParameters::new(id).allow_sending_without_reply())
+            }
+            None => request,
+        }
+    };
+    let Some(parse_mode) = format.parse_mode() else {
+        return Ok(message(chunk.to_string()).await?.id);
+    };
+    match message(format.render(chunk)).parse_mode(parse_mode).await {
+        Err(e) if !is_bot_blocked(&e) => {
+            debug!(
+                "Formatted message rejected in chat {}, sending plain text: {}",
+                chat_id, e
+            );
+            Ok(message(chunk.to_string()).await?.id)
+        }
+        result => Ok(result?.id),
+    }
 }
 
 /// RAII guard to ensure busy state is cleaned up
+///
+/// Dropping the guard advances the chat's queue: the next waiting request
+/// is spawned while the chat stays busy, otherwise the chat is freed.
 struct BusyGuard {
     busy: BusySet,
     chat_id: i64,
+    /// The request this guard belongs to; `/kill` may have replaced it
+    ticket: Option<u64>,
 }
 
 impl BusyGuard {
     fn new(busy: BusySet, chat_id: i64) -> Self {
-        Self { busy, chat_id }
+        let ticket = busy.active_ticket(chat_id);
+        Self {
+            busy,
+            chat_id,
+            ticket,
+        }
     }
 }
 
 impl Drop for BusyGuard {
     fn drop(&mut self) {
         debug!("Cleaning up busy state for chat {}", self.chat_id);
-        self.busy.remove(&self.chat_id);
+        let next = match self.ticket {
+            Some(ticket) => self.busy.release_ticket(self.chat_id, ticket),
+            None => self.busy.release(self.chat_id),
+        };
+        if let Some(next) = next {
+            debug!("Starting next queued request for chat {}", self.chat_id);
+            tokio::spawn(next);
+        }
+    }
+}
+
+/// Discards what `/raw on` captured when a request ends without sending it
+///
+/// Failed and cancelled requests would otherwise leave their answers behind,
+/// to be sent with the next successful one.
+struct RawCaptureGuard {
+    chat_id: i64,
+}
+
+impl Drop for RawCaptureGuard {
+    fn drop(&mut self) {
+        if RAW_ANSWERS.take(self.chat_id).is_some() {
+            debug!("Discarded raw answer of chat {}", self.chat_id);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::telegram::busy::BusyChats;
     use std::collections::HashSet;
 
     #[test]
     fn test_busy_guard_cleanup() {
-        let busy = Arc::new(dashmap::DashSet::new());
+        let busy: BusySet = Arc::new(BusyChats::default());
         let chat_id = 12345i64;
-        
-        // Insert and create guard
-        busy.insert(chat_id);
+
+        // Acquire and create guard
+        assert!(busy.try_acquire(chat_id, None));
         {
             let _guard = BusyGuard::new(busy.clone(), chat_id);
             assert!(busy.contains(&chat_id));
@@ -209,6 +887,38 @@ mod tests {
         assert!(!busy.contains(&chat_id));
     }
 
+    #[test]
+    fn test_raw_capture_guard_discards_unsent_answers() {
+        let chat_id = 12346i64;
+        RAW_ANSWERS.arm(chat_id, true);
+        {
+            let _raw = RawCaptureGuard { chat_id };
+            assert!(RAW_ANSWERS.start(chat_id));
+            RAW_ANSWERS.capture(chat_id, "<think>hm</think>Answer");
+        } // The request failed before sending it
+        assert_eq!(RAW_ANSWERS.take(chat_id), None);
+    }
+
+    #[tokio::test]
+    async fn test_handle_ai_request_takes_the_call_site_arguments() {
+        // The shape command.rs and message.rs call it with; the future is
+        // only built, never polled, so nothing is sent
+        let busy: BusySet = Arc::new(BusyChats::default());
+        let request = handle_ai_request(
+            Bot::new("0:test"),
+            ChatId(1),
+            None,
+            MessageId(7),
+            Some(UserId(2)),
+            "Hello AI!".to_string(),
+            ContextScope::Main,
+            crate::storage::memory_storage(),
+            busy.clone(),
+        );
+        drop(request);
+        assert!(!busy.contains(&1));
+    }
+
     #[test]
     fn test_ai_request_error_display() {
         let error = AiRequestError::ChatBusy;
@@ -217,4 +927,161 @@ mod tests {
         let error = AiRequestError::AiProcessingError("Test error".to_string());
         assert_eq!(error.to_string(), "AI processing error: Test error");
     }
+
+    #[test]
+    fn test_remaining_delay() {
+        let min = Duration::from_millis(1500);
+        assert_eq!(
+            remaining_delay(min, Duration::from_millis(500)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(remaining_delay(min, min), None);
+        assert_eq!(remaining_delay(min, Duration::from_secs(3)), None);
+        assert_eq!(remaining_delay(Duration::ZERO, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_only_transient_send_errors_are_retried() {
+        let flood = RequestError::RetryAfter(teloxide::types::Seconds::from_seconds(5));
+        assert_eq!(send_retry_delay(&flood, 1), Some(Duration::from_secs(5)));
+        let io = RequestError::Io(Arc::new(std::io::Error::other("reset")));
+        assert_eq!(send_retry_delay(&io, 2), Some(Duration::from_secs(2)));
+        assert_eq!(
+            send_retry_delay(&RequestError::Api(ApiError::BotBlocked), 1),
+            None
+        );
+        assert_eq!(
+            send_retry_delay(&RequestError::Api(ApiError::MessageTextIsEmpty), 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bot_blocked_error_detection() {
+        assert!(is_bot_blocked(&RequestError::Api(ApiError::BotBlocked)));
+        assert!(!is_bot_blocked(&RequestError::Api(ApiError::MessageNotModified)));
+        assert!(!is_bot_blocked(&RequestError::RetryAfter(
+            teloxide::types::Seconds::from_seconds(5)
+        )));
+    }
+
+    #[test]
+    fn test_topic_message_goes_to_the_thread() {
+        use teloxide::requests::HasPayload;
+
+        let bot = Bot::new("0:test");
+        let thread_id = ThreadId(MessageId(7));
+        let request = topic_message(&bot, ChatId(1), Some(thread_id), "answer");
+        assert_eq!(request.payload_ref().message_thread_id, Some(thread_id));
+        let request = topic_message(&bot, ChatId(1), None, "answer");
+        assert_eq!(request.payload_ref().message_thread_id, None);
+    }
+
+    /// Answers Bot API calls like Telegram would, one per connection
+    ///
+    /// # Returns
+    /// A bot talking to the server and the API methods it was called with
+    async fn mock_telegram() -> (Bot, Arc<Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        const MESSAGE: &str = r#"{"ok":true,"result":{"message_id":5,"date":0,"chat":{"id":1,"type":"private","first_name":"A"},"text":"notice"}}"#;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let methods = calls.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                // Read the whole request before answering, or the client sees a reset
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let read = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                    let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                        if read == 0 {
+                            break;
+                        }
+                        continue;
+                    };
+                    let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .and_then(|value| value.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if read == 0 || request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+                // "POST /bot<token>/SendMessage HTTP/1.1"
+                let head = String::from_utf8_lossy(&request).to_lowercase();
+                let method = head
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|path| path.rsplit('/').next())
+                    .unwrap_or_default()
+                    .to_string();
+                let body = match method.as_str() {
+                    "sendmessage" | "editmessagetext" => MESSAGE,
+                    _ => r#"{"ok":true,"result":true}"#,
+                };
+                methods.lock().unwrap().push(method);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let bot = Bot::new("0:test").set_api_url(url.parse().unwrap());
+        (bot, calls)
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_notice_is_sent_edited_and_deleted() {
+        let (bot, calls) = mock_telegram().await;
+        let status = NoticeCell::default();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender
+            .send(RateLimitNotice::Waiting(Duration::from_secs(3)))
+            .unwrap();
+        sender.send(RateLimitNotice::Resumed).unwrap();
+        drop(sender);
+
+        show_rate_limit_notices(&bot, ChatId(1), None, Some(receiver), &status).await;
+        assert_eq!(status.get(), Some(MessageId(5)));
+        remove_rate_limit_notice(&bot, ChatId(1), &status).await;
+        assert_eq!(status.get(), None);
+        // Nothing is left to delete a second time
+        remove_rate_limit_notice(&bot, ChatId(1), &status).await;
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["sendmessage", "editmessagetext", "deletemessage"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_notice_is_deleted_while_still_shown() {
+        // A cancelled request removes the notice while its task still waits
+        let (bot, calls) = mock_telegram().await;
+        let status = NoticeCell::default();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender
+            .send(RateLimitNotice::Waiting(Duration::from_secs(3)))
+            .unwrap();
+        let shown = show_rate_limit_notices(&bot, ChatId(1), None, Some(receiver), &status);
+        tokio::select! {
+            _ = shown => unreachable!("the sender is still open"),
+            _ = async {
+                while status.get().is_none() {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            } => {}
+        }
+        remove_rate_limit_notice(&bot, ChatId(1), &status).await;
+        assert_eq!(*calls.lock().unwrap(), ["sendmessage", "deletemessage"]);
+        drop(sender);
+    }
 }
@@ -0,0 +1,397 @@
+//! Busy Tracking Module
+//!
+//! Tracks which chats have an AI request in flight and holds the bounded
+//! per-chat FIFO of requests waiting for the current one to finish. Each
+//! request remembers the user who sent it, so `/cancel` can only drop the
+//! caller's own requests. Active requests also remember when they started,
+//! so owners can spot stuck ones with `/active` and free them with `/kill`.
+
+use dashmap::{DashMap, mapref::entry::Entry};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use teloxide::types::UserId;
+use tokio::sync::Notify;
+
+/// A request waiting for its chat to become free
+pub type QueuedTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Outcome of trying to queue a request behind the active one
+pub enum Enqueued {
+    /// The task was queued at the given position (1-based)
+    Queued(usize),
+    /// The queue is full, the task is handed back
+    Full(QueuedTask),
+    /// The chat became free in the meantime; it is now marked busy
+    /// and the caller should run the task itself
+    Idle(QueuedTask),
+}
+
+/// What `cancel` did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cancelled {
+    /// The user's latest queued request was removed
+    Queued,
+    /// The user's running request was told to stop
+    Active,
+    /// The user has no request in the chat
+    Nothing,
+}
+
+/// Request that currently holds a chat
+struct Active {
+    owner: Option<UserId>,
+    /// Notified to stop the request
+    cancel: Arc<Notify>,
+    started: Instant,
+    /// Tells this request apart from later ones of the same chat
+    ticket: u64,
+}
+
+impl Active {
+    fn new(owner: Option<UserId>, ticket: u64) -> Self {
+        Self {
+            owner,
+            cancel: Arc::new(Notify::new()),
+            started: Instant::now(),
+            ticket,
+        }
+    }
+}
+
+/// The running request of a chat and the ones waiting behind it
+struct ChatRequests {
+    active: Active,
+    queue: VecDeque<(Option<UserId>, QueuedTask)>,
+}
+
+impl ChatRequests {
+    fn new(owner: Option<UserId>, ticket: u64) -> Self {
+        Self {
+            active: Active::new(owner, ticket),
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+/// A chat's running request, as listed by `/active`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveRequest {
+    pub chat_id: i64,
+    pub owner: Option<UserId>,
+    /// Time since the request started running
+    pub elapsed: Duration,
+    /// Requests waiting behind it
+    pub queued: usize,
+}
+
+/// Busy chats and their pending requests
+///
+/// A chat is busy while it has an entry in the map. The entry holds the
+/// queue of requests that will run, in order, once the active one ends.
+#[derive(Default)]
+pub struct BusyChats {
+    chats: DashMap<i64, ChatRequests>,
+    next_ticket: AtomicU64,
+}
+
+impl BusyChats {
+    fn ticket(&self) -> u64 {
+        self.next_ticket.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Marks a chat as busy with a request of `owner`
+    ///
+    /// # Returns
+    /// `false` if the chat already has a request in flight
+    pub fn try_acquire(&self, chat_id: i64, owner: Option<UserId>) -> bool {
+        match self.chats.entry(chat_id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(ChatRequests::new(owner, self.ticket()));
+                true
+            }
+        }
+    }
+
+    /// Queues a task behind the chat's active request
+    ///
+    /// # Arguments
+    /// * `chat_id` - Chat the task belongs to
+    /// * `owner` - User who sent the request
+    /// * `task` - Request to run once the chat is free
+    /// * `max_queued` - Maximum number of waiting requests per chat
+    pub fn enqueue(
+        &self,
+        chat_id: i64,
+        owner: Option<UserId>,
+        task: QueuedTask,
+        max_queued: usize,
+    ) -> Enqueued {
+        match self.chats.entry(chat_id) {
+            Entry::Occupied(mut entry) => {
+                let queue = &mut entry.get_mut().queue;
+                if queue.len() >= max_queued {
+                    return Enqueued::Full(task);
+                }
+                queue.push_back((owner, task));
+                Enqueued::Queued(queue.len())
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(ChatRequests::new(owner, self.ticket()));
+                Enqueued::Idle(task)
+            }
+        }
+    }
+
+    /// Ends the active request of a chat
+    ///
+    /// # Returns
+    /// The next queued task, in which case the chat stays busy and the
+    /// task must be run; `None` once the chat is free
+    pub fn release(&self, chat_id: i64) -> Option<QueuedTask> {
+        self.release_if(chat_id, |_| true)
+    }
+
+    /// Like `release`, but only while `ticket` is still the chat's active request
+    ///
+    /// A request freed with `kill` may end long after; by then the chat can
+    /// be busy with another request, which must keep running.
+    pub fn release_ticket(&self, chat_id: i64, ticket: u64) -> Option<QueuedTask> {
+        self.release_if(chat_id, |active| active.ticket == ticket)
+    }
+
+    fn release_if(&self, chat_id: i64, is_current: impl Fn(&Active) -> bool) -> Option<QueuedTask> {
+        if let Entry::Occupied(mut entry) = self.chats.entry(chat_id) {
+            let requests = entry.get_mut();
+            if !is_current(&requests.active) {
+                return None;
+            }
+            if let Some((owner, next)) = requests.queue.pop_front() {
+                requests.active = Active::new(owner, self.ticket());
+                return Some(next);
+            }
+            entry.remove();
+        }
+        None
+    }
+
+    /// Ticket of the chat's active request, for `release_ticket`
+    pub fn active_ticket(&self, chat_id: i64) -> Option<u64> {
+        self.chats
+            .get(&chat_id)
+            .map(|requests| requests.active.ticket)
+    }
+
+    /// Running requests of all busy chats, the longest-running first
+    pub fn active_requests(&self) -> Vec<ActiveRequest> {
+        let mut active: Vec<ActiveRequest> = self
+            .chats
+            .iter()
+            .map(|entry| ActiveRequest {
+                chat_id: *entry.key(),
+                owner: entry.active.owner,
+                elapsed: entry.active.started.elapsed(),
+                queued: entry.queue.len(),
+            })
+            .collect();
+        active.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+        active
+    }
+
+    /// Frees a chat, whatever its active request is doing
+    ///
+    /// The active request is told to stop and the queued ones are dropped.
+    /// The chat takes new requests right away, even if the old one never
+    /// reacts to the cancellation.
+    ///
+    /// # Returns
+    /// Number of dropped queued requests, `None` if the chat wasn't busy
+    pub fn kill(&self, chat_id: i64) -> Option<usize> {
+        let (_, requests) = self.chats.remove(&chat_id)?;
+        requests.active.cancel.notify_one();
+        Some(requests.queue.len())
+    }
+
+    /// Drops every request waiting in a chat's queue
+    ///
+    /// The active request is unaffected and still releases the chat when done.
+    ///
+    /// # Returns
+    /// Number of dropped requests
+    pub fn clear_queue(&self, chat_id: i64) -> usize {
+        self.chats
+            .get_mut(&chat_id)
+            .map(|mut requests| {
+                let dropped = requests.queue.len();
+                requests.queue.clear();
+                dropped
+            })
+            .unwrap_or(0)
+    }
+
+    /// Cancels a request of the user in the chat
+    ///
+    /// The user's most recently queued request is removed first; otherwise
+    /// the active request is stopped if the user sent it.
+    pub fn cancel(&self, chat_id: i64, user_id: UserId) -> Cancelled {
+        let Some(mut requests) = self.chats.get_mut(&chat_id) else {
+            return Cancelled::Nothing;
+        };
+        if let Some(index) = requests
+            .queue
+            .iter()
+            .rposition(|(owner, _)| *owner == Some(user_id))
+        {
+            requests.queue.remove(index);
+            return Cancelled::Queued;
+        }
+        if requests.active.owner == Some(user_id) {
+            // Stored as a permit if the request isn't waiting on it yet
+            requests.active.cancel.notify_one();
+            return Cancelled::Active;
+        }
+        Cancelled::Nothing
+    }
+
+    /// Signal that stops the chat's active request when notified
+    pub fn cancel_signal(&self, chat_id: i64) -> Option<Arc<Notify>> {
+        self.chats
+            .get(&chat_id)
+            .map(|requests| requests.active.cancel.clone())
+    }
+
+    /// Checks whether a chat has a request in flight
+    pub fn contains(&self, chat_id: &i64) -> bool {
+        self.chats.contains_key(chat_id)
+    }
+
+    /// Number of requests waiting in a chat's queue
+    pub fn queued(&self, chat_id: i64) -> usize {
+        self.chats
+            .get(&chat_id)
+            .map(|requests| requests.queue.len())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop() -> QueuedTask {
+        Box::pin(async {})
+    }
+
+    #[test]
+    fn test_release_advances_queue_then_frees_chat() {
+        let busy = BusyChats::default();
+        assert!(busy.try_acquire(1, None));
+        assert!(!busy.try_acquire(1, None));
+
+        assert!(matches!(
+            busy.enqueue(1, None, noop(), 2),
+            Enqueued::Queued(1)
+        ));
+        assert!(matches!(
+            busy.enqueue(1, None, noop(), 2),
+            Enqueued::Queued(2)
+        ));
+        assert!(matches!(
+            busy.enqueue(1, None, noop(), 2),
+            Enqueued::Full(_)
+        ));
+
+        assert!(busy.release(1).is_some());
+        assert!(busy.contains(&1));
+        assert!(busy.release(1).is_some());
+        assert!(busy.contains(&1));
+        assert!(busy.release(1).is_none());
+        assert!(!busy.contains(&1));
+    }
+
+    #[test]
+    fn test_enqueue_on_idle_chat_acquires_it() {
+        let busy = BusyChats::default();
+        assert!(matches!(
+            busy.enqueue(7, None, noop(), 1),
+            Enqueued::Idle(_)
+        ));
+        assert!(busy.contains(&7));
+        assert_eq!(busy.queued(7), 0);
+    }
+
+    #[test]
+    fn test_cancel_prefers_own_queued_request() {
+        let (ann, bob) = (UserId(1), UserId(2));
+        let busy = BusyChats::default();
+        assert!(busy.try_acquire(1, Some(ann)));
+        assert!(matches!(
+            busy.enqueue(1, Some(bob), noop(), 3),
+            Enqueued::Queued(1)
+        ));
+        assert!(matches!(
+            busy.enqueue(1, Some(ann), noop(), 3),
+            Enqueued::Queued(2)
+        ));
+
+        assert_eq!(busy.cancel(1, ann), Cancelled::Queued);
+        assert_eq!(busy.queued(1), 1);
+        assert_eq!(busy.cancel(1, ann), Cancelled::Active);
+        assert_eq!(busy.cancel(1, UserId(3)), Cancelled::Nothing);
+        assert_eq!(busy.cancel(2, ann), Cancelled::Nothing);
+
+        // Bob's request becomes the active one with a fresh signal
+        assert!(busy.release(1).is_some());
+        assert_eq!(busy.cancel(1, ann), Cancelled::Nothing);
+        assert_eq!(busy.cancel(1, bob), Cancelled::Active);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_signal_reaches_the_active_request() {
+        let busy = BusyChats::default();
+        assert!(busy.try_acquire(1, Some(UserId(1))));
+        let signal = busy.cancel_signal(1).unwrap();
+        // Cancelled before the request starts waiting
+        assert_eq!(busy.cancel(1, UserId(1)), Cancelled::Active);
+        tokio::time::timeout(std::time::Duration::from_secs(1), signal.notified())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_killed_request_does_not_release_its_successor() {
+        let busy = BusyChats::default();
+        assert!(busy.try_acquire(1, None));
+        let stuck = busy.active_ticket(1).unwrap();
+        let signal = busy.cancel_signal(1).unwrap();
+        assert!(matches!(
+            busy.enqueue(1, None, noop(), 2),
+            Enqueued::Queued(1)
+        ));
+        assert_eq!(busy.active_requests().len(), 1);
+        assert_eq!(busy.active_requests()[0].queued, 1);
+
+        assert_eq!(busy.kill(1), Some(1));
+        assert!(!busy.contains(&1));
+        assert_eq!(busy.kill(1), None);
+        tokio::time::timeout(std::time::Duration::from_secs(1), signal.notified())
+            .await
+            .unwrap();
+
+        // A new request takes the chat; the stuck one ending later leaves it alone
+        assert!(busy.try_acquire(1, None));
+        assert!(busy.release_ticket(1, stuck).is_none());
+        assert!(busy.contains(&1));
+        let current = busy.active_ticket(1).unwrap();
+        assert!(busy.release_ticket(1, current).is_none());
+        assert!(!busy.contains(&1));
+    }
+}
@@ -1,10 +1,35 @@
-use crate::storage::Note;
+use crate::CONFIG;
+use crate::answer_cache;
+use crate::answer_format::AnswerFormat;
+use crate::answer_schema;
+use crate::events::{self, EventKind};
+use crate::raw_answer::RAW_ANSWERS;
+use crate::storage::{
+    ClearTarget, ContextScope, Note, NoteFilter, default_temperature, parse_notes_json,
+    validate_max_tokens,
+};
+use crate::summary;
+use crate::system::{self, Brevity, Transform};
 use crate::{
-    storage::Storage, telegram::ai_request::handle_ai_request, telegram::message::BusySet,
+    logging,
+    storage::Storage,
+    telegram::admin::{forget_ban_notice, is_admin, is_banned_sender, is_owner},
+    telegram::ai_request::{AiRequestError, handle_ai_request},
+    telegram::busy::{ActiveRequest, Cancelled},
+    telegram::files,
+    telegram::limiter::{self, Priority},
+    telegram::message::{BusySet, is_stale_update, topic_thread},
+    telegram::quota,
+    telegram::settings,
 };
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use teloxide::utils::command::BotCommands;
-use teloxide::{Bot, prelude::*, types::Message};
+use teloxide::{
+    Bot,
+    prelude::*,
+    types::{BotCommandScope, ChatId, InputFile, Message},
+};
 use tracing::{Level, error, event};
 
 #[derive(BotCommands, Clone, Debug)]
@@ -21,6 +46,12 @@ pub enum UserCommands {
     Chat,
     #[command(description = "try to watch inyour future.")]
     Future,
+    #[command(description = "show bot version and deployment info.")]
+    About,
+    #[command(description = "show your remaining requests and when you can ask next.")]
+    Limits,
+    #[command(description = "cancel your queued or running request.")]
+    Cancel,
 }
 
 /// Bot commands enumeration
@@ -44,19 +75,91 @@ pub enum Command {
     #[command(description = "place your promt after this command. It will be sent to the model.")]
     Chat(String),
     // Clears conversation history
-    #[command(description = "clears conversation context.")]
-    Clear,
+    #[command(
+        description = "clears conversation context; /clear chat or /clear all for the /chat conversation."
+    )]
+    Clear(String),
+    // Keeps the latest message when the history is trimmed
+    #[command(description = "keep your latest message in context however long the chat gets.")]
+    Pin,
+    // Lets the pinned message be trimmed again
+    #[command(description = "release the pinned message.")]
+    Unpin,
+    // Ends the conversation with a farewell and a fresh context
+    #[command(description = "say goodbye and start over next time.")]
+    Bye,
+    #[command(description = "start a new named conversation (private chats).")]
+    NewChat(String),
+    #[command(description = "switch to another named conversation, main is the default one.")]
+    SwitchChat(String),
+    #[command(description = "delete a named conversation and its history.")]
+    DeleteChat(String),
+    #[command(description = "list your conversations.")]
+    Chats,
     // Sets system fingerprint for the model
     #[command(description = "set system fingerprint..")]
     System(String),
+    // Switches to a saved persona
+    #[command(description = "activate a saved persona by name.")]
+    Persona(String),
+    // Saves the current system fingerprint as a persona
+    #[command(description = "save current system fingerprint as a named persona.")]
+    SavePersona(String),
+    // Lists saved personas
+    #[command(description = "list saved personas.")]
+    Personas,
     // Sets temperature for the model
-    #[command(description = "set temperature for model. Choose from 0.0 to 1.0. Default is 0.7.")]
+    #[command(
+        description = "set temperature for model. Choose from 0.0 to 1.0. Default is 0.7 or the model's own."
+    )]
     Temperature(f32),
-    // // Stops current operation
-    // #[command(description = "stops current operation.")]
-    // Stop,
+    #[command(description = "adjust the temperature with buttons.")]
+    Settings,
+    #[command(description = "show the model settings of this chat and where each comes from.")]
+    ModelInfo,
+    // Switches this chat to another model
+    #[command(
+        description = "switch this chat's model: /model <name> or default; without argument shows it."
+    )]
+    Model(String),
+    // Shows or hides the model's <think> blocks in this chat
+    #[command(description = "show or hide model reasoning: on or off.")]
+    Thinking(String),
+    // Sets the preferred answer length
+    #[command(description = "set answer length: short, normal or detailed.")]
+    Brevity(String),
+    #[command(
+        description = "answer format: chat, markdown, code or json; without argument shows the current one."
+    )]
+    Format(String),
+    #[command(
+        description = "JSON schema answers in the json format must match: /schema <schema>, /schema off; without argument shows it."
+    )]
+    Schema(String),
+    #[command(
+        description = "start a fresh conversation after N idle minutes: /autoclear <minutes>, off or default; without argument shows it."
+    )]
+    AutoClear(String),
+    #[command(
+        description = "longest answer in tokens: /maxtokens <tokens> or default; without argument shows it."
+    )]
+    MaxTokens(String),
     #[command(description = "try to watch inyour future.")]
     Future,
+    #[command(description = "translate text (or the replied message): /translate <lang> [text].")]
+    Translate(String),
+    #[command(
+        description = "compare two models: /diff model1|model2 <prompt>, or /diff <prompt> against comparison_model."
+    )]
+    Diff(String),
+    #[command(description = "explain the replied message or text like I'm five.")]
+    Eli5(String),
+    #[command(description = "summarize the replied message or text in a few sentences.")]
+    Tldr(String),
+    #[command(description = "rewrite the replied message or text formally.")]
+    Formal(String),
+    #[command(description = "expand the replied message or text with more detail.")]
+    Expand(String),
     #[command(description = "add note.")]
     AddNote(String),
     #[command(description = "remove note.")]
@@ -65,16 +168,251 @@ pub enum Command {
     ListNotes,
     #[command(description = "erase all notes.")]
     EraseNotes,
+    #[command(
+        description = "notes mode: inject (sent to the model) or store (kept for people only)."
+    )]
+    NotesMode(String),
+    #[command(description = "update the conversation summary note now.")]
+    SummaryPin,
+    #[command(description = "send all notes as a JSON document.")]
+    ExportNotes,
+    #[command(description = "send the conversation as an OpenAI fine-tuning JSONL document.")]
+    ExportJsonl,
+    #[command(description = "show the request log of this chat: /chatlog [page].")]
+    ChatLog(String),
+    #[command(
+        description = "reply to an exported JSON document: merge (default) or replace notes."
+    )]
+    ImportNotes(String),
+    #[command(
+        description = "remove notes matching user:<id> after:<YYYY-MM-DD> before:<YYYY-MM-DD>."
+    )]
+    ClearNotes(String),
+    #[command(description = "show bot version and deployment info.")]
+    About,
+    #[command(description = "show your remaining requests and when you can ask next.")]
+    Limits,
+    #[command(description = "cancel your queued or running request.")]
+    Cancel,
+    #[command(description = "estimate how much of the model's context window this chat uses.")]
+    ContextSize,
+    #[command(
+        description = "ask the model which model it is and compare with the server's answer."
+    )]
+    WhoAmI,
+    #[command(description = "owner only: send the last N lines of today's log.")]
+    Logs(usize),
+    #[command(description = "owner only: refresh the command menu shown to users.")]
+    SyncCommands,
+    #[command(
+        description = "owner only: list, set (Name: value) or remove (Name) request headers for this chat."
+    )]
+    Header(String),
+    #[command(
+        description = "owner only: bill this chat to its own API key (/apikey <key>), back to the global one (/apikey off), or show which is used."
+    )]
+    ApiKey(String),
+    #[command(
+        description = "owner only: /raw on also sends the next answer of this chat unprocessed, as a document; /raw off cancels."
+    )]
+    Raw(String),
+    #[command(description = "owner only: list chats with a request in progress and for how long.")]
+    Active,
+    #[command(
+        description = "owner only: free a stuck chat, cancelling its request and dropping its queue: /kill <chat id>."
+    )]
+    Kill(String),
+    #[command(
+        description = "owner only: show, set (<token id>:<bias> ...) or clear the logit bias of this chat."
+    )]
+    LogitBias(String),
+    #[command(description = "groups: stop answering a user (id, or reply to their message).")]
+    Ban(String),
+    #[command(description = "groups: answer a banned user again (id, or reply to their message).")]
+    Unban(String),
+    #[command(description = "groups: list banned users.")]
+    Banned,
     #[command(description = "enable bot for this chat.")]
     Enable,
     #[command(description = "disable bot for this chat.")]
     Disable,
+    #[command(
+        description = "forums: on to answer in new topics of an enabled chat, off to require /enable per topic."
+    )]
+    AutoThreads(String),
+}
+
+/// Upper bound for `/logs` so a single reply stays reasonably small
+const MAX_LOG_LINES: usize = 1000;
+
+/// Moment the bot was started, injected as a dispatcher dependency
+#[derive(Clone, Copy, Debug)]
+pub struct StartedAt(pub Instant);
+
+/// Publishes the command menus shown in Telegram's autocomplete
+///
+/// Private chats and group admins get the full `Command` list, other group
+/// members only `UserCommands`.
+pub async fn sync_commands(bot: &Bot) -> ResponseResult<()> {
+    let scopes = [
+        (BotCommandScope::AllPrivateChats, Command::bot_commands()),
+        (
+            BotCommandScope::AllChatAdministrators,
+            Command::bot_commands(),
+        ),
+        (BotCommandScope::AllGroupChats, UserCommands::bot_commands()),
+    ];
+    for (scope, commands) in scopes {
+        let count = commands.len();
+        match bot.set_my_commands(commands).scope(scope.clone()).await {
+            Ok(_) => event!(Level::INFO, "Set {} commands for {:?}", count, scope),
+            Err(e) => {
+                error!("Failed to set commands for {:?}: {}", scope, e);
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Formats a duration as e.g. `2d 3h 4m 5s`, omitting leading zero units
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, minutes, seconds) = (
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+    if days > 0 {
+        format!("{}d {}h {}m {}s", days, hours, minutes, seconds)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Text of `/active`: one line per busy chat, the longest-running first
+fn format_active(requests: &[ActiveRequest]) -> String {
+    if requests.is_empty() {
+        return "No requests in progress".to_string();
+    }
+    let lines: Vec<String> = requests
+        .iter()
+        .map(|request| {
+            let mut line = format!("{} · {}", request.chat_id, format_uptime(request.elapsed));
+            if let Some(owner) = request.owner {
+                line.push_str(&format!(" · user {}", owner));
+            }
+            if request.queued > 0 {
+                line.push_str(&format!(" · {} queued", request.queued));
+            }
+            line
+        })
+        .collect();
+    format!("Requests in progress:\n{}", lines.join("\n"))
+}
+
+/// Sends a message that deletes itself after `ttl`
+pub async fn send_ephemeral(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: impl Into<String>,
+    ttl: Duration,
+) -> ResponseResult<()> {
+    let sent = bot.send_message(chat_id, text).await?;
+    let bot = bot.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(ttl).await;
+        let _ = bot.delete_message(chat_id, sent.id).await;
+    });
+    Ok(())
+}
+
+/// Confirms a group command whose message was deleted
+///
+/// Does nothing unless `confirm_silent_commands` is on; the confirmation
+/// disappears after `confirm_silent_commands_ttl` seconds.
+async fn confirm_silent(bot: &Bot, chat_id: ChatId, text: &str) -> ResponseResult<()> {
+    if !CONFIG.get_bool("confirm_silent_commands").unwrap_or(false) {
+        return Ok(());
+    }
+    let ttl = CONFIG
+        .get::<u64>("confirm_silent_commands_ttl")
+        .unwrap_or(5);
+    send_ephemeral(bot, chat_id, text, Duration::from_secs(ttl)).await
+}
+
+/// Counts a model request of the command's sender, as `/chat` does
+///
+/// # Returns
+/// Whether the request may go ahead; otherwise the sender was told why not
+async fn acquire_quota(bot: &Bot, msg: &Message) -> ResponseResult<bool> {
+    if let Some(user) = &msg.from {
+        if let Err(blocked) = quota::try_acquire(user.id, msg.chat.id) {
+            bot.send_message(msg.chat.id, blocked.to_string()).await?;
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Answers a transform command for its text, or the replied message's
+async fn run_transform(
+    bot: &Bot,
+    msg: &Message,
+    kind: Transform,
+    text: &str,
+    storage: &Arc<dyn Storage>,
+) -> ResponseResult<()> {
+    let text = match text.trim() {
+        "" => msg
+            .reply_to_message()
+            .and_then(|reply| reply.text().or(reply.caption()))
+            .unwrap_or_default(),
+        text => text,
+    };
+    if text.is_empty() {
+        let usage = format!(
+            "Usage: /{0} <text>, or reply to a message with /{0}",
+            kind.name()
+        );
+        bot.send_message(msg.chat.id, usage).await?;
+        return Ok(());
+    }
+    if !acquire_quota(bot, msg).await? {
+        return Ok(());
+    }
+
+    let reply = system::transform(msg.chat.id.0, storage, kind, text)
+        .await
+        .unwrap_or_else(|e| e);
+    for chunk in system::split_into_chunks(&reply, None) {
+        bot.send_message(msg.chat.id, chunk).await?;
+    }
+    Ok(())
+}
+
+/// User a `/ban` or `/unban` is about: the id argument, else the author of the replied message
+fn ban_target(msg: &Message, arg: &str) -> Option<UserId> {
+    let arg = arg.trim();
+    if !arg.is_empty() {
+        return arg.parse().ok().map(UserId);
+    }
+    msg.reply_to_message()
+        .and_then(|reply| reply.from.as_ref())
+        .map(|user| user.id)
 }
 
-async fn is_admin(bot: &Bot, chat_id: ChatId, user_id: UserId) -> bool {
-    match bot.get_chat_administrators(chat_id).await {
-        Ok(admins) => admins.iter().any(|m| m.user.id == user_id),
-        Err(_) => false,
+/// Empties the conversations of a chat that `/clear` targets
+async fn clear_scopes(chat_id: i64, target: ClearTarget, storage: &Arc<dyn Storage>) {
+    for scope in target.scopes() {
+        storage
+            .clear_conversation_context(storage.scoped_context_key(chat_id, *scope).await)
+            .await;
     }
 }
 
@@ -86,7 +424,9 @@ async fn is_admin(bot: &Bot, chat_id: ChatId, user_id: UserId) -> bool {
 /// * `bot` - Telegram Bot instance
 /// * `msg` - Incoming message containing the command
 /// * `command` - Parsed command enum
-/// * `senders` - Thread-safe set of chat IDs who await for the answer
+/// * `busy` - Chats with a request in progress, and their queues
+/// * `storage` - Storage implementation holding the chat settings
+/// * `started_at` - Start time of the bot, for `/about`
 ///
 /// # Returns
 /// * `ResponseResult<()>` - Result of the command execution
@@ -96,11 +436,31 @@ pub async fn command_handler(
     command: Command,
     busy: BusySet,
     storage: Arc<dyn Storage>,
+    started_at: StartedAt,
 ) -> ResponseResult<()> {
+    if is_stale_update(&msg) || is_banned_sender(&bot, &msg, &storage).await {
+        return Ok(());
+    }
+
     match command {
         Command::Start => {
             bot.send_message(msg.chat.id, "Welcome to AI Telegram Bot!")
                 .await?;
+
+            // Personal greeting from notes, only on the chat's first /start
+            if CONFIG.get_bool("warm_start").unwrap_or(false)
+                && storage.mark_started(msg.chat.id.0).await
+            {
+                match system::warm_greeting(msg.chat.id.0, storage.clone()).await {
+                    Ok(greeting) if !greeting.is_empty() => {
+                        bot.send_message(msg.chat.id, greeting).await?;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        event!(Level::WARN, "Warm start failed for {}: {}", msg.chat.id, e);
+                    }
+                }
+            }
         }
         Command::Help => {
             if let Some(user) = msg.from {
@@ -119,34 +479,63 @@ pub async fn command_handler(
             }
         }
         Command::Chat(text) => {
+            if let Some(user) = &msg.from {
+                if let Err(blocked) = quota::try_acquire(user.id, msg.chat.id) {
+                    bot.send_message(msg.chat.id, blocked.to_string()).await?;
+                    return Ok(());
+                }
+            }
             let message_id = msg.id;
+            let user_id = msg.from.as_ref().map(|user| user.id);
             let chat_id = msg.chat.id;
             let thread_id = msg.thread_id;
+            let thread = topic_thread(&msg);
+            let scope = ContextScope::for_command();
             let bot_clone = bot.clone();
             let storage_clone = storage.clone();
             let busy_clone = busy.clone();
 
-            if !msg.chat.is_private() && storage.is_enabled(chat_id.0, thread_id, msg.chat.is_supergroup()).await {
-                handle_ai_request(
+            if !msg.chat.is_private()
+                && storage
+                    .is_enabled(chat_id.0, thread_id, msg.chat.is_supergroup())
+                    .await
+            {
+                if let Err(AiRequestError::ChatBusy) = handle_ai_request(
                     bot_clone,
                     chat_id,
+                    thread,
                     message_id,
+                    user_id,
                     text,
+                    scope,
                     storage_clone,
                     busy_clone,
                 )
-                .await;
+                .await
+                {
+                    if let Some(user_id) = user_id {
+                        quota::refund(user_id, chat_id);
+                    }
+                }
             } else {
                 tokio::spawn(async move {
-                    handle_ai_request(
+                    if let Err(AiRequestError::ChatBusy) = handle_ai_request(
                         bot_clone,
                         chat_id,
+                        thread,
                         message_id,
+                        user_id,
                         text,
+                        scope,
                         storage_clone,
                         busy_clone,
                     )
-                    .await;
+                    .await
+                    {
+                        if let Some(user_id) = user_id {
+                            quota::refund(user_id, chat_id);
+                        }
+                    }
                 });
             }
         }
@@ -157,6 +546,7 @@ pub async fn command_handler(
                     storage
                         .set_system_fingerprint(msg.chat.id.0, fingerprint)
                         .await;
+                    confirm_silent(&bot, msg.chat.id, "System fingerprint set").await?;
                 } else if msg.chat.is_private() {
                     storage
                         .set_system_fingerprint(msg.chat.id.0, fingerprint)
@@ -166,35 +556,546 @@ pub async fn command_handler(
                 }
             }
         }
+        Command::Persona(name) => {
+            let name = name.trim().to_lowercase();
+            if let Some(user) = msg.from {
+                if (!msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await)
+                    || msg.chat.is_private()
+                {
+                    match storage.get_persona(msg.chat.id.0, &name).await {
+                        Some(fingerprint) => {
+                            storage
+                                .set_system_fingerprint(msg.chat.id.0, fingerprint)
+                                .await;
+                            if msg.chat.is_private() {
+                                bot.send_message(
+                                    msg.chat.id,
+                                    format!("Persona '{}' activated", name),
+                                )
+                                .await?;
+                            } else {
+                                bot.delete_message(msg.chat.id, msg.id).await?;
+                                confirm_silent(
+                                    &bot,
+                                    msg.chat.id,
+                                    &format!("Persona '{}' activated", name),
+                                )
+                                .await?;
+                            }
+                        }
+                        None => {
+                            bot.send_message(
+                                msg.chat.id,
+                                format!("No persona named '{}'. Use /personas to list them.", name),
+                            )
+                            .await?;
+                        }
+                    }
+                }
+            }
+        }
+        Command::SavePersona(name) => {
+            let name = name.trim().to_lowercase();
+            if name.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /savepersona <name>")
+                    .await?;
+                return Ok(());
+            }
+            if let Some(user) = msg.from {
+                if (!msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await)
+                    || msg.chat.is_private()
+                {
+                    let fingerprint = storage.get_system_fingerprint(msg.chat.id.0).await;
+                    storage
+                        .save_persona(msg.chat.id.0, name.clone(), fingerprint)
+                        .await;
+                    if msg.chat.is_private() {
+                        bot.send_message(msg.chat.id, format!("Persona '{}' saved", name))
+                            .await?;
+                    } else {
+                        bot.delete_message(msg.chat.id, msg.id).await?;
+                        confirm_silent(&bot, msg.chat.id, &format!("Persona '{}' saved", name))
+                            .await?;
+                    }
+                }
+            }
+        }
+        Command::Personas => {
+            let personas = storage.list_personas(msg.chat.id.0).await;
+            let text = if personas.is_empty() {
+                "No personas saved. Use /savepersona <name> to save the current fingerprint."
+                    .to_string()
+            } else {
+                format!("Personas:\n{}", personas.join("\n"))
+            };
+            bot.send_message(msg.chat.id, text).await?;
+        }
         Command::Temperature(temperature) => {
             let mut temperature = temperature as f32;
             if !{ 0.0..=2.0 }.contains(&temperature) {
-                temperature = 0.7;
+                let model = storage.get_model(msg.chat.id.0).await.unwrap_or_default();
+                temperature = default_temperature(&model);
             }
+            let change = format!("temperature={}", temperature);
             if let Some(user) = msg.from {
                 if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
                     bot.delete_message(msg.chat.id, msg.id).await?;
                     storage.set_temperature(msg.chat.id.0, temperature).await;
+                    system::record_setting_change(msg.chat.id.0, &storage, &change).await;
+                    confirm_silent(&bot, msg.chat.id, "Temperature set").await?;
                 } else if msg.chat.is_private() {
                     storage.set_temperature(msg.chat.id.0, temperature).await;
+                    system::record_setting_change(msg.chat.id.0, &storage, &change).await;
                     bot.send_message(msg.chat.id, "Temperature set").await?;
                 }
             }
         }
-        Command::Clear => {
+        Command::Settings => {
+            let Some(user) = msg.from.as_ref() else {
+                return Ok(());
+            };
+            if !msg.chat.is_private() && !is_admin(&bot, msg.chat.id, user.id).await {
+                return Ok(());
+            }
+            let (model, temperature) = settings::chat_settings(msg.chat.id, &storage).await;
+            let allowed = system::allowed_models();
+            bot.send_message(msg.chat.id, settings::settings_text(temperature, &model))
+                .reply_markup(settings::settings_keyboard(temperature, &model, &allowed))
+                .await?;
+        }
+        Command::ModelInfo => {
+            let info = system::describe_settings(msg.chat.id.0, &storage).await;
+            bot.send_message(msg.chat.id, info).await?;
+        }
+        Command::Model(name) => {
+            let chat_id = msg.chat.id.0;
+            let name = name.trim();
+            let allowed = system::allowed_models();
+            if name.is_empty() {
+                let model = storage.resolve_model(chat_id).await;
+                let mut reply = format!("model={} ({})", model.value, model.source);
+                if !allowed.is_empty() {
+                    reply.push_str(&format!("\nAvailable: {}", allowed.join(", ")));
+                }
+                bot.send_message(msg.chat.id, reply).await?;
+                return Ok(());
+            }
+            let model = if name.eq_ignore_ascii_case("default") {
+                None
+            } else {
+                if let Err(e) = system::check_model_allowed(name, &allowed) {
+                    bot.send_message(msg.chat.id, e).await?;
+                    return Ok(());
+                }
+                Some(name.to_string())
+            };
+            let reply = match &model {
+                Some(model) => format!("Model set to {}", model),
+                None => "Model follows the bot default".to_string(),
+            };
+            let change = format!("model={}", model.as_deref().unwrap_or("default"));
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage.set_model(chat_id, model).await;
+                    system::record_setting_change(chat_id, &storage, &change).await;
+                    confirm_silent(&bot, msg.chat.id, &reply).await?;
+                } else if msg.chat.is_private() {
+                    storage.set_model(chat_id, model).await;
+                    system::record_setting_change(chat_id, &storage, &change).await;
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+            }
+        }
+        Command::Thinking(mode) => {
+            let thinking = match mode.trim().to_lowercase().as_str() {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    bot.send_message(msg.chat.id, "Usage: /thinking on|off")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let reply = if thinking {
+                "Model reasoning will be shown"
+            } else {
+                "Model reasoning will be hidden"
+            };
             if let Some(user) = msg.from {
                 if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
                     bot.delete_message(msg.chat.id, msg.id).await?;
-                    storage.clear_conversation_context(msg.chat.id.0).await;
+                    storage.set_thinking(msg.chat.id.0, thinking).await;
+                    confirm_silent(&bot, msg.chat.id, reply).await?;
                 } else if msg.chat.is_private() {
-                    storage.clear_conversation_context(msg.chat.id.0).await;
+                    storage.set_thinking(msg.chat.id.0, thinking).await;
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+            }
+        }
+        Command::NotesMode(mode) => {
+            let inject = match mode.trim().to_lowercase().as_str() {
+                "inject" => true,
+                "store" => false,
+                _ => {
+                    bot.send_message(msg.chat.id, "Usage: /notesmode inject|store")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let reply = if inject {
+                "Notes will be sent to the model"
+            } else {
+                "Notes will be stored only, the model won't see them"
+            };
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage.set_inject_notes(msg.chat.id.0, inject).await;
+                    confirm_silent(&bot, msg.chat.id, reply).await?;
+                } else if msg.chat.is_private() {
+                    storage.set_inject_notes(msg.chat.id.0, inject).await;
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+            }
+        }
+        Command::Brevity(level) => {
+            let brevity = match level.parse::<Brevity>() {
+                Ok(brevity) => brevity,
+                Err(_) => {
+                    bot.send_message(msg.chat.id, "Usage: /brevity short|normal|detailed")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let reply = format!("Answer length set to {}", brevity.as_str());
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage.set_brevity(msg.chat.id.0, brevity).await;
+                    confirm_silent(&bot, msg.chat.id, &reply).await?;
+                } else if msg.chat.is_private() {
+                    storage.set_brevity(msg.chat.id.0, brevity).await;
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+            }
+        }
+        Command::Format(preset) => {
+            if preset.trim().is_empty() {
+                let format = storage.get_answer_format(msg.chat.id.0).await;
+                bot.send_message(msg.chat.id, format!("Answer format: {}", format.as_str()))
+                    .await?;
+                return Ok(());
+            }
+            let format = match preset.parse::<AnswerFormat>() {
+                Ok(format) => format,
+                Err(_) => {
+                    bot.send_message(msg.chat.id, "Usage: /format [chat|markdown|code|json]")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let reply = format!("Answer format set to {}", format.as_str());
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage.set_answer_format(msg.chat.id.0, format).await;
+                    confirm_silent(&bot, msg.chat.id, &reply).await?;
+                } else if msg.chat.is_private() {
+                    storage.set_answer_format(msg.chat.id.0, format).await;
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+            }
+        }
+        Command::Schema(schema) => {
+            let schema = schema.trim();
+            if schema.is_empty() {
+                let reply = match storage.get_json_schema(msg.chat.id.0).await {
+                    Some(schema) => format!("JSON schema: {}", schema),
+                    None => "No JSON schema set".to_string(),
+                };
+                bot.send_message(msg.chat.id, reply).await?;
+                return Ok(());
+            }
+            let schema = if schema.eq_ignore_ascii_case("off") {
+                None
+            } else {
+                match answer_schema::parse(schema) {
+                    Ok(schema) => Some(schema),
+                    Err(e) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            format!("{}. Usage: /schema <schema> or /schema off", e),
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                }
+            };
+            let reply = match (&schema, storage.get_answer_format(msg.chat.id.0).await) {
+                (None, _) => "JSON schema removed".to_string(),
+                (Some(_), AnswerFormat::Json) => "JSON schema set".to_string(),
+                (Some(_), _) => "JSON schema set, it applies once /format json is used".to_string(),
+            };
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage.set_json_schema(msg.chat.id.0, schema).await;
+                    confirm_silent(&bot, msg.chat.id, &reply).await?;
+                } else if msg.chat.is_private() {
+                    storage.set_json_schema(msg.chat.id.0, schema).await;
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+            }
+        }
+        Command::AutoClear(minutes) => {
+            let chat_id = msg.chat.id.0;
+            let minutes = minutes.trim().to_lowercase();
+            if minutes.is_empty() {
+                let reply = match system::auto_clear_minutes(chat_id, &storage).await {
+                    Some(minutes) => {
+                        format!("Conversation is cleared after {} idle minutes", minutes)
+                    }
+                    None => "Conversation is never cleared automatically".to_string(),
+                };
+                bot.send_message(msg.chat.id, reply).await?;
+                return Ok(());
+            }
+            let minutes = match minutes.as_str() {
+                "default" => None,
+                "off" => Some(0),
+                minutes => match minutes.parse::<u32>() {
+                    Ok(minutes) => Some(minutes),
+                    Err(_) => {
+                        bot.send_message(
+                            msg.chat.id,
+                            "Usage: /autoclear <minutes>, off or default",
+                        )
+                        .await?;
+                        return Ok(());
+                    }
+                },
+            };
+            let reply = match minutes {
+                None => "Automatic clearing follows the bot default".to_string(),
+                Some(0) => "Automatic clearing turned off".to_string(),
+                Some(minutes) => format!(
+                    "Conversation will be cleared after {} idle minutes",
+                    minutes
+                ),
+            };
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage.set_auto_clear_mins(chat_id, minutes).await;
+                    confirm_silent(&bot, msg.chat.id, &reply).await?;
+                } else if msg.chat.is_private() {
+                    storage.set_auto_clear_mins(chat_id, minutes).await;
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+            }
+        }
+        Command::MaxTokens(tokens) => {
+            let chat_id = msg.chat.id.0;
+            let tokens = tokens.trim().to_lowercase();
+            if tokens.is_empty() {
+                let max_tokens = storage.resolve_max_tokens(chat_id).await;
+                bot.send_message(
+                    msg.chat.id,
+                    format!("max_tokens={} ({})", max_tokens.value, max_tokens.source),
+                )
+                .await?;
+                return Ok(());
+            }
+            let max_tokens = match tokens.as_str() {
+                "default" => None,
+                tokens => match tokens.parse::<u32>() {
+                    Ok(tokens) => match validate_max_tokens(tokens) {
+                        Ok(tokens) => Some(tokens),
+                        Err(e) => {
+                            bot.send_message(msg.chat.id, format!("⚠️ {}", e)).await?;
+                            return Ok(());
+                        }
+                    },
+                    Err(_) => {
+                        bot.send_message(msg.chat.id, "Usage: /maxtokens <tokens> or default")
+                            .await?;
+                        return Ok(());
+                    }
+                },
+            };
+            let reply = match max_tokens {
+                Some(tokens) => format!("Answers are limited to {} tokens", tokens),
+                None => "max_tokens follows the bot default".to_string(),
+            };
+            let change = format!(
+                "max_tokens={}",
+                max_tokens.map_or("default".to_string(), |tokens| tokens.to_string())
+            );
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage.set_max_tokens(chat_id, max_tokens).await;
+                    system::record_setting_change(chat_id, &storage, &change).await;
+                    confirm_silent(&bot, msg.chat.id, &reply).await?;
+                } else if msg.chat.is_private() {
+                    storage.set_max_tokens(chat_id, max_tokens).await;
+                    system::record_setting_change(chat_id, &storage, &change).await;
+                    bot.send_message(msg.chat.id, reply).await?;
+                }
+            }
+        }
+        Command::Clear(target) => {
+            let target = match target.parse::<ClearTarget>() {
+                Ok(target) => target,
+                Err(e) => {
+                    bot.send_message(msg.chat.id, format!("{}. Usage: /clear [chat|all]", e))
+                        .await?;
+                    return Ok(());
+                }
+            };
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    clear_scopes(msg.chat.id.0, target, &storage).await;
+                    confirm_silent(&bot, msg.chat.id, "Conversation cleared").await?;
+                } else if msg.chat.is_private() {
+                    clear_scopes(msg.chat.id.0, target, &storage).await;
                     bot.send_message(msg.chat.id, "Conversation cleared")
                         .await?;
                 }
             }
         }
+        Command::Pin => {
+            if let Some(user) = msg.from {
+                if msg.chat.is_private() || is_admin(&bot, msg.chat.id, user.id).await {
+                    if !msg.chat.is_private() {
+                        bot.delete_message(msg.chat.id, msg.id).await?;
+                    }
+                    let reply = if storage
+                        .pin_last_user_message(storage.context_key(msg.chat.id.0).await)
+                        .await
+                    {
+                        "📌 Latest message pinned"
+                    } else {
+                        "Nothing to pin yet"
+                    };
+                    if msg.chat.is_private() {
+                        bot.send_message(msg.chat.id, reply).await?;
+                    } else {
+                        confirm_silent(&bot, msg.chat.id, reply).await?;
+                    }
+                }
+            }
+        }
+        Command::Unpin => {
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage
+                        .unpin_message(storage.context_key(msg.chat.id.0).await)
+                        .await;
+                    confirm_silent(&bot, msg.chat.id, "Message unpinned").await?;
+                } else if msg.chat.is_private() {
+                    storage
+                        .unpin_message(storage.context_key(msg.chat.id.0).await)
+                        .await;
+                    bot.send_message(msg.chat.id, "Message unpinned").await?;
+                }
+            }
+        }
+        Command::Bye => {
+            if let Some(user) = msg.from {
+                if msg.chat.is_private() || is_admin(&bot, msg.chat.id, user.id).await {
+                    storage
+                        .clear_conversation_context(storage.context_key(msg.chat.id.0).await)
+                        .await;
+                    let farewell = CONFIG
+                        .get_string("farewell_message")
+                        .unwrap_or("👋 Bye, {name}! Our conversation has been reset.".into())
+                        .replace("{name}", &user.first_name);
+                    bot.send_message(msg.chat.id, farewell).await?;
+                }
+            }
+        }
+        Command::NewChat(_) | Command::SwitchChat(_) | Command::DeleteChat(_)
+            if !msg.chat.is_private() =>
+        {
+            bot.send_message(
+                msg.chat.id,
+                "Parallel chats are only available in private chats",
+            )
+            .await?;
+        }
+        Command::NewChat(name) => {
+            let name = name.trim().to_lowercase();
+            if name.is_empty() {
+                bot.send_message(msg.chat.id, "Usage: /newchat <name>")
+                    .await?;
+                return Ok(());
+            }
+            let mut slots = storage.get_chat_slots(msg.chat.id.0).await;
+            let max_slots = CONFIG.get::<usize>("max_chat_slots").unwrap_or(10);
+            let reply = match slots.create(&name, max_slots) {
+                Ok(()) => {
+                    storage.set_chat_slots(msg.chat.id.0, slots).await;
+                    format!("💬 Started chat '{}'", name)
+                }
+                Err(e) => e,
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::SwitchChat(name) => {
+            let name = name.trim().to_lowercase();
+            let mut slots = storage.get_chat_slots(msg.chat.id.0).await;
+            let reply = if slots.switch(&name) {
+                storage.set_chat_slots(msg.chat.id.0, slots).await;
+                format!("💬 Switched to chat '{}'", name)
+            } else {
+                format!("No chat named '{}'. Use /chats to list them.", name)
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::DeleteChat(name) => {
+            let name = name.trim().to_lowercase();
+            let mut slots = storage.get_chat_slots(msg.chat.id.0).await;
+            let reply = match slots.delete(msg.chat.id.0, &name) {
+                Some(context_key) => {
+                    storage.clear_conversation_context(context_key).await;
+                    storage.set_chat_slots(msg.chat.id.0, slots).await;
+                    format!("Chat '{}' deleted", name)
+                }
+                None => format!(
+                    "No chat named '{}'. The main chat can only be cleared.",
+                    name
+                ),
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Chats => {
+            let slots = storage.get_chat_slots(msg.chat.id.0).await;
+            let list = slots
+                .list()
+                .iter()
+                .map(|name| {
+                    if *name == slots.active_name() {
+                        format!("• {} (active)", name)
+                    } else {
+                        format!("• {}", name)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            bot.send_message(msg.chat.id, format!("Chats:\n{}", list))
+                .await?;
+        }
         Command::Future => {
+            let thread = topic_thread(&msg);
             if let Some(user) = msg.from {
+                if let Err(blocked) = quota::try_acquire(user.id, msg.chat.id) {
+                    bot.send_message(msg.chat.id, blocked.to_string()).await?;
+                    return Ok(());
+                }
                 let chat_id = msg.chat.id;
                 let message_id = msg.id;
                 let bot_clone = bot.clone();
@@ -204,38 +1105,120 @@ pub async fn command_handler(
                 let promt = format!("Ты опытный предсказатель. Тебе нужно составить предсказание на день для человека. 
             Для гадания можешь на выбор использовать Таро, Руны или по звёздам. Текущая дата: {}
         Пользователь: {} Имя: {} Отвечай очень кратко.", chrono::Local::now(), user.username.clone().unwrap_or("Unknown".into()), user.full_name());
-                handle_ai_request(
+                if let Err(AiRequestError::ChatBusy) = handle_ai_request(
                     bot_clone,
                     chat_id,
+                    thread,
                     message_id,
+                    Some(user.id),
                     promt,
+                    ContextScope::Main,
                     storage_clone,
                     busy_clone,
                 )
-                .await;
+                .await
+                {
+                    quota::refund(user.id, chat_id);
+                }
+            }
+        }
+        Command::Translate(args) => {
+            let args = args.trim();
+            let (lang, text) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            let text = match text.trim() {
+                "" => msg
+                    .reply_to_message()
+                    .and_then(|reply| reply.text().or(reply.caption()))
+                    .unwrap_or_default(),
+                text => text,
+            };
+            if !system::is_valid_language(lang) || text.is_empty() {
+                bot.send_message(
+                    msg.chat.id,
+                    "Usage: /translate <lang> <text>, or reply to a message with /translate <lang>",
+                )
+                .await?;
+                return Ok(());
+            }
+            if !acquire_quota(&bot, &msg).await? {
+                return Ok(());
+            }
+
+            let reply = system::translate(msg.chat.id.0, &storage, text, lang)
+                .await
+                .unwrap_or_else(|e| e);
+            for chunk in system::split_into_chunks(&reply, None) {
+                bot.send_message(msg.chat.id, chunk).await?;
+            }
+        }
+        Command::Eli5(text) => run_transform(&bot, &msg, Transform::Eli5, &text, &storage).await?,
+        Command::Tldr(text) => run_transform(&bot, &msg, Transform::Tldr, &text, &storage).await?,
+        Command::Formal(text) => {
+            run_transform(&bot, &msg, Transform::Formal, &text, &storage).await?
+        }
+        Command::Expand(text) => {
+            run_transform(&bot, &msg, Transform::Expand, &text, &storage).await?
+        }
+        Command::Diff(args) => {
+            let active = storage.get_model(msg.chat.id.0).await.unwrap_or_default();
+            let comparison = CONFIG.get_string("comparison_model").ok();
+            let (a, b, prompt) = match system::parse_diff_args(
+                &args,
+                &active,
+                comparison.as_deref().filter(|model| !model.is_empty()),
+            ) {
+                Ok(parsed) => parsed,
+                Err(usage) => {
+                    bot.send_message(msg.chat.id, usage).await?;
+                    return Ok(());
+                }
+            };
+            let allowed = system::allowed_models();
+            if let Err(e) = system::check_model_allowed(&a, &allowed)
+                .and_then(|_| system::check_model_allowed(&b, &allowed))
+            {
+                bot.send_message(msg.chat.id, e).await?;
+                return Ok(());
+            }
+            if !acquire_quota(&bot, &msg).await? {
+                return Ok(());
+            }
+
+            let reply = system::compare_models(msg.chat.id.0, &storage, (&a, &b), &prompt).await;
+            for chunk in system::split_into_chunks(&reply, None) {
+                bot.send_message(msg.chat.id, chunk).await?;
             }
         }
         Command::AddNote(text) => {
             if let Some(user) = msg.from {
-                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
-                    let _ = bot.delete_message(msg.chat.id, msg.id).await;
+                if (!msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await)
+                    || msg.chat.is_private()
+                {
+                    if !msg.chat.is_private() {
+                        let _ = bot.delete_message(msg.chat.id, msg.id).await;
+                    }
+                    let embedding = system::note_embedding(msg.chat.id.0, &storage, &text).await;
+                    let note_id = chrono::Local::now().timestamp_millis();
                     storage
                         .add_note(Note {
-                            note_id: chrono::Local::now().timestamp_millis(),
+                            note_id,
                             chat_id: msg.chat.id.0,
                             user_id: user.id.0,
                             text: text,
+                            created_at: chrono::Local::now().timestamp(),
+                            embedding,
                         })
                         .await;
-                } else if msg.chat.is_private() {
-                    storage
-                        .add_note(Note {
-                            note_id: chrono::Local::now().timestamp_millis(),
-                            chat_id: msg.chat.id.0,
+                    events::notify_event(
+                        msg.chat.id.0,
+                        EventKind::NoteAdded {
+                            note_id,
                             user_id: user.id.0,
-                            text: text,
-                        })
-                        .await;
+                        },
+                    );
+                    if !msg.chat.is_private() {
+                        confirm_silent(&bot, msg.chat.id, "Note added").await?;
+                    }
                 }
             }
         }
@@ -244,6 +1227,7 @@ pub async fn command_handler(
                 if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
                     let _ = bot.delete_message(msg.chat.id, msg.id).await;
                     storage.remove_note(msg.chat.id.0, id).await;
+                    confirm_silent(&bot, msg.chat.id, "Note removed").await?;
                 } else if msg.chat.is_private() {
                     storage.remove_note(msg.chat.id.0, id).await;
                 }
@@ -276,6 +1260,33 @@ pub async fn command_handler(
                 }
             }
         }
+        Command::SummaryPin => {
+            let Some(user) = msg.from.as_ref() else {
+                return Ok(());
+            };
+            if msg.chat.is_private() {
+                if !acquire_quota(&bot, &msg).await? {
+                    return Ok(());
+                }
+                let reply = match summary::refresh(msg.chat.id.0, &storage).await {
+                    Ok(text) => format!("{}{}", summary::SUMMARY_PREFIX, text),
+                    Err(e) => format!("Summary not updated: {}", e),
+                };
+                bot.send_message(msg.chat.id, reply).await?;
+            } else if is_admin(&bot, msg.chat.id, user.id).await {
+                if !acquire_quota(&bot, &msg).await? {
+                    return Ok(());
+                }
+                let _ = bot.delete_message(msg.chat.id, msg.id).await;
+                match summary::refresh(msg.chat.id.0, &storage).await {
+                    Ok(_) => confirm_silent(&bot, msg.chat.id, "Summary note updated").await?,
+                    Err(e) => {
+                        bot.send_message(user.id, format!("Summary not updated: {}", e))
+                            .await?;
+                    }
+                }
+            }
+        }
         Command::EraseNotes => {
             if let Some(user) = msg.from {
                 if (!msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await)
@@ -285,9 +1296,585 @@ pub async fn command_handler(
                         let _ = bot.delete_message(msg.chat.id, msg.id).await;
                     }
                     storage.erase_notes(msg.chat.id.0).await;
+                    if !msg.chat.is_private() {
+                        confirm_silent(&bot, msg.chat.id, "Notes erased").await?;
+                    }
+                }
+            }
+        }
+        Command::ExportNotes => {
+            if let Some(user) = msg.from {
+                if (!msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await)
+                    || msg.chat.is_private()
+                {
+                    if !msg.chat.is_private() {
+                        let _ = bot.delete_message(msg.chat.id, msg.id).await;
+                    }
+                    let notes = storage.list_notes(msg.chat.id.0).await;
+                    let json = serde_json::to_string_pretty(&notes).unwrap_or_default();
+                    // Same as /listnotes: notes go to the requester's DM
+                    bot.send_document(
+                        user.id,
+                        InputFile::memory(json.into_bytes()).file_name("notes.json"),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Command::ExportJsonl => {
+            if let Some(user) = msg.from {
+                if (!msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await)
+                    || msg.chat.is_private()
+                {
+                    if !msg.chat.is_private() {
+                        let _ = bot.delete_message(msg.chat.id, msg.id).await;
+                    }
+                    let chat_id = msg.chat.id.0;
+                    let mut messages = Vec::new();
+                    // Notes and seed turns are never exported, the system prompt optionally
+                    if CONFIG.get_bool("export_jsonl_system").unwrap_or(true) {
+                        messages.extend(
+                            system::build_messages(chat_id, &storage, ContextScope::Main, None)
+                                .await
+                                .into_iter()
+                                .next(),
+                        );
+                    }
+                    messages.extend(
+                        storage
+                            .get_conversation_context(storage.context_key(chat_id).await)
+                            .await,
+                    );
+                    match system::format_jsonl(&messages) {
+                        Ok(line) => {
+                            bot.send_document(
+                                user.id,
+                                InputFile::memory(format!("{}\n", line).into_bytes())
+                                    .file_name("conversation.jsonl"),
+                            )
+                            .await?;
+                        }
+                        Err(e) => {
+                            bot.send_message(user.id, format!("Nothing to export: {}", e))
+                                .await?;
+                        }
+                    }
+                }
+            }
+        }
+        Command::ChatLog(page) => {
+            let Some(user) = msg.from.as_ref() else {
+                return Ok(());
+            };
+            if !msg.chat.is_private() && !is_admin(&bot, msg.chat.id, user.id).await {
+                return Ok(());
+            }
+            if !CONFIG.get_bool("enable_chat_log").unwrap_or(false) {
+                bot.send_message(msg.chat.id, "The request log is not enabled.")
+                    .await?;
+                return Ok(());
+            }
+            let page = match page.trim() {
+                "" => 1,
+                page => match page.parse::<usize>() {
+                    Ok(page) if page > 0 => page,
+                    _ => {
+                        bot.send_message(msg.chat.id, "Usage: /chatlog [page]")
+                            .await?;
+                        return Ok(());
+                    }
+                },
+            };
+
+            let page_size = CONFIG
+                .get::<usize>("chat_log_page_size")
+                .unwrap_or(5)
+                .max(1);
+            let (entries, total) = storage
+                .get_chat_log(msg.chat.id.0, (page - 1) * page_size, page_size)
+                .await;
+            let pages = total.div_ceil(page_size).max(1);
+            let text = if total == 0 {
+                "The request log is empty.".to_string()
+            } else if entries.is_empty() {
+                format!("There are only {} page(s).", pages)
+            } else {
+                system::format_chat_log(&entries, page, pages)
+            };
+            for chunk in system::split_into_chunks(&text, None) {
+                bot.send_message(msg.chat.id, chunk).await?;
+            }
+        }
+        Command::ImportNotes(mode) => {
+            let replace = match mode.trim().to_lowercase().as_str() {
+                "" | "merge" => false,
+                "replace" => true,
+                _ => {
+                    bot.send_message(msg.chat.id, "Usage: /importnotes [merge|replace]")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let Some(user) = msg.from.as_ref() else {
+                return Ok(());
+            };
+            if !msg.chat.is_private() && !is_admin(&bot, msg.chat.id, user.id).await {
+                return Ok(());
+            }
+            let Some(document) = msg.reply_to_message().and_then(|reply| reply.document()) else {
+                bot.send_message(
+                    msg.chat.id,
+                    "Reply to a notes JSON document with /importnotes [merge|replace]",
+                )
+                .await?;
+                return Ok(());
+            };
+
+            let chat_id = msg.chat.id.0;
+            let imported = files::download_text(&bot, document)
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|json| parse_notes_json(&json, chat_id));
+            let (imported, mut rejected) = match imported {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    bot.send_message(msg.chat.id, format!("Import failed: {}", e))
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            let mut notes = if replace {
+                Vec::new()
+            } else {
+                storage.list_notes(chat_id).await
+            };
+            let max_notes = CONFIG.get::<usize>("max_notes_per_chat").unwrap_or(200);
+            let mut added = 0;
+            for note in imported {
+                if notes.len() >= max_notes
+                    || notes
+                        .iter()
+                        .any(|existing| existing.note_id == note.note_id)
+                {
+                    rejected += 1;
+                    continue;
+                }
+                notes.push(note);
+                added += 1;
+            }
+            storage.replace_notes(chat_id, notes).await;
+
+            bot.send_message(
+                msg.chat.id,
+                format!("Imported {} note(s), rejected {}", added, rejected),
+            )
+            .await?;
+        }
+        Command::ClearNotes(filter) => {
+            let filter = match filter.parse::<NoteFilter>() {
+                Ok(filter) if !filter.is_empty() => filter,
+                Ok(_) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        "Usage: /clearnotes user:<id> after:<YYYY-MM-DD> before:<YYYY-MM-DD> (use /erasenotes to remove all)",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                Err(e) => {
+                    bot.send_message(msg.chat.id, e).await?;
+                    return Ok(());
+                }
+            };
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    let _ = bot.delete_message(msg.chat.id, msg.id).await;
+                    let removed = storage.remove_notes_where(msg.chat.id.0, &filter).await;
+                    confirm_silent(&bot, msg.chat.id, &format!("Removed {} note(s)", removed))
+                        .await?;
+                } else if msg.chat.is_private() {
+                    let removed = storage.remove_notes_where(msg.chat.id.0, &filter).await;
+                    bot.send_message(msg.chat.id, format!("Removed {} note(s)", removed))
+                        .await?;
                 }
             }
         }
+        Command::SyncCommands => {
+            let Some(user) = msg.from else {
+                return Ok(());
+            };
+            if !is_owner(user.id) {
+                bot.send_message(msg.chat.id, "⛔ This command is for bot owners only")
+                    .await?;
+                return Ok(());
+            }
+            let reply = match sync_commands(&bot).await {
+                Ok(()) => "✅ Command menu updated".to_string(),
+                Err(e) => format!("Failed to update the command menu: {}", e),
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Logs(lines) => {
+            let Some(user) = msg.from else {
+                return Ok(());
+            };
+            if !is_owner(user.id) {
+                bot.send_message(msg.chat.id, "⛔ This command is for bot owners only")
+                    .await?;
+                return Ok(());
+            }
+            if !msg.chat.is_private() {
+                let _ = bot.delete_message(msg.chat.id, msg.id).await;
+            }
+
+            let lines = lines.clamp(1, MAX_LOG_LINES);
+            let path = logging::today_log_path();
+            // Logs may contain prompts, so they only ever go to the owner's DM
+            match logging::tail_lines(&path, lines) {
+                Ok(tail) => {
+                    bot.send_document(
+                        user.id,
+                        InputFile::memory(tail.into_bytes()).file_name("log.txt"),
+                    )
+                    .await?;
+                }
+                Err(e) => {
+                    error!("Failed to read log {:?}: {}", path, e);
+                    bot.send_message(user.id, format!("Failed to read log: {}", e))
+                        .await?;
+                }
+            }
+        }
+        Command::Header(arg) => {
+            let Some(user) = msg.from else {
+                return Ok(());
+            };
+            if !is_owner(user.id) {
+                bot.send_message(msg.chat.id, "⛔ This command is for bot owners only")
+                    .await?;
+                return Ok(());
+            }
+            if !msg.chat.is_private() {
+                let _ = bot.delete_message(msg.chat.id, msg.id).await;
+            }
+
+            let chat_id = msg.chat.id.0;
+            let mut headers = storage.get_extra_headers(chat_id).await;
+            // Header values may be credentials, so replies go to the owner's DM
+            let arg = arg.trim();
+            let reply = if arg.is_empty() {
+                if headers.is_empty() {
+                    "No custom headers for this chat".to_string()
+                } else {
+                    let mut lines: Vec<String> = headers
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}", name, value))
+                        .collect();
+                    lines.sort();
+                    lines.join("\n")
+                }
+            } else if let Some((name, value)) = arg.split_once(':') {
+                match system::parse_header(name, value) {
+                    // A key set this way would override the chat's /apikey
+                    Some((name, _)) if system::is_credential_header(&name) => {
+                        format!("Header {} carries the API key, use /apikey instead", name)
+                    }
+                    Some((name, _)) => {
+                        headers.insert(name.to_string(), value.trim().to_string());
+                        storage.set_extra_headers(chat_id, headers).await;
+                        format!("Header {} set", name)
+                    }
+                    None => "Invalid header. Usage: /header Name: value".to_string(),
+                }
+            } else {
+                let name = arg.to_lowercase();
+                if headers.remove(&name).is_some() {
+                    storage.set_extra_headers(chat_id, headers).await;
+                    format!("Header {} removed", name)
+                } else {
+                    format!("Header {} is not set", name)
+                }
+            };
+            bot.send_message(user.id, reply).await?;
+        }
+        Command::Raw(mode) => {
+            let Some(user) = msg.from else {
+                return Ok(());
+            };
+            if !is_owner(user.id) {
+                bot.send_message(msg.chat.id, "⛔ This command is for bot owners only")
+                    .await?;
+                return Ok(());
+            }
+            let chat_id = msg.chat.id.0;
+            let reply = match mode.trim().to_lowercase().as_str() {
+                "on" => {
+                    RAW_ANSWERS.arm(chat_id, true);
+                    "The next answer will also be sent unprocessed as a document"
+                }
+                "off" => {
+                    RAW_ANSWERS.arm(chat_id, false);
+                    "Raw mode off"
+                }
+                "" if RAW_ANSWERS.is_armed(chat_id) => "Raw mode is on for the next answer",
+                "" => "Raw mode is off",
+                _ => "Usage: /raw on or /raw off",
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Active => {
+            let Some(user) = msg.from else {
+                return Ok(());
+            };
+            if !is_owner(user.id) {
+                bot.send_message(msg.chat.id, "⛔ This command is for bot owners only")
+                    .await?;
+                return Ok(());
+            }
+            if !msg.chat.is_private() {
+                let _ = bot.delete_message(msg.chat.id, msg.id).await;
+            }
+            // Chat ids and requesters of other chats only ever go to the owner's DM
+            bot.send_message(user.id, format_active(&busy.active_requests()))
+                .await?;
+        }
+        Command::Kill(chat_id) => {
+            let Some(user) = msg.from else {
+                return Ok(());
+            };
+            if !is_owner(user.id) {
+                bot.send_message(msg.chat.id, "⛔ This command is for bot owners only")
+                    .await?;
+                return Ok(());
+            }
+            if !msg.chat.is_private() {
+                let _ = bot.delete_message(msg.chat.id, msg.id).await;
+            }
+            // Like /active, the reply names another chat and only goes to the owner's DM
+            let Ok(chat_id) = chat_id.trim().parse::<i64>() else {
+                bot.send_message(user.id, "Usage: /kill <chat id>, see /active").await?;
+                return Ok(());
+            };
+            let reply = match busy.kill(chat_id) {
+                Some(dropped) => {
+                    event!(
+                        Level::WARN,
+                        "Owner {} killed the request of chat {}",
+                        user.id,
+                        chat_id
+                    );
+                    format!(
+                        "Chat {} is free again; its request was cancelled and {} queued request(s) dropped",
+                        chat_id, dropped
+                    )
+                }
+                None => format!("Chat {} has no request in progress", chat_id),
+            };
+            bot.send_message(user.id, reply).await?;
+        }
+        Command::ApiKey(key) => {
+            let Some(user) = msg.from else {
+                return Ok(());
+            };
+            if !is_owner(user.id) {
+                bot.send_message(msg.chat.id, "⛔ This command is for bot owners only")
+                    .await?;
+                return Ok(());
+            }
+            // The message may hold a key, so it is removed everywhere and the
+            // reply, which never contains the key, goes to the owner's DM
+            let _ = bot.delete_message(msg.chat.id, msg.id).await;
+
+            let chat_id = msg.chat.id.0;
+            let key = key.trim();
+            let reply = if key.is_empty() {
+                if storage.get_api_key(chat_id).await.is_some() {
+                    format!("Chat {} uses its own API key", chat_id)
+                } else {
+                    format!("Chat {} uses the global API key", chat_id)
+                }
+            } else if key.eq_ignore_ascii_case("off") {
+                storage.set_api_key(chat_id, None).await;
+                format!("Chat {} uses the global API key again", chat_id)
+            } else if key.contains(char::is_whitespace) {
+                "Invalid API key. Usage: /apikey <key> or /apikey off".to_string()
+            } else {
+                storage.set_api_key(chat_id, Some(key.to_string())).await;
+                format!("API key set for chat {}", chat_id)
+            };
+            bot.send_message(user.id, reply).await?;
+        }
+        Command::LogitBias(spec) => {
+            let Some(user) = msg.from else {
+                return Ok(());
+            };
+            if !is_owner(user.id) {
+                bot.send_message(msg.chat.id, "⛔ This command is for bot owners only")
+                    .await?;
+                return Ok(());
+            }
+
+            let chat_id = msg.chat.id.0;
+            let spec = spec.trim();
+            let reply = if spec.is_empty() {
+                let bias = storage.get_logit_bias(chat_id).await;
+                if bias.is_empty() {
+                    "No logit bias for this chat".to_string()
+                } else {
+                    let mut pairs: Vec<String> = bias
+                        .iter()
+                        .map(|(token, bias)| format!("{}:{}", token, bias))
+                        .collect();
+                    pairs.sort();
+                    pairs.join(" ")
+                }
+            } else if spec == "clear" {
+                storage.set_logit_bias(chat_id, Default::default()).await;
+                "Logit bias cleared".to_string()
+            } else {
+                match system::parse_logit_bias(spec) {
+                    Ok(bias) => {
+                        storage.set_logit_bias(chat_id, bias).await;
+                        "Logit bias set. Token ids are specific to the model's tokenizer."
+                            .to_string()
+                    }
+                    Err(e) => format!("{}\nUsage: /logitbias <token id>:<bias> ... | clear", e),
+                }
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::About => {
+            let mut about = format!(
+                "🤖 {} v{}\nCommit: {}\nStorage: {}\nModel: {}\nUptime: {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                env!("GIT_COMMIT"),
+                storage.backend_name(),
+                CONFIG.get_string("model").unwrap_or("not set".into()),
+                format_uptime(started_at.0.elapsed())
+            );
+            if let Some(depths) = limiter::queue_depths() {
+                let waiting: Vec<String> = Priority::ALL
+                    .iter()
+                    .zip(depths)
+                    .map(|(priority, depth)| format!("{} {}", priority.as_str(), depth))
+                    .collect();
+                about.push_str(&format!("\nWaiting: {}", waiting.join(", ")));
+            }
+            if let Some((hits, misses)) = answer_cache::stats() {
+                about.push_str(&format!("\nAnswer cache: {} hits, {} misses", hits, misses));
+            }
+            bot.send_message(msg.chat.id, about).await?;
+        }
+        Command::Cancel => {
+            let Some(user) = msg.from.as_ref() else {
+                return Ok(());
+            };
+            let reply = match busy.cancel(msg.chat.id.0, user.id) {
+                Cancelled::Queued => "Removed your queued request",
+                Cancelled::Active => "Cancelled your in-progress request",
+                Cancelled::Nothing => "Nothing to cancel",
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Limits => {
+            if let Some(user) = &msg.from {
+                bot.send_message(msg.chat.id, quota::describe(user.id, msg.chat.id))
+                    .await?;
+            }
+        }
+        Command::WhoAmI => {
+            if !acquire_quota(&bot, &msg).await? {
+                return Ok(());
+            }
+            let reply = match system::probe_identity(msg.chat.id.0, &storage).await {
+                Ok(probe) => probe.to_string(),
+                Err(e) => e,
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::ContextSize => {
+            let messages =
+                system::build_messages(msg.chat.id.0, &storage, ContextScope::Main, None).await;
+            let tokens = system::estimate_tokens(&messages);
+            let model = storage.get_model(msg.chat.id.0).await.unwrap_or_default();
+            let text = match system::context_window(&model) {
+                Some(window) => system::format_context_usage(tokens, window),
+                None => format!(
+                    "≈{} tokens (context window of {} unknown, see model_context_windows)",
+                    system::format_thousands(tokens),
+                    model
+                ),
+            };
+            bot.send_message(msg.chat.id, text).await?;
+        }
+        Command::Ban(target) => {
+            let Some(user) = msg.from.as_ref() else {
+                return Ok(());
+            };
+            if msg.chat.is_private() || !is_admin(&bot, msg.chat.id, user.id).await {
+                return Ok(());
+            }
+            let Some(target) = ban_target(&msg, &target) else {
+                bot.send_message(
+                    msg.chat.id,
+                    "Usage: /ban <user id>, or reply to the user's message with /ban",
+                )
+                .await?;
+                return Ok(());
+            };
+            if is_admin(&bot, msg.chat.id, target).await {
+                bot.send_message(msg.chat.id, "Administrators can't be banned")
+                    .await?;
+                return Ok(());
+            }
+            let reply = if storage.ban_user(msg.chat.id.0, target.0).await {
+                format!("User {} is banned from the bot in this chat", target)
+            } else {
+                format!("User {} is already banned", target)
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Unban(target) => {
+            let Some(user) = msg.from.as_ref() else {
+                return Ok(());
+            };
+            if msg.chat.is_private() || !is_admin(&bot, msg.chat.id, user.id).await {
+                return Ok(());
+            }
+            let Some(target) = ban_target(&msg, &target) else {
+                bot.send_message(
+                    msg.chat.id,
+                    "Usage: /unban <user id>, or reply to the user's message with /unban",
+                )
+                .await?;
+                return Ok(());
+            };
+            let reply = if storage.unban_user(msg.chat.id.0, target.0).await {
+                forget_ban_notice(msg.chat.id, target);
+                format!("User {} can use the bot again", target)
+            } else {
+                format!("User {} is not banned", target)
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Banned => {
+            let Some(user) = msg.from.as_ref() else {
+                return Ok(());
+            };
+            if msg.chat.is_private() || !is_admin(&bot, msg.chat.id, user.id).await {
+                return Ok(());
+            }
+            let banned = storage.list_banned(msg.chat.id.0).await;
+            let reply = if banned.is_empty() {
+                "No users are banned in this chat".to_string()
+            } else {
+                let ids: Vec<String> = banned.iter().map(|id| id.to_string()).collect();
+                format!("Banned users:\n{}", ids.join("\n"))
+            };
+            bot.send_message(msg.chat.id, reply).await?;
+        }
         Command::Enable => {
             let chat_id = msg.chat.id;
             let user_id = msg.from.as_ref().map(|u| u.id);
@@ -455,6 +2042,34 @@ pub async fn command_handler(
                 }
             }
         }
+        Command::AutoThreads(mode) => {
+            let auto_enable = match mode.trim().to_lowercase().as_str() {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    bot.send_message(msg.chat.id, "Usage: /autothreads on|off")
+                        .await?;
+                    return Ok(());
+                }
+            };
+            let reply = if auto_enable {
+                "New topics follow the chat's /enable setting"
+            } else {
+                "New topics stay silent until enabled with /enable"
+            };
+            if let Some(user) = msg.from {
+                if !msg.chat.is_private() && is_admin(&bot, msg.chat.id, user.id).await {
+                    bot.delete_message(msg.chat.id, msg.id).await?;
+                    storage
+                        .set_auto_enable_threads(msg.chat.id.0, auto_enable)
+                        .await;
+                    confirm_silent(&bot, msg.chat.id, reply).await?;
+                } else if msg.chat.is_private() {
+                    bot.send_message(msg.chat.id, "Topics only exist in forum groups")
+                        .await?;
+                }
+            }
+        }
     };
 
     Ok(())
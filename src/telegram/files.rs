@@ -0,0 +1,39 @@
+//! File Download Module
+//!
+//! Fetches documents users send to the bot, with a size cap so a large
+//! upload can't exhaust memory.
+
+use teloxide::{Bot, net::Download, prelude::*, types::Document};
+
+use crate::{CONFIG, Error, system};
+
+/// Largest document the bot downloads, from `max_download_bytes`
+fn max_download_bytes() -> u32 {
+    CONFIG.get("max_download_bytes").unwrap_or(1024 * 1024)
+}
+
+/// Downloads a document into memory
+///
+/// # Errors
+/// When the document exceeds `max_download_bytes` or the download fails
+pub async fn download_document(bot: &Bot, document: &Document) -> Result<Vec<u8>, Error> {
+    let limit = max_download_bytes();
+    if document.file.size > limit {
+        return Err(format!("File is too large (limit is {} KB)", limit / 1024).into());
+    }
+
+    let file = bot.get_file(document.file.id.clone()).await?;
+    let mut bytes = Vec::with_capacity(file.size as usize);
+    bot.download_file(&file.path, &mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Downloads a document and decodes it as text
+///
+/// # Errors
+/// As `download_document`, plus "Unsupported file type" for binary files
+pub async fn download_text(bot: &Bot, document: &Document) -> Result<String, Error> {
+    let bytes = download_document(bot, document).await?;
+    let mime = document.mime_type.as_ref().map(|mime| mime.essence_str());
+    system::bytes_to_text(&bytes, mime)
+}
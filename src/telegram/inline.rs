@@ -1,5 +1,339 @@
-use teloxide::prelude::ResponseResult;
+//! Inline Mode Module
+//!
+//! Answers `@bot <question>` inline queries with `inline_mode` on. The
+//! answer is generated while the query is open and offered as a result the
+//! user can post in any chat. Telegram caps that message at 4096 characters,
+//! so a longer answer is cut with a "…(full answer sent)" note; once the user
+//! picks the result (inline feedback must be enabled with @BotFather), the
+//! full answer follows in their private chat with the bot.
+//!
+//! Telegram sends a new query for every keystroke, so a query is only
+//! answered once the user stopped typing for `inline_debounce_ms`. It then
+//! counts against the user's rate limits like a private message.
 
-pub async fn inline_handler() -> ResponseResult<()> {
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use teloxide::{
+    prelude::*,
+    types::{
+        ChosenInlineResult, InlineQuery, InlineQueryResult, InlineQueryResultArticle,
+        InputMessageContent, InputMessageContentText,
+    },
+};
+use tracing::{Level, event};
+
+use super::{limiter, quota, rolling::TELEGRAM_LIMIT};
+use crate::{CONFIG, lm_types::Message, storage::Storage, system};
+
+/// Appended to an answer cut for the inline message
+const TRUNCATION_NOTE: &str = "…(full answer sent)";
+
+/// Replaces `TRUNCATION_NOTE` when the full answer could not be sent
+const UNDELIVERED_NOTE: &str = "…(start a private chat with the bot to get the full answer)";
+
+/// Most results Telegram accepts for one inline query
+const TELEGRAM_MAX_RESULTS: usize = 50;
+
+/// Characters of the answer shown under a result's title
+const DESCRIPTION_CHARS: usize = 100;
+
+/// How long a cut answer waits for its result to be picked
+const PENDING_TTL: Duration = Duration::from_secs(600);
+
+/// Full texts of cut results by result id, until the result is picked
+static PENDING: Lazy<DashMap<String, (Instant, String)>> = Lazy::new(DashMap::new);
+
+/// Makes result ids unique across queries
+static NEXT_BATCH: AtomicU64 = AtomicU64::new(0);
+
+/// Batch of each user's latest query, while it waits or is being answered
+static LATEST: Lazy<DashMap<UserId, u64>> = Lazy::new(DashMap::new);
+
+/// How long a query must stay unchanged before it is answered
+fn debounce() -> Duration {
+    Duration::from_millis(CONFIG.get::<u64>("inline_debounce_ms").unwrap_or(700))
+}
+
+/// Records a new query of the user, superseding the ones before it
+fn start_query(user_id: UserId) -> u64 {
+    let batch = NEXT_BATCH.fetch_add(1, Ordering::Relaxed);
+    LATEST.insert(user_id, batch);
+    batch
+}
+
+/// Whether `batch` is still the user's latest query
+fn is_latest(user_id: UserId, batch: u64) -> bool {
+    LATEST.get(&user_id).map(|latest| *latest) == Some(batch)
+}
+
+/// Cuts an answer to fit one message together with the truncation note
+///
+/// Lengths are counted in UTF-16 code units, as Telegram does; characters
+/// are never split.
+///
+/// # Returns
+/// `None` when the answer fits as it is
+pub fn truncate_for_inline(answer: &str, limit: usize) -> Option<String> {
+    truncate_with_note(answer, limit, TRUNCATION_NOTE)
+}
+
+fn truncate_with_note(answer: &str, limit: usize, note: &str) -> Option<String> {
+    if answer.encode_utf16().count() <= limit {
+        return None;
+    }
+    let budget = limit.saturating_sub(note.encode_utf16().count());
+    let mut units = 0;
+    let cut: String = answer
+        .chars()
+        .take_while(|c| {
+            units += c.len_utf16();
+            units <= budget
+        })
+        .collect();
+    Some(format!("{}{}", cut.trim_end(), note))
+}
+
+/// Messages that deliver the full answer after its cut inline message
+pub fn follow_up_messages(answer: &str) -> Vec<String> {
+    system::split_into_chunks(answer, None)
+}
+
+/// Results Telegram may get for one query, from `inline_max_results`
+fn max_results() -> usize {
+    CONFIG
+        .get::<usize>("inline_max_results")
+        .unwrap_or(TELEGRAM_MAX_RESULTS)
+        .clamp(1, TELEGRAM_MAX_RESULTS)
+}
+
+/// An article result posting `text`, described by the start of the answer
+fn article(id: String, title: &str, answer: &str, text: String) -> InlineQueryResult {
+    let description: String = answer.chars().take(DESCRIPTION_CHARS).collect();
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::new(
+            id,
+            title,
+            InputMessageContent::Text(InputMessageContentText::new(text)),
+        )
+        .description(description),
+    )
+}
+
+/// Results offered for an answer: the answer alone, and quoted with its question
+///
+/// # Returns
+/// At most `max` results with their id and, for those that had to be cut,
+/// their full text
+fn answer_results(
+    batch: u64,
+    question: &str,
+    answer: &str,
+    max: usize,
+) -> Vec<(String, InlineQueryResult, Option<String>)> {
+    let variants = [
+        ("💬 Answer", answer.to_string()),
+        (
+            "❓ Question and answer",
+            format!("❓ {}\n\n{}", question, answer),
+        ),
+    ];
+    variants
+        .into_iter()
+        .enumerate()
+        .take(max)
+        .map(|(index, (title, text))| {
+            let id = format!("{}:{}", batch, index);
+            match truncate_for_inline(&text, TELEGRAM_LIMIT) {
+                Some(cut) => (id.clone(), article(id, title, answer, cut), Some(text)),
+                None => (id.clone(), article(id, title, answer, text), None),
+            }
+        })
+        .collect()
+}
+
+/// Answers an inline query with the model's answer to it
+///
+/// Does nothing but acknowledge the query unless `inline_mode` is on.
+/// Queries superseded while waiting are dropped; refused and failed ones
+/// get no results.
+pub async fn inline_handler(
+    bot: Bot,
+    q: InlineQuery,
+    storage: Arc<dyn Storage>,
+) -> ResponseResult<()> {
+    let question = q.query.trim();
+    if !CONFIG.get_bool("inline_mode").unwrap_or(false) || question.is_empty() {
+        bot.answer_inline_query(q.id, Vec::<InlineQueryResult>::new())
+            .await?;
+        return Ok(());
+    }
+
+    let user_id = q.from.id;
+    let batch = start_query(user_id);
+    tokio::time::sleep(debounce()).await;
+    if !is_latest(user_id, batch) {
+        return Ok(());
+    }
+    let answer = inline_answer(user_id, question, &storage).await;
+    LATEST.remove_if(&user_id, |_, latest| *latest == batch);
+    let Some(answer) = answer else {
+        bot.answer_inline_query(q.id, Vec::<InlineQueryResult>::new())
+            .await?;
+        return Ok(());
+    };
+
+    PENDING.retain(|_, (created, _)| created.elapsed() < PENDING_TTL);
+    let mut results = Vec::new();
+    for (id, result, full_text) in answer_results(batch, question, &answer, max_results()) {
+        if let Some(full_text) = full_text {
+            PENDING.insert(id, (Instant::now(), full_text));
+        }
+        results.push(result);
+    }
+    bot.answer_inline_query(q.id, results).await?;
+    Ok(())
+}
+
+/// The model's answer to an inline question
+///
+/// The asker's private chat settings pick the model and temperature, and
+/// its limits apply.
+///
+/// # Returns
+/// `None` when the request is refused or fails; errors are only logged, they
+/// are no results to post
+async fn inline_answer(
+    user_id: UserId,
+    question: &str,
+    storage: &Arc<dyn Storage>,
+) -> Option<String> {
+    let chat_id = ChatId(user_id.0 as i64);
+    if let Err(blocked) = quota::try_acquire(user_id, chat_id) {
+        event!(
+            Level::DEBUG,
+            "Inline query of {} refused: {:?}",
+            user_id,
+            blocked
+        );
+        return None;
+    }
+    let model = storage.get_model(chat_id.0).await?;
+    let temperature = storage.get_temperature(chat_id.0, &model).await;
+    let messages = [Message {
+        role: "user".to_string(),
+        content: question.to_string(),
+        reasoning: None,
+    }];
+    // Held while the model answers; waits when `max_concurrent_requests` is reached
+    let _slot = limiter::acquire_slot(chat_id).await;
+    match system::complete_with(chat_id.0, storage, &model, &messages, temperature).await {
+        Ok(answer) => Some(answer),
+        Err(e) => {
+            event!(Level::WARN, "Inline answer for {} failed: {}", user_id, e);
+            None
+        }
+    }
+}
+
+/// Sends the full answer of a picked result that was cut
+///
+/// The answer goes to the user's private chat, split into as many messages
+/// as it takes. When the bot can't write there (the user never started it),
+/// the note of the inline message is corrected, if Telegram told us which
+/// message it is.
+pub async fn chosen_inline_result_handler(
+    bot: Bot,
+    chosen: ChosenInlineResult,
+) -> ResponseResult<()> {
+    let Some((_, (_, full_text))) = PENDING.remove(&chosen.result_id) else {
+        return Ok(());
+    };
+    for message in follow_up_messages(&full_text) {
+        if let Err(e) = bot.send_message(chosen.from.id, message).await {
+            event!(
+                Level::WARN,
+                "Failed to send full inline answer to {}: {}",
+                chosen.from.id,
+                e
+            );
+            if let Some(inline_message_id) = chosen.inline_message_id {
+                let text = truncate_with_note(&full_text, TELEGRAM_LIMIT, UNDELIVERED_NOTE)
+                    .unwrap_or(full_text);
+                let _ = bot.edit_message_text_inline(inline_message_id, text).await;
+            }
+            break;
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answer_is_cut_only_past_the_limit() {
+        let fits = "a".repeat(TELEGRAM_LIMIT);
+        assert_eq!(truncate_for_inline(&fits, TELEGRAM_LIMIT), None);
+
+        let long = "a".repeat(TELEGRAM_LIMIT + 1);
+        let cut = truncate_for_inline(&long, TELEGRAM_LIMIT).unwrap();
+        assert!(cut.ends_with(TRUNCATION_NOTE));
+        assert_eq!(cut.encode_utf16().count(), TELEGRAM_LIMIT);
+
+        // Emoji take two UTF-16 units and are never split
+        let emoji = "😀".repeat(TELEGRAM_LIMIT);
+        let cut = truncate_for_inline(&emoji, TELEGRAM_LIMIT).unwrap();
+        assert!(cut.encode_utf16().count() <= TELEGRAM_LIMIT);
+        assert!(
+            cut.trim_end_matches(TRUNCATION_NOTE)
+                .chars()
+                .all(|c| c == '😀')
+        );
+    }
+
+    #[test]
+    fn test_full_answer_follows_in_several_messages() {
+        let answer = "word ".repeat(2000);
+        let messages = follow_up_messages(&answer);
+        assert_eq!(messages.len(), 3);
+        assert!(
+            messages
+                .iter()
+                .all(|message| message.encode_utf16().count() <= TELEGRAM_LIMIT)
+        );
+        assert_eq!(messages.concat(), answer);
+    }
+
+    #[test]
+    fn test_results_are_capped() {
+        let long = "a".repeat(TELEGRAM_LIMIT + 1);
+        let results = answer_results(7, "Why?", &long, 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, "7:0");
+        assert_eq!(results[0].2.as_deref(), Some(long.as_str()));
+
+        let results = answer_results(7, "Why?", "Because.", TELEGRAM_MAX_RESULTS);
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|(_, _, full_text)| full_text.is_none()));
+    }
+
+    #[test]
+    fn test_only_the_latest_query_is_answered() {
+        let user = UserId(7);
+        let first = start_query(user);
+        let second = start_query(user);
+        assert!(!is_latest(user, first));
+        assert!(is_latest(user, second));
+        // Another user's queries don't supersede this one
+        start_query(UserId(8));
+        assert!(is_latest(user, second));
+    }
+}
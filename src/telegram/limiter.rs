@@ -0,0 +1,189 @@
+//! Request Limiter Module
+//!
+//! Caps the number of AI requests running at once across all chats
+//! (`max_concurrent_requests`). Waiting requests are served by priority,
+//! so DMs can be configured to get a slot before busy groups.
+
+use once_cell::sync::Lazy;
+use std::{collections::VecDeque, str::FromStr, sync::Mutex};
+use teloxide::types::{ChatId, UserId};
+use tokio::sync::oneshot;
+
+use crate::{CONFIG, telegram::admin::is_owner};
+
+/// Scheduling priority of a chat's requests, highest first
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Resolves a chat's priority from the `priority` map
+///
+/// Keys: `owner` (private chats of bot owners), `private` and `group`.
+pub fn priority_for(chat_id: ChatId) -> Priority {
+    let lookup = |key: &str| {
+        CONFIG
+            .get_string(&format!("priority.{}", key))
+            .ok()
+            .and_then(|value| value.parse().ok())
+    };
+
+    if chat_id.is_user() {
+        if is_owner(UserId(chat_id.0 as u64)) {
+            if let Some(priority) = lookup("owner") {
+                return priority;
+            }
+        }
+        lookup("private").unwrap_or_default()
+    } else {
+        lookup("group").unwrap_or_default()
+    }
+}
+
+struct State {
+    available: usize,
+    waiting: [VecDeque<oneshot::Sender<Permit>>; 3],
+}
+
+/// Counting semaphore whose waiters are served highest priority first
+pub struct PriorityLimiter {
+    state: Mutex<State>,
+}
+
+/// A running slot; dropping it hands the slot to the next waiter
+pub struct Permit(&'static PriorityLimiter);
+
+impl PriorityLimiter {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                available: permits,
+                waiting: Default::default(),
+            }),
+        }
+    }
+
+    /// Waits for a free slot
+    pub async fn acquire(&'static self, priority: Priority) -> Permit {
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                return Permit(self);
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting[priority as usize].push_back(tx);
+            rx
+        };
+        // The sender is only dropped together with the limiter
+        slot.await.expect("limiter outlives its waiters")
+    }
+
+    /// Number of waiting requests per priority, highest first
+    pub fn queue_depths(&self) -> [usize; 3] {
+        let state = self.state.lock().unwrap();
+        [
+            state.waiting[0].len(),
+            state.waiting[1].len(),
+            state.waiting[2].len(),
+        ]
+    }
+
+    fn release(&'static self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let Some(waiter) = state.waiting.iter_mut().find_map(|queue| queue.pop_front()) else {
+                state.available += 1;
+                return;
+            };
+            // A waiter that gave up hands the permit back; forget it instead
+            // of dropping, which would re-enter `release` under the lock
+            match waiter.send(Permit(self)) {
+                Ok(()) => return,
+                Err(permit) => std::mem::forget(permit),
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Shared limiter, `None` when `max_concurrent_requests` is 0 (unlimited)
+static LIMITER: Lazy<Option<PriorityLimiter>> = Lazy::new(|| {
+    let permits = CONFIG.get::<usize>("max_concurrent_requests").unwrap_or(0);
+    (permits > 0).then(|| PriorityLimiter::new(permits))
+});
+
+/// Waits for a request slot for the chat
+///
+/// # Returns
+/// A permit to keep while the request runs; `None` when unlimited
+pub async fn acquire_slot(chat_id: ChatId) -> Option<Permit> {
+    match LIMITER.as_ref() {
+        Some(limiter) => Some(limiter.acquire(priority_for(chat_id)).await),
+        None => None,
+    }
+}
+
+/// Waiting requests per priority, `None` when unlimited
+pub fn queue_depths() -> Option<[usize; 3]> {
+    LIMITER.as_ref().map(PriorityLimiter::queue_depths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_high_priority_waiter_goes_first() {
+        let limiter: &'static PriorityLimiter = Box::leak(Box::new(PriorityLimiter::new(1)));
+        let running = limiter.acquire(Priority::Normal).await;
+
+        let low = tokio::spawn(limiter.acquire(Priority::Low));
+        tokio::task::yield_now().await;
+        let high = tokio::spawn(limiter.acquire(Priority::High));
+        tokio::task::yield_now().await;
+        assert_eq!(limiter.queue_depths(), [1, 0, 1]);
+
+        drop(running);
+        let high = high.await.unwrap();
+        assert_eq!(limiter.queue_depths(), [0, 0, 1]);
+        assert!(!low.is_finished());
+
+        drop(high);
+        low.await.unwrap();
+        assert_eq!(limiter.queue_depths(), [0, 0, 0]);
+    }
+}
@@ -2,16 +2,28 @@
 //!
 //! This module implements the telegram bot command handling functionality.
 //! It processes user commands and manages interactions with the Llama AI model.
-use crate::{storage::Storage, telegram::ai_request::handle_ai_request};
-use dashmap::DashSet;
+use crate::{
+    CONFIG,
+    reply_chain::{self, REPLY_CHAINS},
+    storage::{ContextScope, Storage},
+    telegram::{
+        admin::is_banned_sender,
+        ai_request::{AiRequestError, handle_ai_request},
+        busy::BusyChats,
+        quota,
+    },
+};
+use chrono::{DateTime, Utc};
 use log::info;
-use std::sync::Arc;
+use std::{ops::Range, sync::Arc};
 use teloxide::{
-    prelude::*, types::{ChatKind, False, Message}, Bot
+    Bot,
+    prelude::*,
+    types::{False, Me, Message, MessageEntityKind, ThreadId},
 };
-use tracing::warn;
+use tracing::{debug, warn};
 
-pub type BusySet = Arc<DashSet<i64>>;
+pub type BusySet = Arc<BusyChats>;
 
 /// Message handler
 /// Alternative of /chat command
@@ -30,40 +42,76 @@ pub async fn message_handler(
     busy: BusySet,
     storage: Arc<dyn Storage>,
     bot_id: UserId,
+    me: Me,
 ) -> ResponseResult<()> {
+    if is_stale_update(&msg) {
+        return Ok(());
+    }
+    if is_own_message(&msg, bot_id) && CONFIG.get_bool("ignore_own_messages").unwrap_or(true) {
+        debug!("Ignoring own message in chat {}", msg.chat.id);
+        return Ok(());
+    }
     if let Some(user) = &msg.from {
         let chat_id = msg.chat.id;
-        let thread_id = msg.thread_id;
-        
-        
-        // Обработка разных типов чатов
-        let enabled = match msg.chat.clone().kind {
-            ChatKind::Private(_) => {},
-            ChatKind::Public(chat_public) => match chat_public.kind {
-                teloxide::types::PublicChatKind::Channel(public_chat_channel) => {return Ok(());},
-                teloxide::types::PublicChatKind::Group => {},
-                teloxide::types::PublicChatKind::Supergroup(public_chat_supergroup) => match public_chat_supergroup.is_forum {
-                    true => {},
-                    false => {},
-                },
-            },
-        };
-
+        if msg.chat.is_channel() {
+            return Ok(());
+        }
 
-        if !msg.chat.is_private() {
-            if !msg
+        let mentions = bot_mentions(&msg, &me);
+        let trigger = if msg.chat.is_private() {
+            Trigger::Always
+        } else {
+            let group_trigger = GroupTrigger::from_config();
+            let is_reply = msg
                 .reply_to_message()
-                .is_some_and(|reply| reply.from.as_ref().is_some_and(|u| u.id == bot_id))
-            {
+                .is_some_and(|reply| reply.from.as_ref().is_some_and(|u| u.id == bot_id));
+            if is_reply && group_trigger != GroupTrigger::Mention {
+                Trigger::Reply
+            } else if !mentions.is_empty() && group_trigger != GroupTrigger::Reply {
+                Trigger::Mention
+            } else {
                 return Ok(());
             }
+        };
+
+        if msg.forward_origin().is_some() && ignores_forwarded(trigger) {
+            debug!("Ignoring forwarded message in chat {}", chat_id);
+            return Ok(());
+        }
+
+        if !is_chat_enabled(&msg, &storage).await {
+            debug!("Ignoring message in disabled chat {}", chat_id);
+            hint_disabled(&bot, &msg, &storage).await?;
+            return Ok(());
+        }
+        // Only messages the bot would answer cost a lookup (and get the notice)
+        if is_banned_sender(&bot, &msg, &storage).await {
+            return Ok(());
         }
 
-        let Some(text) = &msg.text() else {
+        // Stickers, GIFs, polls etc. have no text; media may carry a caption
+        let Some(text) = prompt_text(&msg) else {
+            debug!("Ignoring non-text message in chat {}", chat_id);
             return Ok(());
         };
+        // "@bot what's 2+2" asks "what's 2+2"
+        let text = strip_mentions(text, &mentions);
+        if text.is_empty() {
+            debug!("Ignoring bare mention in chat {}", chat_id);
+            return Ok(());
+        }
+
+        if let Err(blocked) = quota::try_acquire(user.id, chat_id) {
+            debug!(
+                "Request of user {} in chat {} refused: {:?}",
+                user.id, chat_id, blocked
+            );
+            bot.send_message(chat_id, blocked.to_string()).await?;
+            return Ok(());
+        }
 
         let message_id = msg.id;
+        let user_id = Some(user.id);
         let text = format!(
             "{{Username: {} (@{}), DateTime: {}, Message: {}}}",
             user.full_name(),
@@ -72,49 +120,339 @@ pub async fn message_handler(
             text
         );
 
+        // In groups, a reply to the bot is answered from its reply chain
+        let scope = if !msg.chat.is_private() && reply_chain::max_depth() > 0 {
+            let parent = msg.reply_to_message().map(|reply| {
+                // Answers from before a restart are only known by their text
+                if let Some(answer) = reply.text().filter(|_| trigger == Trigger::Reply) {
+                    REPLY_CHAINS.record(chat_id.0, reply.id, None, "assistant", answer);
+                }
+                reply.id
+            });
+            REPLY_CHAINS.record(chat_id.0, message_id, parent, "user", &text);
+            match trigger {
+                Trigger::Reply => ContextScope::Reply(message_id),
+                _ => ContextScope::Main,
+            }
+        } else {
+            ContextScope::Main
+        };
+
         // Clone necessary resources for async task
         let bot_clone = bot.clone();
         let storage_clone = storage.clone();
         let busy_clone = busy.clone();
 
+        let thread = topic_thread(&msg);
+        // A request turned away because the chat is busy doesn't use up quota
         if !msg.chat.is_private() {
-            handle_ai_request(
+            if let Err(AiRequestError::ChatBusy) = handle_ai_request(
                 bot_clone,
                 chat_id,
+                thread,
                 message_id,
+                user_id,
                 text,
+                scope,
                 storage_clone,
                 busy_clone,
             )
-            .await;
+            .await
+            {
+                if let Some(user_id) = user_id {
+                    quota::refund(user_id, chat_id);
+                }
+            }
         } else {
             tokio::spawn(async move {
-                handle_ai_request(
+                if let Err(AiRequestError::ChatBusy) = handle_ai_request(
                     bot_clone,
                     chat_id,
+                    thread,
                     message_id,
+                    user_id,
                     text,
+                    scope,
                     storage_clone,
                     busy_clone,
                 )
-                .await;
+                .await
+                {
+                    if let Some(user_id) = user_id {
+                        quota::refund(user_id, chat_id);
+                    }
+                }
             });
         }
     }
     Ok(())
 }
 
+/// How a message came to be addressed to the bot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// Every message is answered (private chats)
+    Always,
+    /// The message replies to the bot
+    Reply,
+    /// The message mentions the bot's @username
+    Mention,
+}
+
+impl Trigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::Always => "always",
+            Trigger::Reply => "reply",
+            Trigger::Mention => "mention",
+        }
+    }
+}
+
+/// Which group messages the bot answers, from `group_trigger`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupTrigger {
+    /// Replies to the bot's messages
+    Reply,
+    /// Messages mentioning the bot anywhere in the text
+    Mention,
+    /// Either of the above
+    Any,
+}
+
+impl GroupTrigger {
+    /// Reads `group_trigger` from configuration, defaulting to `reply`
+    pub fn from_config() -> Self {
+        match CONFIG
+            .get_string("group_trigger")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "mention" => Self::Mention,
+            "any" => Self::Any,
+            _ => Self::Reply,
+        }
+    }
+}
+
+/// Whether an entity of a message refers to this bot
+fn is_bot_mention(kind: &MessageEntityKind, text: &str, username: &str, bot_id: UserId) -> bool {
+    match kind {
+        MessageEntityKind::Mention => text
+            .strip_prefix('@')
+            .is_some_and(|name| name.eq_ignore_ascii_case(username)),
+        MessageEntityKind::TextMention { user } => user.id == bot_id,
+        _ => false,
+    }
+}
+
+/// Byte ranges of the prompt text where the bot is mentioned
+fn bot_mentions(msg: &Message, me: &Me) -> Vec<Range<usize>> {
+    let entities = if msg.text().is_some() {
+        msg.parse_entities()
+    } else {
+        msg.parse_caption_entities()
+    };
+    entities
+        .unwrap_or_default()
+        .iter()
+        .filter(|entity| is_bot_mention(entity.kind(), entity.text(), me.username(), me.id))
+        .map(|entity| entity.start()..entity.end())
+        .collect()
+}
+
+/// Removes the mentions at `spans` (sorted byte ranges) from `text`
+///
+/// A comma or colon right after a mention goes with it ("@bot, hi" is
+/// "hi") and the words around it are joined by a single space.
+pub fn strip_mentions(text: &str, spans: &[Range<usize>]) -> String {
+    let mut result = String::new();
+    let mut rest_start = 0;
+    for span in spans {
+        result.push_str(&text[rest_start..span.start]);
+        let after = &text[span.end..];
+        let after = after.strip_prefix([',', ':']).unwrap_or(after);
+        rest_start = text.len() - after.len();
+
+        let kept = result.trim_end().len();
+        result.truncate(kept);
+        let next = &text[rest_start..];
+        let trimmed = next.trim_start();
+        rest_start += next.len() - trimmed.len();
+        if !result.is_empty() && !trimmed.is_empty() {
+            result.push(' ');
+        }
+    }
+    result.push_str(&text[rest_start..]);
+    result.trim().to_string()
+}
+
+/// Whether forwarded messages are skipped for the given trigger
+///
+/// `ignore_forwarded` is either a flag for every trigger or a table such as
+/// `{ always=true, reply=false }`; missing triggers default to false.
+fn ignores_forwarded(trigger: Trigger) -> bool {
+    CONFIG.get_bool("ignore_forwarded").unwrap_or_else(|_| {
+        CONFIG
+            .get_bool(&format!("ignore_forwarded.{}", trigger.as_str()))
+            .unwrap_or(false)
+    })
+}
+
+/// Whether a message sent at `date` is older than `max_age_secs` at `now`
+///
+/// A limit of 0 accepts messages of any age.
+pub fn is_older_than(date: DateTime<Utc>, now: DateTime<Utc>, max_age_secs: u64) -> bool {
+    max_age_secs > 0 && (now - date).num_seconds() > max_age_secs as i64
+}
+
+/// Whether a message should be skipped as left over from downtime
+///
+/// After a restart Telegram delivers the updates the bot missed; messages
+/// older than `max_update_age_secs` are not answered anymore.
+pub fn is_stale_update(msg: &Message) -> bool {
+    let max_age = CONFIG.get::<u64>("max_update_age_secs").unwrap_or(0);
+    let stale = is_older_than(msg.date, Utc::now(), max_age);
+    if stale {
+        info!(
+            "Skipping message {} in chat {} sent at {}",
+            msg.id, msg.chat.id, msg.date
+        );
+    }
+    stale
+}
+
+/// One-time answer to a message the bot ignores because it is disabled
+const DISABLED_HINT: &str =
+    "👋 I'm not enabled in this chat yet. An administrator can turn me on with /enable.";
+
+/// Tells a chat how to enable the bot, once, if `first_disabled_hint` is on
+///
+/// For messages addressed to the bot in a group it ignores because of
+/// `groups_enabled_by_default = false`, while no admin has used `/enable`
+/// or `/disable` there. A chat or topic disabled on purpose stays quiet.
+/// Whether the hint was given is stored per chat, so it survives restarts
+/// with a database backend.
+async fn hint_disabled(
+    bot: &Bot,
+    msg: &Message,
+    storage: &Arc<dyn Storage>,
+) -> ResponseResult<()> {
+    if !CONFIG.get_bool("first_disabled_hint").unwrap_or(false)
+        || storage.has_chat_settings(msg.chat.id.0).await
+        || !storage.mark_disabled_hinted(msg.chat.id.0).await
+    {
+        return Ok(());
+    }
+    info!("Telling chat {} how to enable the bot", msg.chat.id);
+    let mut hint = bot.send_message(msg.chat.id, DISABLED_HINT);
+    if let Some(thread_id) = topic_thread(msg) {
+        hint = hint.message_thread_id(thread_id);
+    }
+    hint.await?;
+    Ok(())
+}
+
+/// Whether the bot answers in the chat, and forum topic, of a message
+///
+/// Private chats always are. Groups follow `/enable` and `/disable`, or
+/// `groups_enabled_by_default` before either is used; in forums the topic's
+/// setting decides, see `ChatSettings::thread_enabled`.
+async fn is_chat_enabled(msg: &Message, storage: &Arc<dyn Storage>) -> bool {
+    msg.chat.is_private()
+        || storage
+            .is_enabled(msg.chat.id.0, topic_thread(msg), msg.chat.is_supergroup())
+            .await
+}
+
+/// Forum topic of a message, `None` outside forum topics
+///
+/// Replies in regular supergroups carry a thread id too; it must not be
+/// passed on where Telegram expects a topic.
+pub fn topic_thread(msg: &Message) -> Option<ThreadId> {
+    msg.thread_id.filter(|_| msg.is_topic_message)
+}
+
+/// Extracts the text that should be sent to the model
+///
+/// Plain text always qualifies. Captions of photos, videos and documents are
+/// used only when `respond_to_captions` is enabled; everything else is ignored.
+fn prompt_text(msg: &Message) -> Option<&str> {
+    msg.text().or_else(|| {
+        if CONFIG.get_bool("respond_to_captions").unwrap_or(false) {
+            msg.caption()
+        } else {
+            None
+        }
+    })
+}
+
+/// Fallback behaviour for commands the bot does not recognise
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnknownCommandMode {
+    /// Always answer with an "Invalid command" hint
+    Reply,
+    /// Never answer
+    Silent,
+    /// Answer only in private chats
+    SilentInGroups,
+}
+
+impl UnknownCommandMode {
+    /// Reads `unknown_command_mode` from configuration, defaulting to `reply`
+    pub fn from_config() -> Self {
+        match CONFIG
+            .get_string("unknown_command_mode")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "silent" => Self::Silent,
+            "silent_in_groups" => Self::SilentInGroups,
+            _ => Self::Reply,
+        }
+    }
+}
+
+/// Returns the bot username a command is addressed to (`/cmd@botname`), if any
+pub fn command_target(text: &str) -> Option<&str> {
+    let command = text.split_whitespace().next()?.strip_prefix('/')?;
+    command.split_once('@').map(|(_, target)| target)
+}
+
 /// Invalid command handler
 ///
-/// Responds to unrecognized bot commands
+/// Responds to unrecognized bot commands according to `unknown_command_mode`.
+/// Commands addressed to other bots are ignored entirely.
 ///
 /// # Arguments
 /// * `bot` - Telegram Bot instance
 /// * `msg` - Message containing the invalid command
+/// * `me` - Information about this bot, used to match `@username` mentions
 ///
 /// # Returns
 /// * `ResponseResult<()>` - Result of sending the error message
-pub async fn invalid(bot: Bot, msg: Message) -> ResponseResult<()> {
+pub async fn invalid(bot: Bot, msg: Message, me: Me) -> ResponseResult<()> {
+    if let Some(target) = msg.text().and_then(command_target) {
+        if !me
+            .username
+            .as_deref()
+            .is_some_and(|name| name.eq_ignore_ascii_case(target))
+        {
+            return Ok(());
+        }
+    }
+
+    let reply = match UnknownCommandMode::from_config() {
+        UnknownCommandMode::Reply => true,
+        UnknownCommandMode::Silent => false,
+        UnknownCommandMode::SilentInGroups => msg.chat.is_private(),
+    };
+    if !reply {
+        return Ok(());
+    }
+
     warn!("Invalid command received from chat {}", msg.chat.id);
     bot.send_message(
         msg.chat.id,
@@ -123,3 +461,172 @@ pub async fn invalid(bot: Bot, msg: Message) -> ResponseResult<()> {
     .await?;
     Ok(())
 }
+
+/// Whether the bot itself sent the message or is its forward origin
+///
+/// Such messages would otherwise be stored as user turns, e.g. when another
+/// bot forwards the answers back to the chat.
+fn is_own_message(msg: &Message, bot_id: UserId) -> bool {
+    msg.from.as_ref().is_some_and(|user| user.id == bot_id)
+        || msg
+            .forward_from_user()
+            .is_some_and(|user| user.id == bot_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_older_than_threshold() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let date = now - chrono::Duration::seconds(120);
+
+        assert!(is_older_than(date, now, 60));
+        assert!(!is_older_than(date, now, 120));
+        assert!(!is_older_than(date, now, 300));
+        // 0 disables the check
+        assert!(!is_older_than(date, now, 0));
+    }
+
+    #[test]
+    fn test_strip_mentions() {
+        // Leading, with punctuation
+        let text = "@mybot, what's 2+2";
+        assert_eq!(strip_mentions(text, &[0..6]), "what's 2+2");
+        // Middle and trailing
+        let text = "so @mybot what's 2+2 @mybot";
+        assert_eq!(strip_mentions(text, &[3..9, 21..27]), "so what's 2+2");
+        // Only a mention
+        assert_eq!(strip_mentions("@mybot", &[0..6]), "");
+        // Multibyte text around the mention
+        let text = "привет @mybot как дела";
+        let start = text.find('@').unwrap();
+        assert_eq!(strip_mentions(text, &[start..start + 6]), "привет как дела");
+    }
+
+    #[test]
+    fn test_only_own_mentions_count() {
+        let bot_id = UserId(42);
+        let mention = MessageEntityKind::Mention;
+        assert!(is_bot_mention(&mention, "@MyBot", "mybot", bot_id));
+        assert!(!is_bot_mention(&mention, "@alice", "mybot", bot_id));
+        assert!(!is_bot_mention(&mention, "@mybot_fan", "mybot", bot_id));
+        assert!(!is_bot_mention(
+            &MessageEntityKind::Bold,
+            "@mybot",
+            "mybot",
+            bot_id
+        ));
+
+        // Another user's mention stays in the prompt
+        let text = "@mybot ask @alice about it";
+        assert_eq!(strip_mentions(text, &[0..6]), "ask @alice about it");
+    }
+
+    fn message_from(user_id: u64) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": Utc::now().timestamp(),
+            "chat": {"id": 7, "type": "private", "first_name": "Ann"},
+            "from": {"id": user_id, "is_bot": user_id == 42, "first_name": "Ann"},
+            "text": "hello"
+        }))
+        .unwrap()
+    }
+
+    fn bot_me() -> Me {
+        serde_json::from_value(serde_json::json!({
+            "id": 42,
+            "is_bot": true,
+            "first_name": "Bot",
+            "username": "mybot",
+            "can_join_groups": true,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false,
+            "can_connect_to_business": false,
+            "has_main_web_app": false
+        }))
+        .unwrap()
+    }
+
+    /// A reply to the bot in topic `topic` of a forum supergroup
+    fn forum_reply(topic: i32) -> Message {
+        let chat = serde_json::json!({
+            "id": -1007,
+            "type": "supergroup",
+            "title": "Forum",
+            "is_forum": true
+        });
+        serde_json::from_value(serde_json::json!({
+            "message_id": 10,
+            "message_thread_id": topic,
+            "is_topic_message": true,
+            "date": Utc::now().timestamp(),
+            "chat": chat,
+            "from": {"id": 7, "is_bot": false, "first_name": "Ann"},
+            "reply_to_message": {
+                "message_id": 9,
+                "message_thread_id": topic,
+                "is_topic_message": true,
+                "date": Utc::now().timestamp(),
+                "chat": chat,
+                "from": {"id": 42, "is_bot": true, "first_name": "Bot"},
+                "text": "Earlier answer"
+            },
+            "text": "And then?"
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_own_messages_are_dropped_before_storage() {
+        let me = bot_me();
+        let bot_id = me.user.id;
+        assert!(!is_own_message(&message_from(7), bot_id));
+
+        let msg = message_from(42);
+        assert!(is_own_message(&msg, bot_id));
+        let storage = crate::storage::memory_storage();
+        // Returns before any request, so the token is never used
+        message_handler(
+            Bot::new("0:test"),
+            msg,
+            BusySet::default(),
+            storage.clone(),
+            bot_id,
+            me,
+        )
+        .await
+        .unwrap();
+        assert!(storage.get_conversation_context(7).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_topic_in_enabled_forum_is_ignored() {
+        let storage = crate::storage::memory_storage();
+        storage.enable(-1007, None, true).await;
+        storage.disable(-1007, Some(3), true).await;
+
+        assert!(!is_chat_enabled(&forum_reply(3), &storage).await);
+        assert!(is_chat_enabled(&forum_reply(4), &storage).await);
+        // Private chats are answered whatever their stored setting
+        storage.disable(7, None, false).await;
+        assert!(is_chat_enabled(&message_from(7), &storage).await);
+
+        let me = bot_me();
+        let bot_id = me.user.id;
+        // Returns before any request, so the token is never used
+        message_handler(
+            Bot::new("0:test"),
+            forum_reply(3),
+            BusySet::default(),
+            storage.clone(),
+            bot_id,
+            me,
+        )
+        .await
+        .unwrap();
+        assert!(storage.get_conversation_context(-1007).await.is_empty());
+    }
+}
@@ -3,15 +3,30 @@ use message::{invalid, message_handler};
 use teloxide::{
     dispatching::{HandlerExt, UpdateFilterExt},
     dptree::{self, Handler},
-    types::Update,
+    types::{Message, Update},
 };
 
-use crate::telegram::inline::inline_handler;
+use crate::telegram::{
+    admin::chat_member_handler,
+    inline::{chosen_inline_result_handler, inline_handler},
+    settings::callback_handler,
+};
+
+pub use busy::BusyChats;
+pub use command::{StartedAt, sync_commands};
+pub use message::BusySet;
 
+mod admin;
 mod ai_request;
+mod busy;
 mod command;
+mod files;
 mod inline;
+mod limiter;
 mod message;
+mod quota;
+mod rolling;
+mod settings;
 
 pub fn get_storage_handler()
 -> Handler<'static, Result<(), teloxide::RequestError>, teloxide::dispatching::DpHandlerDescription>
@@ -20,13 +35,24 @@ pub fn get_storage_handler()
         .filter_command::<Command>()
         .endpoint(command_handler);
 
+    // Anything that looks like a command but failed to parse as one of ours
+    let unknown_command_branch = Update::filter_message()
+        .filter(|msg: Message| msg.text().is_some_and(|text| text.starts_with('/')))
+        .endpoint(invalid);
+
     let message_branch = Update::filter_message().endpoint(message_handler);
     let inline_branch = Update::filter_inline_query().endpoint(inline_handler);
-    let fallback = Update::filter_message().endpoint(invalid);
+    let chosen_inline_branch =
+        Update::filter_chosen_inline_result().endpoint(chosen_inline_result_handler);
+    let chat_member_branch = Update::filter_chat_member().endpoint(chat_member_handler);
+    let callback_branch = Update::filter_callback_query().endpoint(callback_handler);
 
     dptree::entry()
         .branch(command_branch)
+        .branch(unknown_command_branch)
         .branch(message_branch)
         .branch(inline_branch)
-        .branch(fallback)
+        .branch(chosen_inline_branch)
+        .branch(chat_member_branch)
+        .branch(callback_branch)
 }
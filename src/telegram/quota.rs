@@ -0,0 +1,418 @@
+//! Rate Limit Module
+//!
+//! Per-user request budgets: a token bucket refilled at
+//! `rate_limit_per_minute`, a `daily_quota` reset at 00:00 UTC and, in
+//! groups, a `group_cooldown_secs` pause between requests of the chat.
+//! A limit set to 0 is off. The accounting lives in memory and starts over
+//! when the bot restarts.
+
+use chrono::{NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use teloxide::types::{ChatId, UserId};
+
+use crate::CONFIG;
+
+/// Configured limits, 0 meaning off
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub per_minute: u32,
+    pub daily: u32,
+    pub group_cooldown: Duration,
+}
+
+impl Limits {
+    fn from_config() -> Self {
+        Self {
+            per_minute: CONFIG.get::<u32>("rate_limit_per_minute").unwrap_or(0),
+            daily: CONFIG.get::<u32>("daily_quota").unwrap_or(0),
+            group_cooldown: Duration::from_secs(
+                CONFIG.get::<u64>("group_cooldown_secs").unwrap_or(0),
+            ),
+        }
+    }
+}
+
+/// Why a request was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Blocked {
+    /// The user's bucket is empty; a token is back after the wait
+    RateLimited(Duration),
+    /// The user's daily quota is used up
+    QuotaExhausted,
+    /// The group asked too recently
+    Cooldown(Duration),
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Blocked::RateLimited(wait) => write!(
+                f,
+                "⏳ Too many requests, try again in {}.",
+                format_wait(*wait)
+            ),
+            Blocked::QuotaExhausted => {
+                write!(f, "⏳ Your daily quota is used up, it resets at 00:00 UTC.")
+            }
+            Blocked::Cooldown(wait) => write!(
+                f,
+                "⏳ This chat is cooling down, try again in {}.",
+                format_wait(*wait)
+            ),
+        }
+    }
+}
+
+/// What `/limits` reports, without consuming anything
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitStatus {
+    pub limits: Limits,
+    /// Whole requests left in the bucket, `None` when rate limiting is off
+    pub tokens: Option<u32>,
+    /// Requests left today, `None` when there is no quota
+    pub quota_left: Option<u32>,
+    /// Remaining group cooldown, `None` in private chats or when off
+    pub chat_cooldown: Option<Duration>,
+}
+
+impl LimitStatus {
+    /// Wait until the next request is allowed, `None` until the daily reset
+    pub fn next_allowed(&self, bucket_wait: Duration) -> Option<Duration> {
+        if self.quota_left == Some(0) {
+            return None;
+        }
+        Some(bucket_wait.max(self.chat_cooldown.unwrap_or_default()))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    buckets: HashMap<u64, Bucket>,
+    /// Requests per user on the given UTC day
+    daily: HashMap<u64, (NaiveDate, u32)>,
+    /// Last accepted request per group
+    last_group_request: HashMap<i64, Instant>,
+}
+
+impl State {
+    /// Refills the user's bucket up to `now` and returns its tokens
+    fn refill(&mut self, limits: &Limits, user_id: u64, now: Instant) -> f64 {
+        let capacity = limits.per_minute as f64;
+        let bucket = self.buckets.entry(user_id).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.updated = now;
+        bucket.tokens
+    }
+
+    fn used_today(&self, user_id: u64, today: NaiveDate) -> u32 {
+        match self.daily.get(&user_id) {
+            Some((day, count)) if *day == today => *count,
+            _ => 0,
+        }
+    }
+
+    fn cooldown_left(&self, limits: &Limits, chat_id: i64, now: Instant) -> Duration {
+        self.last_group_request
+            .get(&chat_id)
+            .map(|last| {
+                limits
+                    .group_cooldown
+                    .saturating_sub(now.saturating_duration_since(*last))
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Time until the bucket holds a whole token again
+fn bucket_wait(limits: &Limits, tokens: f64) -> Duration {
+    if limits.per_minute == 0 || tokens >= 1.0 {
+        return Duration::ZERO;
+    }
+    Duration::from_secs_f64((1.0 - tokens) * 60.0 / limits.per_minute as f64)
+}
+
+/// Rounds a wait up to whole seconds
+pub fn format_wait(wait: Duration) -> String {
+    format!("{}s", wait.as_secs_f64().ceil() as u64)
+}
+
+pub struct RateLimiter {
+    limits: Limits,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Checks every limit and, only if all pass, counts the request
+    pub fn try_acquire_at(
+        &self,
+        user_id: u64,
+        group: Option<i64>,
+        now: Instant,
+        today: NaiveDate,
+    ) -> Result<(), Blocked> {
+        let limits = &self.limits;
+        let mut state = self.state.lock().unwrap();
+
+        if limits.daily > 0 && state.used_today(user_id, today) >= limits.daily {
+            return Err(Blocked::QuotaExhausted);
+        }
+        if let Some(chat_id) = group.filter(|_| !limits.group_cooldown.is_zero()) {
+            let left = state.cooldown_left(limits, chat_id, now);
+            if !left.is_zero() {
+                return Err(Blocked::Cooldown(left));
+            }
+        }
+        if limits.per_minute > 0 {
+            let tokens = state.refill(limits, user_id, now);
+            if tokens < 1.0 {
+                return Err(Blocked::RateLimited(bucket_wait(limits, tokens)));
+            }
+        }
+
+        if limits.per_minute > 0 {
+            if let Some(bucket) = state.buckets.get_mut(&user_id) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        if limits.daily > 0 {
+            let used = state.used_today(user_id, today);
+            state.daily.insert(user_id, (today, used + 1));
+        }
+        if let Some(chat_id) = group.filter(|_| !limits.group_cooldown.is_zero()) {
+            state.last_group_request.insert(chat_id, now);
+        }
+        Ok(())
+    }
+
+    /// Gives back a request counted by `try_acquire_at` that was never served
+    pub fn refund_at(&self, user_id: u64, group: Option<i64>, today: NaiveDate) {
+        let limits = &self.limits;
+        let mut state = self.state.lock().unwrap();
+
+        if limits.per_minute > 0 {
+            if let Some(bucket) = state.buckets.get_mut(&user_id) {
+                bucket.tokens = (bucket.tokens + 1.0).min(limits.per_minute as f64);
+            }
+        }
+        if limits.daily > 0 {
+            let used = state.used_today(user_id, today);
+            if used > 0 {
+                state.daily.insert(user_id, (today, used - 1));
+            }
+        }
+        // The cooldown had run out for the request to be accepted
+        if let Some(chat_id) = group {
+            state.last_group_request.remove(&chat_id);
+        }
+    }
+
+    /// Reports the user's limits, along with the wait for a bucket token
+    pub fn status_at(
+        &self,
+        user_id: u64,
+        group: Option<i64>,
+        now: Instant,
+        today: NaiveDate,
+    ) -> (LimitStatus, Duration) {
+        let limits = &self.limits;
+        let mut state = self.state.lock().unwrap();
+
+        let (tokens, wait) = if limits.per_minute > 0 {
+            let tokens = state.refill(limits, user_id, now);
+            (Some(tokens.floor() as u32), bucket_wait(limits, tokens))
+        } else {
+            (None, Duration::ZERO)
+        };
+        let quota_left = (limits.daily > 0).then(|| {
+            limits
+                .daily
+                .saturating_sub(state.used_today(user_id, today))
+        });
+        let chat_cooldown = group
+            .filter(|_| !limits.group_cooldown.is_zero())
+            .map(|chat_id| state.cooldown_left(limits, chat_id, now));
+
+        let status = LimitStatus {
+            limits: *limits,
+            tokens,
+            quota_left,
+            chat_cooldown,
+        };
+        (status, wait)
+    }
+}
+
+/// Shared limiter configured from `_settings.toml`
+static RATE_LIMITER: Lazy<RateLimiter> = Lazy::new(|| RateLimiter::new(Limits::from_config()));
+
+fn group_of(chat_id: ChatId) -> Option<i64> {
+    (!chat_id.is_user()).then_some(chat_id.0)
+}
+
+/// Counts a request of the user in the chat
+///
+/// # Errors
+/// The limit that refused it; nothing is counted then
+pub fn try_acquire(user_id: UserId, chat_id: ChatId) -> Result<(), Blocked> {
+    RATE_LIMITER.try_acquire_at(
+        user_id.0,
+        group_of(chat_id),
+        Instant::now(),
+        Utc::now().date_naive(),
+    )
+}
+
+/// Gives back a request of the user that was refused after `try_acquire`,
+/// e.g. because the chat was busy
+pub fn refund(user_id: UserId, chat_id: ChatId) {
+    RATE_LIMITER.refund_at(user_id.0, group_of(chat_id), Utc::now().date_naive());
+}
+
+/// Describes the user's remaining budget in the chat for `/limits`
+pub fn describe(user_id: UserId, chat_id: ChatId) -> String {
+    let (status, wait) = RATE_LIMITER.status_at(
+        user_id.0,
+        group_of(chat_id),
+        Instant::now(),
+        Utc::now().date_naive(),
+    );
+    format_status(&status, wait)
+}
+
+fn format_status(status: &LimitStatus, bucket_wait: Duration) -> String {
+    let limits = &status.limits;
+    let mut lines = Vec::new();
+    lines.push(match status.tokens {
+        Some(tokens) => format!(
+            "Rate limit: {} of {} requests available ({} per minute)",
+            tokens, limits.per_minute, limits.per_minute
+        ),
+        None => "Rate limit: off".to_string(),
+    });
+    lines.push(match status.quota_left {
+        Some(left) => format!("Daily quota: {} of {} left", left, limits.daily),
+        None => "Daily quota: off".to_string(),
+    });
+    if let Some(cooldown) = status.chat_cooldown {
+        lines.push(if cooldown.is_zero() {
+            "Chat cooldown: ready".to_string()
+        } else {
+            format!("Chat cooldown: {} left", format_wait(cooldown))
+        });
+    }
+    lines.push(match status.next_allowed(bucket_wait) {
+        Some(wait) if wait.is_zero() => "Next request: now".to_string(),
+        Some(wait) => format!("Next request: in {}", format_wait(wait)),
+        None => "Next request: after 00:00 UTC".to_string(),
+    });
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_minute: u32, daily: u32, cooldown_secs: u64) -> RateLimiter {
+        RateLimiter::new(Limits {
+            per_minute,
+            daily,
+            group_cooldown: Duration::from_secs(cooldown_secs),
+        })
+    }
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let limiter = limiter(2, 0, 0);
+        let now = Instant::now();
+        let today = NaiveDate::from_ymd_opt(2026, 1, 1).unwrap();
+
+        assert_eq!(limiter.try_acquire_at(1, None, now, today), Ok(()));
+        assert_eq!(limiter.try_acquire_at(1, None, now, today), Ok(()));
+        assert_eq!(
+            limiter.try_acquire_at(1, None, now, today),
+            Err(Blocked::RateLimited(Duration::from_secs(30)))
+        );
+        // Other users have their own bucket
+        assert_eq!(limiter.try_acquire_at(2, None, now, today), Ok(()));
+
+        let later = now + Duration::from_secs(30);
+        let (status, wait) = limiter.status_at(1, None, later, today);
+        assert_eq!(status.tokens, Some(1));
+        assert_eq!(status.next_allowed(wait), Some(Duration::ZERO));
+        assert_eq!(limiter.try_acquire_at(1, None, later, today), Ok(()));
+    }
+
+    #[test]
+    fn test_quota_and_cooldown_are_reported() {
+        let limiter = limiter(0, 2, 10);
+        let now = Instant::now();
+        let today = NaiveDate::from_ymd_opt(2026, 1, 1).unwrap();
+
+        assert_eq!(limiter.try_acquire_at(1, Some(-5), now, today), Ok(()));
+        // A refused request counts against nothing
+        assert_eq!(
+            limiter.try_acquire_at(1, Some(-5), now, today),
+            Err(Blocked::Cooldown(Duration::from_secs(10)))
+        );
+        let (status, wait) = limiter.status_at(1, Some(-5), now, today);
+        assert_eq!(status.quota_left, Some(1));
+        assert_eq!(status.tokens, None);
+        assert_eq!(
+            format_status(&status, wait),
+            "Rate limit: off\nDaily quota: 1 of 2 left\nChat cooldown: 10s left\nNext request: in 10s"
+        );
+
+        assert_eq!(limiter.try_acquire_at(1, None, now, today), Ok(()));
+        assert_eq!(
+            limiter.try_acquire_at(1, None, now, today),
+            Err(Blocked::QuotaExhausted)
+        );
+        let (status, wait) = limiter.status_at(1, None, now, today);
+        assert_eq!(status.next_allowed(wait), None);
+
+        let tomorrow = today.succ_opt().unwrap();
+        assert_eq!(limiter.try_acquire_at(1, None, now, tomorrow), Ok(()));
+    }
+    #[test]
+    fn test_refund_gives_the_request_back() {
+        let limiter = limiter(1, 1, 10);
+        let now = Instant::now();
+        let today = NaiveDate::from_ymd_opt(2026, 1, 1).unwrap();
+
+        assert_eq!(limiter.try_acquire_at(1, Some(-5), now, today), Ok(()));
+        limiter.refund_at(1, Some(-5), today);
+        let (status, wait) = limiter.status_at(1, Some(-5), now, today);
+        assert_eq!(status.tokens, Some(1));
+        assert_eq!(status.quota_left, Some(1));
+        assert_eq!(status.next_allowed(wait), Some(Duration::ZERO));
+        assert_eq!(limiter.try_acquire_at(1, Some(-5), now, today), Ok(()));
+
+        // Nothing to give back beyond what was counted
+        limiter.refund_at(2, None, today);
+        let (status, _) = limiter.status_at(2, None, now, today);
+        assert_eq!(status.tokens, Some(1));
+        assert_eq!(status.quota_left, Some(1));
+    }
+}
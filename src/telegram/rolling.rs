@@ -0,0 +1,278 @@
+//! Rolling Message Module
+//!
+//! Shows a growing answer by editing a Telegram message in place. When the
+//! text outgrows Telegram's message limit, the current message is finalized
+//! and the overflow continues in a new one. With `stream_progress_header`,
+//! the first message is topped by a "⏳ Generating…" line until the answer
+//! is complete. With a message to reply to, the first message of the answer
+//! replies to it. In a forum topic, every message goes to the topic.
+
+use teloxide::{
+    Bot, RequestError,
+    prelude::*,
+    types::{ChatId, MessageId, ReplyParameters, ThreadId},
+};
+
+use crate::{CONFIG, system::format_thousands};
+
+/// Telegram's message length limit, counted in UTF-16 code units
+pub const TELEGRAM_LIMIT: usize = 4096;
+
+/// Room kept free in the first message for the progress header
+const HEADER_RESERVE: usize = 64;
+
+/// Prefixes the text of the first message with the progress header
+fn with_progress_header(text: &str, received: usize) -> String {
+    format!(
+        "⏳ Generating… ({} chars)\n\n{}",
+        format_thousands(received),
+        text
+    )
+}
+
+/// Splits a growing text into messages of at most `limit` UTF-16 units
+///
+/// Characters are never split, so a surrogate pair always ends up whole
+/// in one message.
+pub struct RollingBuffer {
+    limit: usize,
+    current: String,
+    current_units: usize,
+}
+
+impl RollingBuffer {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            current: String::new(),
+            current_units: 0,
+        }
+    }
+
+    /// Appends a piece of the answer
+    ///
+    /// # Returns
+    /// Texts of the messages that filled up and must be finalized, in order
+    pub fn push(&mut self, delta: &str) -> Vec<String> {
+        let mut full = Vec::new();
+        for c in delta.chars() {
+            let units = c.len_utf16();
+            if self.current_units + units > self.limit {
+                full.push(std::mem::take(&mut self.current));
+                self.current_units = 0;
+            }
+            self.current.push(c);
+            self.current_units += units;
+        }
+        full
+    }
+
+    /// Text of the message still being written
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+}
+
+/// A streamed answer shown through one or more edited messages
+pub struct RollingMessage {
+    bot: Bot,
+    chat_id: ChatId,
+    /// Forum topic the messages are sent to
+    thread_id: Option<ThreadId>,
+    message_id: Option<MessageId>,
+    /// Messages that filled up, with the text they show
+    finalized: Vec<(MessageId, String)>,
+    buffer: RollingBuffer,
+    shown: String,
+    /// Whether the first message shows a progress header while streaming
+    progress_header: bool,
+    /// Whether the message being written is the first one
+    first: bool,
+    /// Characters of the answer received so far
+    received: usize,
+    /// Message the first message replies to, until that one is sent
+    reply_to: Option<MessageId>,
+}
+
+impl RollingMessage {
+    pub fn new(
+        bot: Bot,
+        chat_id: ChatId,
+        thread_id: Option<ThreadId>,
+        reply_to: Option<MessageId>,
+    ) -> Self {
+        let progress_header = CONFIG.get_bool("stream_progress_header").unwrap_or(false);
+        let limit = if progress_header {
+            TELEGRAM_LIMIT - HEADER_RESERVE
+        } else {
+            TELEGRAM_LIMIT
+        };
+        Self {
+            bot,
+            chat_id,
+            thread_id,
+            message_id: None,
+            finalized: Vec::new(),
+            buffer: RollingBuffer::new(limit),
+            shown: String::new(),
+            progress_header,
+            first: true,
+            received: 0,
+            reply_to,
+        }
+    }
+
+    /// Appends a piece of the answer
+    ///
+    /// Messages that fill up are finalized right away, without the progress
+    /// header; the rest is only shown on the next `flush`, so callers
+    /// control the edit rate.
+    pub async fn push(&mut self, delta: &str) -> Result<(), RequestError> {
+        self.received += delta.chars().count();
+        for text in self.buffer.push(delta) {
+            self.show(text).await?;
+            if let Some(id) = self.message_id.take() {
+                self.finalized.push((id, std::mem::take(&mut self.shown)));
+            }
+            if self.first {
+                // Only the first message carries the header
+                self.first = false;
+                self.buffer.limit = TELEGRAM_LIMIT;
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings the current message up to date with the buffer
+    pub async fn flush(&mut self) -> Result<(), RequestError> {
+        let text = self.buffer.current();
+        if text.trim().is_empty() {
+            return Ok(());
+        }
+        let text = self.rendered(text);
+        if text == self.shown {
+            return Ok(());
+        }
+        self.show(text).await
+    }
+
+    /// Whether any message has been sent yet
+    pub fn is_started(&self) -> bool {
+        self.message_id.is_some() || !self.finalized.is_empty()
+    }
+
+    /// Makes the sent messages show `chunks`, the final form of the answer
+    ///
+    /// Post-processing may change the streamed text, so each message is
+    /// edited to its chunk, missing ones are sent and surplus ones deleted.
+    ///
+    /// # Returns
+    /// The messages showing the answer, in order
+    pub async fn replace_with(
+        &mut self,
+        chunks: &[String],
+    ) -> Result<Vec<MessageId>, RequestError> {
+        let mut sent = std::mem::take(&mut self.finalized);
+        if let Some(id) = self.message_id.take() {
+            sent.push((id, std::mem::take(&mut self.shown)));
+        }
+        let mut sent = sent.into_iter();
+        let mut shown = Vec::new();
+        for chunk in chunks {
+            match sent.next() {
+                // Editing to the same text would fail with "message is not modified"
+                Some((id, text)) if text == *chunk => shown.push(id),
+                Some((id, _)) => {
+                    self.bot.edit_message_text(self.chat_id, id, chunk).await?;
+                    shown.push(id);
+                }
+                None => shown.push(self.send(chunk).await?),
+            }
+        }
+        for (id, _) in sent {
+            self.bot.delete_message(self.chat_id, id).await?;
+        }
+        Ok(shown)
+    }
+
+    /// Text of the current message as displayed while streaming
+    fn rendered(&self, text: &str) -> String {
+        if self.progress_header && self.first {
+            with_progress_header(text, self.received)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// Sends the current message or edits it when it already exists
+    async fn show(&mut self, text: String) -> Result<(), RequestError> {
+        match self.message_id {
+            Some(id) => {
+                self.bot.edit_message_text(self.chat_id, id, &text).await?;
+            }
+            None => self.message_id = Some(self.send(&text).await?),
+        }
+        self.shown = text;
+        Ok(())
+    }
+
+    /// Sends a new message, the first one as a reply
+    ///
+    /// A deleted request doesn't stop the answer, it is then sent on its own.
+    async fn send(&mut self, text: &str) -> Result<MessageId, RequestError> {
+        let mut request = self.bot.send_message(self.chat_id, text);
+        if let Some(thread_id) = self.thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        if let Some(id) = self.reply_to.take() {
+            request =
+                request.reply_parameters(ReplyParameters::new(id).allow_sending_without_reply());
+        }
+        Ok(request.await?.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_past_limit_starts_second_message() {
+        let mut buffer = RollingBuffer::new(TELEGRAM_LIMIT);
+        let mut finalized = Vec::new();
+        // Emoji take two UTF-16 units each, so the limit is hit at 2048 of them
+        for _ in 0..2100 {
+            finalized.extend(buffer.push("😀"));
+        }
+
+        assert_eq!(finalized.len(), 1);
+        assert_eq!(finalized[0].encode_utf16().count(), TELEGRAM_LIMIT);
+        assert_eq!(buffer.current().chars().count(), 2100 - 2048);
+        assert_eq!(
+            format!("{}{}", finalized[0], buffer.current()),
+            "😀".repeat(2100)
+        );
+    }
+
+    #[test]
+    fn test_progress_header_only_while_first_message_streams() {
+        let mut message = RollingMessage::new(Bot::new("0:test"), ChatId(1), None, None);
+        message.progress_header = true;
+        message.received = 1200;
+
+        assert_eq!(
+            message.rendered("Once upon"),
+            "⏳ Generating… (1,200 chars)\n\nOnce upon"
+        );
+        message.first = false;
+        assert_eq!(message.rendered("Once upon"), "Once upon");
+    }
+
+    #[test]
+    fn test_surrogate_pair_is_not_split() {
+        let mut buffer = RollingBuffer::new(3);
+        let finalized = buffer.push("ab😀");
+        assert_eq!(finalized, vec!["ab"]);
+        assert_eq!(buffer.current(), "😀");
+    }
+}
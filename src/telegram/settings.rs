@@ -0,0 +1,254 @@
+//! Settings Keyboard Module
+//!
+//! `/settings` posts an inline keyboard for adjusting the chat's temperature
+//! with buttons, a mobile-friendly alternative to `/temperature 0.8`. With
+//! `allowed_models` set, a second row cycles the chat's model through that
+//! list. Presses arrive as callback queries; the message is edited to show
+//! the new values. In groups only administrators may press the buttons.
+
+use std::sync::Arc;
+use teloxide::{
+    Bot,
+    prelude::*,
+    types::{CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup},
+};
+
+use crate::{storage::Storage, system, telegram::admin::is_admin};
+
+/// Temperature change of one button press
+const TEMPERATURE_STEP: f32 = 0.1;
+
+/// What a settings button does, carried in its callback data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SettingsAction {
+    /// Changes the temperature by the step, encoded as `temp:+0.1`
+    Temperature(f32),
+    /// Switches to the next allowed model, encoded as `model:next`
+    NextModel,
+    /// Switches to the previous allowed model, encoded as `model:prev`
+    PreviousModel,
+    /// The label between the buttons, encoded as `noop`
+    Noop,
+}
+
+impl SettingsAction {
+    pub fn encode(self) -> String {
+        match self {
+            SettingsAction::Temperature(step) => format!("temp:{:+}", step),
+            SettingsAction::NextModel => "model:next".to_string(),
+            SettingsAction::PreviousModel => "model:prev".to_string(),
+            SettingsAction::Noop => "noop".to_string(),
+        }
+    }
+
+    /// Parses callback data; `None` for buttons of other features
+    pub fn decode(data: &str) -> Option<Self> {
+        match data {
+            "noop" => return Some(SettingsAction::Noop),
+            "model:next" => return Some(SettingsAction::NextModel),
+            "model:prev" => return Some(SettingsAction::PreviousModel),
+            _ => {}
+        }
+        data.strip_prefix("temp:")?
+            .parse::<f32>()
+            .ok()
+            .filter(|step| step.is_finite())
+            .map(SettingsAction::Temperature)
+    }
+}
+
+/// Applies a step, rounded to one decimal and kept within 0.0..=2.0
+pub fn step_temperature(current: f32, step: f32) -> f32 {
+    (((current + step) * 10.0).round() / 10.0).clamp(0.0, 2.0)
+}
+
+/// The allowed model before or after `current`, wrapping around
+///
+/// A model outside the list steps to its first or last entry; `None` when
+/// the list is empty.
+pub fn step_model(current: &str, allowed: &[String], forward: bool) -> Option<String> {
+    let last = allowed.len().checked_sub(1)?;
+    let index = match allowed.iter().position(|name| name == current) {
+        Some(index) if forward => (index + 1) % allowed.len(),
+        Some(index) => index.checked_sub(1).unwrap_or(last),
+        None if forward => 0,
+        None => last,
+    };
+    Some(allowed[index].clone())
+}
+
+/// Text of the settings message
+pub fn settings_text(temperature: f32, model: &str) -> String {
+    format!(
+        "⚙️ Settings\nModel: {}\nTemperature: {:.1}",
+        model, temperature
+    )
+}
+
+/// Keyboard of the settings message
+///
+/// The model row is only shown when `allowed` lists models to cycle through.
+pub fn settings_keyboard(
+    temperature: f32,
+    model: &str,
+    allowed: &[String],
+) -> InlineKeyboardMarkup {
+    let button = |label: String, action: SettingsAction| {
+        InlineKeyboardButton::callback(label, action.encode())
+    };
+    let mut rows = vec![vec![
+        button(
+            format!("➖ {}", TEMPERATURE_STEP),
+            SettingsAction::Temperature(-TEMPERATURE_STEP),
+        ),
+        button(format!("🌡 {:.1}", temperature), SettingsAction::Noop),
+        button(
+            format!("➕ {}", TEMPERATURE_STEP),
+            SettingsAction::Temperature(TEMPERATURE_STEP),
+        ),
+    ]];
+    if !allowed.is_empty() {
+        rows.push(vec![
+            button("◀️".to_string(), SettingsAction::PreviousModel),
+            button(format!("🤖 {}", model), SettingsAction::Noop),
+            button("▶️".to_string(), SettingsAction::NextModel),
+        ]);
+    }
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// Current model and temperature of a chat, as its requests use them
+pub async fn chat_settings(chat_id: ChatId, storage: &Arc<dyn Storage>) -> (String, f32) {
+    let model = storage.get_model(chat_id.0).await.unwrap_or_default();
+    let temperature = storage.get_temperature(chat_id.0, &model).await;
+    (model, temperature)
+}
+
+/// Handles presses of the `/settings` buttons
+///
+/// # Arguments
+/// * `bot` - Telegram Bot instance
+/// * `q` - Callback query of the pressed button
+/// * `storage` - Storage implementation holding the chat settings
+pub async fn callback_handler(
+    bot: Bot,
+    q: CallbackQuery,
+    storage: Arc<dyn Storage>,
+) -> ResponseResult<()> {
+    let action = q.data.as_deref().and_then(SettingsAction::decode);
+    let (Some(action), Some(message)) = (action, q.regular_message()) else {
+        bot.answer_callback_query(q.id.clone()).await?;
+        return Ok(());
+    };
+    let chat_id = message.chat.id;
+    if !message.chat.is_private() && !is_admin(&bot, chat_id, q.from.id).await {
+        bot.answer_callback_query(q.id.clone())
+            .text("Only administrators can change settings")
+            .await?;
+        return Ok(());
+    }
+
+    let (model, current) = chat_settings(chat_id, &storage).await;
+    let allowed = system::allowed_models();
+    let notice = match action {
+        SettingsAction::Temperature(step) => {
+            let temperature = step_temperature(current, step);
+            if temperature == current {
+                // Editing to the same text would fail with "message is not modified"
+                bot.answer_callback_query(q.id.clone())
+                    .text(format!("Temperature is already {:.1}", temperature))
+                    .await?;
+                return Ok(());
+            }
+            storage.set_temperature(chat_id.0, temperature).await;
+            let change = format!("temperature={}", temperature);
+            system::record_setting_change(chat_id.0, &storage, &change).await;
+            format!("Temperature set to {:.1}", temperature)
+        }
+        SettingsAction::NextModel | SettingsAction::PreviousModel => {
+            let forward = action == SettingsAction::NextModel;
+            let Some(next) = step_model(&model, &allowed, forward).filter(|next| *next != model)
+            else {
+                bot.answer_callback_query(q.id.clone())
+                    .text(format!("Model is already {}", model))
+                    .await?;
+                return Ok(());
+            };
+            storage.set_model(chat_id.0, Some(next.clone())).await;
+            let change = format!("model={}", next);
+            system::record_setting_change(chat_id.0, &storage, &change).await;
+            format!("Model set to {}", next)
+        }
+        SettingsAction::Noop => {
+            bot.answer_callback_query(q.id.clone()).await?;
+            return Ok(());
+        }
+    };
+
+    // The temperature may be per model, so both are read back
+    let (model, temperature) = chat_settings(chat_id, &storage).await;
+    bot.answer_callback_query(q.id.clone()).text(notice).await?;
+    bot.edit_message_text(chat_id, message.id, settings_text(temperature, &model))
+        .reply_markup(settings_keyboard(temperature, &model, &allowed))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_callback_data_round_trip() {
+        for action in [
+            SettingsAction::Temperature(0.1),
+            SettingsAction::Temperature(-0.1),
+            SettingsAction::NextModel,
+            SettingsAction::PreviousModel,
+            SettingsAction::Noop,
+        ] {
+            assert_eq!(SettingsAction::decode(&action.encode()), Some(action));
+        }
+        assert_eq!(SettingsAction::Temperature(0.1).encode(), "temp:+0.1");
+        assert_eq!(SettingsAction::decode("temp:abc"), None);
+        assert_eq!(SettingsAction::decode("temp:NaN"), None);
+        assert_eq!(SettingsAction::decode("model:last"), None);
+    }
+
+    #[test]
+    fn test_step_temperature_rounds_and_clamps() {
+        assert_eq!(step_temperature(0.7, 0.1), 0.8);
+        assert_eq!(step_temperature(0.7, -0.1), 0.6);
+        assert_eq!(step_temperature(2.0, 0.1), 2.0);
+        assert_eq!(step_temperature(0.0, -0.1), 0.0);
+        // Drift from repeated float steps is rounded away
+        let mut temperature = 0.0;
+        for _ in 0..7 {
+            temperature = step_temperature(temperature, 0.1);
+        }
+        assert_eq!(temperature, 0.7);
+    }
+
+    #[test]
+    fn test_step_model_cycles_through_allowed_models() {
+        let allowed = ["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(step_model("a", &allowed, true).as_deref(), Some("b"));
+        assert_eq!(step_model("c", &allowed, true).as_deref(), Some("a"));
+        assert_eq!(step_model("a", &allowed, false).as_deref(), Some("c"));
+        assert_eq!(step_model("b", &allowed, false).as_deref(), Some("a"));
+        // A model set before the allowlist steps onto it
+        assert_eq!(step_model("old", &allowed, true).as_deref(), Some("a"));
+        assert_eq!(step_model("old", &allowed, false).as_deref(), Some("c"));
+        assert_eq!(step_model("a", &[], true), None);
+    }
+
+    #[test]
+    fn test_keyboard_shows_the_current_model() {
+        let allowed = ["a".to_string(), "b".to_string()];
+        let keyboard = settings_keyboard(0.7, "b", &allowed);
+        assert_eq!(keyboard.inline_keyboard.len(), 2);
+        assert_eq!(keyboard.inline_keyboard[1][1].text, "🤖 b");
+        assert_eq!(settings_keyboard(0.7, "b", &[]).inline_keyboard.len(), 1);
+        assert!(settings_text(0.7, "b").contains("Model: b"));
+    }
+}